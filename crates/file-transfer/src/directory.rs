@@ -0,0 +1,300 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Ok, Result};
+use quinn::Connection;
+use tokio::sync::mpsc;
+
+use crate::file_receiver::{accept_manifest, close_control_stream, FileReceiver};
+use crate::file_transfer::FileTransfer;
+use crate::progress::Progress;
+use crate::protocol::{Ack, Manifest, ManifestEntry, RejectedError};
+
+/// 目录传输结构体，先发送目录清单，再逐个发送目录中的文件
+/// 文件名是包含目录名的相对路径，接收端按相对路径保存
+#[derive(Debug)]
+pub struct DirectoryTransfer {
+    /// 目录路径
+    pub dir_path: PathBuf,
+    /// 目录清单
+    pub manifest: Manifest,
+    /// 目录中的文件，和清单的顺序相同，可以在发送前修改分块大小和数据流数量
+    pub files: Vec<FileTransfer>,
+}
+
+impl DirectoryTransfer {
+    /// 遍历目录，创建目录清单和每个文件的传输，空的子目录不会发送
+    /// 目录本身为空时清单中只有目录名；dir_path 是文件时清单中只有这一个文件
+    pub fn new(dir_path: impl AsRef<Path>, chunk_size: usize) -> Result<Self> {
+        let dir_path = dir_path.as_ref().canonicalize()
+            .with_context(|| format!("cannot access {}", dir_path.as_ref().display()))?;
+        let name = dir_path.file_name().context("path has no name")?.to_str()
+            .with_context(|| format!("{} is not valid UTF-8", dir_path.display()))?;
+        let mut files = Vec::new();
+        if dir_path.is_dir() {
+            walk_dir(&dir_path, name, chunk_size, &mut files)?;
+        } else {
+            files.push(FileTransfer::new(&dir_path, chunk_size));
+        }
+        let entries = files.iter()
+            .map(|file| ManifestEntry { path: file.file_name.clone(), size: file.file_size as u64 })
+            .collect();
+        let manifest = Manifest::new(name, entries)?;
+        Ok(Self { dir_path, manifest, files })
+    }
+
+    /// 在连接上发送目录清单和所有文件，有文件失败时停止
+    pub async fn send_dir(&mut self, connection: &Connection) -> Result<()> {
+        let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+        self.manifest.write_to(&mut send_stream).await?;
+        match Ack::read_from(&mut recv_stream).await? {
+            Ack::Ok => {}
+            Ack::Error(message) => return Err(RejectedError(message).into()),
+            ack => anyhow::bail!("unexpected reply to manifest: {ack:?}"),
+        }
+        send_stream.finish()?;
+        for file in &mut self.files {
+            if file.state().is_done() {
+                continue;
+            }
+            file.send_file(connection).await.with_context(|| format!("failed to send {}", file.file_name))?;
+        }
+        Ok(())
+    }
+}
+
+/// 递归遍历目录，按名称排序，prefix 是目录的相对路径
+pub(crate) fn walk_dir(dir: &Path, prefix: &str, chunk_size: usize, files: &mut Vec<FileTransfer>) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        let name = name.to_str().with_context(|| format!("{} is not valid UTF-8", entry.path().display()))?;
+        let relative = format!("{prefix}/{name}");
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk_dir(&entry.path(), &relative, chunk_size, files)?;
+        } else if file_type.is_file() {
+            let mut transfer = FileTransfer::new(entry.path(), chunk_size);
+            transfer.file_name = relative;
+            files.push(transfer);
+        }
+    }
+    Ok(())
+}
+
+/// 目录接收结构体，按目录清单接收所有文件，保存到 output_dir 下的相对路径
+#[derive(Debug)]
+pub struct DirectoryReceiver {
+    /// 保存目录的位置
+    pub output_dir: PathBuf,
+    /// 收到的目录清单，开始接收后才有
+    pub manifest: Option<Arc<Manifest>>,
+    /// 已经保存的文件
+    pub received: Vec<PathBuf>,
+    events: Option<mpsc::UnboundedSender<Progress>>,
+}
+
+impl DirectoryReceiver {
+    /// 创建目录接收结构体
+    pub fn new(output_dir: impl AsRef<Path>) -> Self {
+        Self { output_dir: output_dir.as_ref().to_path_buf(), manifest: None, received: Vec::new(), events: None }
+    }
+
+    /// 订阅接收进度，每保存一个文件发送一次，只保留最后一次订阅
+    pub fn events(&mut self) -> mpsc::UnboundedReceiver<Progress> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.events = Some(sender);
+        receiver
+    }
+
+    /// 接收目录清单和清单中的所有文件，清单中的路径不合法时拒绝，不在清单中的文件也拒绝
+    /// 返回保存的目录，发送的是单个文件时返回文件路径
+    pub async fn receive_dir(&mut self, connection: &Connection) -> Result<PathBuf> {
+        let (mut send_stream, mut recv_stream) = connection.accept_bi().await?;
+        let res = accept_manifest(&mut send_stream, &mut recv_stream, &self.output_dir, |_| Ok(())).await;
+        close_control_stream(send_stream, recv_stream).await;
+        let manifest = Arc::new(res?);
+        self.manifest = Some(manifest.clone());
+        let dir = self.output_dir.join(manifest.root());
+        // 空目录也创建出来
+        if manifest.entries().is_empty() {
+            tokio::fs::create_dir_all(&dir).await?;
+        }
+        let (start, total) = (Instant::now(), manifest.total_size());
+        let mut bytes = 0;
+        for _ in manifest.entries() {
+            let mut receiver = FileReceiver::new(&self.output_dir);
+            receiver.manifest = Some(manifest.clone());
+            self.received.push(receiver.receive_file(connection).await?);
+            bytes += receiver.header.map_or(0, |header| header.file_size);
+            if let Some(events) = &self.events {
+                let rate = bytes as f64 / start.elapsed().as_secs_f64().max(f64::EPSILON);
+                let eta = (rate > 0.0).then(|| Duration::from_secs_f64(total.saturating_sub(bytes) as f64 / rate));
+                events.send(Progress { bytes, total, rate, eta }).ok();
+            }
+        }
+        Ok(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use quic_transport::quic_endpoint::{generate_self_signed, ClientEndpoint, ServerEndpointContainer};
+
+    #[tokio::test]
+    async fn test_send_dir() {
+        let dir = std::env::temp_dir().join(format!("directory-test-{}", std::process::id()));
+        let files = [("photos/a.jpg", 40_000), ("photos/2024/b.jpg", 100), ("photos/2024/empty", 0), ("photos/c/d.txt", 5)];
+        for (name, size) in files {
+            let path = dir.join("src").join(name);
+            tokio::fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            tokio::fs::write(path, data).await.unwrap();
+        }
+        let output_dir = dir.join("out");
+        tokio::fs::create_dir_all(&output_dir).await.unwrap();
+
+        let (cert, key) = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let server_endpoint = ServerEndpointContainer::with_cert(addr, cert.clone(), key).unwrap();
+        let client_endpoint = ClientEndpoint::with_cert(addr, cert).unwrap();
+        let server = server_endpoint.get_endpoint().clone();
+        let receiver_dir = output_dir.clone();
+        let server_task = tokio::spawn(async move {
+            let connection = server.accept().await.unwrap().await.unwrap();
+            let mut receiver = DirectoryReceiver::new(receiver_dir);
+            let mut events = receiver.events();
+            let res = receiver.receive_dir(&connection).await;
+            drop(receiver.events.take());
+            let mut last = None;
+            while let Some(progress) = events.recv().await {
+                last = Some(progress);
+            }
+            (receiver, res, last)
+        });
+        let connecting = client_endpoint.get_endpoint().connect(server_endpoint.get_bind_addr(), "localhost");
+        let connection = connecting.unwrap().await.unwrap();
+
+        let mut transfer = DirectoryTransfer::new(dir.join("src/photos"), 16 * 1024).unwrap();
+        let paths: Vec<_> = transfer.manifest.entries().iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["photos/2024/b.jpg", "photos/2024/empty", "photos/a.jpg", "photos/c/d.txt"]);
+        transfer.files[2].streams = 2;
+        transfer.send_dir(&connection).await.unwrap();
+
+        let (receiver, res, last) = server_task.await.unwrap();
+        assert_eq!(res.unwrap(), output_dir.join("photos"));
+        assert_eq!(receiver.received.len(), 4);
+        let last = last.unwrap();
+        assert_eq!((last.bytes, last.total), (40_105, 40_105));
+        for (name, _) in files {
+            let sent = tokio::fs::read(dir.join("src").join(name)).await.unwrap();
+            assert_eq!(tokio::fs::read(output_dir.join(name)).await.unwrap(), sent);
+        }
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_empty_dir() {
+        let dir = std::env::temp_dir().join(format!("directory-empty-test-{}", std::process::id()));
+        tokio::fs::create_dir_all(dir.join("src/empty")).await.unwrap();
+        let output_dir = dir.join("out");
+        tokio::fs::create_dir_all(&output_dir).await.unwrap();
+
+        let (cert, key) = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let server_endpoint = ServerEndpointContainer::with_cert(addr, cert.clone(), key).unwrap();
+        let client_endpoint = ClientEndpoint::with_cert(addr, cert).unwrap();
+        let server = server_endpoint.get_endpoint().clone();
+        let receiver_dir = output_dir.clone();
+        let server_task = tokio::spawn(async move {
+            let connection = server.accept().await.unwrap().await.unwrap();
+            DirectoryReceiver::new(receiver_dir).receive_dir(&connection).await
+        });
+        let connecting = client_endpoint.get_endpoint().connect(server_endpoint.get_bind_addr(), "localhost");
+        let connection = connecting.unwrap().await.unwrap();
+
+        // 空目录的清单只有目录名，接收端创建这个目录并返回它
+        let mut transfer = DirectoryTransfer::new(dir.join("src/empty"), 1024).unwrap();
+        assert_eq!(transfer.manifest.root(), "empty");
+        assert!(transfer.manifest.entries().is_empty());
+        transfer.send_dir(&connection).await.unwrap();
+        let received = server_task.await.unwrap().unwrap();
+        assert_eq!(received, output_dir.join("empty"));
+        assert!(received.is_dir());
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reject_unlisted_file() {
+        let dir = std::env::temp_dir().join(format!("directory-reject-test-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let source = dir.join("a.txt");
+        tokio::fs::write(&source, b"hello").await.unwrap();
+
+        let mut receiver = FileReceiver::new(&dir);
+        let entries = vec![ManifestEntry { path: "photos/a.txt".to_string(), size: 5 }];
+        receiver.manifest = Some(Arc::new(Manifest::new("photos", entries).unwrap()));
+        let mut transfer = FileTransfer::new(&source, 1024);
+        transfer.file_name = "other/a.txt".to_string();
+        let (sender_side, receiver_side) = tokio::io::duplex(64 * 1024);
+        let (mut sender_recv, mut sender_send) = tokio::io::split(sender_side);
+        let (mut receiver_recv, mut receiver_send) = tokio::io::split(receiver_side);
+        let (sent, received) = tokio::join!(
+            transfer.send_on_stream(None, &mut sender_send, &mut sender_recv),
+            receiver.receive_on_stream(None, &mut receiver_send, &mut receiver_recv),
+        );
+        assert!(received.unwrap_err().to_string().contains("not in the manifest"));
+        assert!(sent.unwrap_err().downcast_ref::<RejectedError>().is_some());
+        assert!(!dir.join("other").exists());
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_receive_files_manifest() {
+        use crate::file_receiver::receive_files;
+        let dir = std::env::temp_dir().join(format!("directory-manifest-test-{}", std::process::id()));
+        for name in ["a.txt", "b.txt"] {
+            let path = dir.join("src/photos").join(name);
+            tokio::fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            tokio::fs::write(path, name).await.unwrap();
+        }
+        let output_dir = dir.join("out");
+        tokio::fs::create_dir_all(&output_dir).await.unwrap();
+
+        let (cert, key) = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let server_endpoint = ServerEndpointContainer::with_cert(addr, cert.clone(), key).unwrap();
+        let client_endpoint = ClientEndpoint::with_cert(addr, cert).unwrap();
+        let server = server_endpoint.get_endpoint().clone();
+        let receiver_dir = output_dir.clone();
+        let (results, mut received) = mpsc::unbounded_channel();
+        let server_task = tokio::spawn(async move {
+            let connection = server.accept().await.unwrap().await.unwrap();
+            receive_files(&connection, receiver_dir, results).await
+        });
+        let connecting = client_endpoint.get_endpoint().connect(server_endpoint.get_bind_addr(), "localhost");
+        let connection = connecting.unwrap().await.unwrap();
+
+        // 清单之后发送一个不在清单中的文件
+        let mut transfer = DirectoryTransfer::new(dir.join("src/photos"), 1024).unwrap();
+        transfer.files[1].file_name = "photos/c.txt".to_string();
+        let err = transfer.send_dir(&connection).await.unwrap_err();
+        assert!(format!("{err:#}").contains("not in the manifest"), "{err:#}");
+        connection.close(0u32.into(), b"done");
+        server_task.await.unwrap().unwrap();
+
+        let mut results = Vec::new();
+        while let Some((_, res)) = received.recv().await {
+            results.push(res.map_err(|e| e.to_string()));
+        }
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap(), &output_dir.join("photos/a.txt"));
+        assert!(results[1].as_ref().unwrap_err().contains("not in the manifest"));
+        assert!(!output_dir.join("photos/c.txt").exists());
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -0,0 +1,815 @@
+use std::collections::HashMap;
+use std::{io::SeekFrom, ops::Range, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
+use anyhow::{Ok, Result};
+use tokio::{fs::{File, OpenOptions}, io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter}};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use quinn::{Connection, ConnectionError, RecvStream, SendStream};
+
+use crate::file_transfer::TransferState;
+use crate::protocol::{hash_prefix, read_chunk, Ack, DataHeader, FileHeader, IntegrityError, Manifest, Trailer, DATA_MAGIC, MANIFEST_MAGIC};
+
+/// 默认最多要求重发几轮校验失败的分块
+pub const DEFAULT_REPAIR_ROUNDS: u32 = 3;
+
+/// 每校验多少字节保存一次断点
+const CHECKPOINT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// 文件接收结构体，把收到的文件写入 output_dir
+#[derive(Debug)]
+pub struct FileReceiver {
+    /// 保存文件的目录
+    pub output_dir: PathBuf,
+    /// 收到的文件头，开始接收后才有
+    pub header: Option<FileHeader>,
+    /// 已经写入的字节数
+    pub received: u64,
+    /// 分块校验失败时最多要求重发几轮，为 0 时直接失败
+    pub repair_rounds: u32,
+    /// 传输状态
+    pub state: TransferState,
+    /// 接收目录时的清单，设置后只接收清单中的文件，同一个目录的所有文件共用一份
+    pub manifest: Option<Arc<Manifest>>,
+    /// 目标文件已经存在时是否覆盖，默认拒绝接收
+    pub overwrite: bool,
+}
+
+impl FileReceiver {
+    /// 创建文件接收结构体
+    pub fn new(output_dir: impl AsRef<Path>) -> Self {
+        Self {
+            output_dir: output_dir.as_ref().to_path_buf(),
+            header: None,
+            received: 0,
+            repair_rounds: DEFAULT_REPAIR_ROUNDS,
+            state: TransferState::Queued,
+            manifest: None,
+            overwrite: false,
+        }
+    }
+
+    /// 接受连接上的下一个文件流，保存后回复发送端，返回保存的路径
+    /// 接收失败时删除写了一半的文件，并把原因告诉发送端
+    pub async fn receive_file(&mut self, connection: &Connection) -> Result<PathBuf> {
+        let (mut send_stream, mut recv_stream) = connection.accept_bi().await?;
+        let mut data_streams = DataStreams::Connection(connection);
+        let res = self.receive_on_stream(Some(&mut data_streams), &mut send_stream, &mut recv_stream).await;
+        close_control_stream(send_stream, recv_stream).await;
+        res
+    }
+
+    /// 在已经打开的控制流上接收文件，校验失败时状态为 `Failed(Corrupted)`
+    /// 发送端使用多个数据流时需要 data_streams 接受数据流
+    pub(crate) async fn receive_on_stream(
+        &mut self,
+        data_streams: Option<&mut DataStreams<'_>>,
+        send_stream: &mut (impl AsyncWrite + Unpin),
+        recv_stream: &mut (impl AsyncRead + Unpin),
+    ) -> Result<PathBuf> {
+        self.state = TransferState::Transferring;
+        let res = self.receive_stream(data_streams, send_stream, recv_stream).await;
+        let ack = match &res {
+            std::result::Result::Ok(_) => Ack::Ok,
+            Err(e) if e.downcast_ref::<IntegrityError>().is_some() => Ack::Corrupted(format!("{e:#}")),
+            Err(e) => Ack::Error(format!("{e:#}")),
+        };
+        self.state = TransferState::from_result(&res);
+        ack.write_to(send_stream).await.ok();
+        res
+    }
+
+    /// 先写入临时文件，校验通过后再改名
+    /// 上次中断时留下的临时文件校验通过后从断点继续
+    async fn receive_stream(
+        &mut self,
+        mut data_streams: Option<&mut DataStreams<'_>>,
+        send_stream: &mut (impl AsyncWrite + Unpin),
+        recv_stream: &mut (impl AsyncRead + Unpin),
+    ) -> Result<PathBuf> {
+        let header = FileHeader::read_from(recv_stream).await?;
+        if let Some(manifest) = &self.manifest {
+            anyhow::ensure!(
+                manifest.size_of(&header.file_name) == Some(header.file_size),
+                "{} is not in the manifest",
+                header.file_name
+            );
+        }
+        let path = self.output_dir.join(&header.file_name);
+        // 开始接收前检查，不让发送端白白发送整个文件
+        self.check_target(&path)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let part_path = self.output_dir.join(format!("{}.part", header.file_name));
+        self.header = Some(header.clone());
+        if let Some(data_streams) = data_streams.as_deref_mut() {
+            data_streams.register(&header.file_name)?;
+        }
+        let mut checkpoint = Checkpoint::load(&header, &part_path).await.unwrap_or_default();
+        self.received = checkpoint.offset;
+        Ack::Resume(checkpoint.offset).write_to(send_stream).await?;
+        let res = self.write_file(data_streams, send_stream, recv_stream, &header, &part_path, &mut checkpoint).await;
+        match &res {
+            std::result::Result::Ok(()) => {
+                // 接收期间可能有其他程序创建了目标文件，保留临时文件和断点
+                self.check_target(&path)?;
+                tokio::fs::rename(&part_path, &path).await?;
+                tokio::fs::remove_file(Checkpoint::path(&part_path)).await.ok();
+            }
+            // 校验失败时不保留，其他错误保留已经校验的部分，下次续传
+            Err(e) if e.downcast_ref::<IntegrityError>().is_some() => {
+                tokio::fs::remove_file(&part_path).await.ok();
+                tokio::fs::remove_file(Checkpoint::path(&part_path)).await.ok();
+            }
+            Err(_) => {}
+        }
+        res.map(|()| path)
+    }
+
+    /// 没有设置 overwrite 时目标文件必须不存在
+    fn check_target(&self, path: &Path) -> Result<()> {
+        anyhow::ensure!(
+            self.overwrite || !path.exists(),
+            "{} already exists",
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// 打开临时文件写入，接收全部分块，结束后对照结尾检查大小，并要求发送端重发校验失败的分块
+    /// 最后检查整个文件的哈希，出错时保存断点
+    async fn write_file(
+        &mut self,
+        data_streams: Option<&mut DataStreams<'_>>,
+        send_stream: &mut (impl AsyncWrite + Unpin),
+        recv_stream: &mut (impl AsyncRead + Unpin),
+        header: &FileHeader,
+        path: &Path,
+        checkpoint: &mut Checkpoint,
+    ) -> Result<()> {
+        let file = OpenOptions::new().create(true).write(true).truncate(false).open(path).await?;
+        // 去掉断点之后没有校验过的内容
+        file.set_len(checkpoint.offset).await?;
+        let mut file = BufWriter::new(file);
+        file.seek(SeekFrom::Start(checkpoint.offset)).await?;
+        let res = async {
+            let bad_chunks = if header.streams > 1 {
+                let data_streams =
+                    data_streams.ok_or_else(|| anyhow::anyhow!("receiving several streams needs a connection"))?;
+                self.receive_ranges(data_streams, header, path, checkpoint).await?
+            } else {
+                self.receive_chunks(recv_stream, header, path, &mut file, checkpoint).await?
+            };
+            let trailer = Trailer::read_from(recv_stream).await?;
+            anyhow::ensure!(
+                trailer.file_size == header.file_size,
+                "sender sent {} bytes, expected {}",
+                trailer.file_size,
+                header.file_size
+            );
+            anyhow::ensure!(trailer.hash == header.hash, "{} changed while it was being sent", header.file_name);
+            self.repair_chunks(send_stream, recv_stream, header, &mut file, bad_chunks).await?;
+            file.flush().await?;
+            // 断点之后的内容（重发过的分块、并行写入的分块）从文件中读取计算哈希
+            checkpoint.advance(path, header.file_size, header.chunk_size as usize).await?;
+            if *checkpoint.hasher.finalize().as_bytes() != header.hash {
+                return Err(IntegrityError(format!("{} is corrupted: hash mismatch", header.file_name)).into());
+            }
+            Ok(())
+        }
+        .await;
+        if res.is_err() && file.flush().await.is_ok() {
+            checkpoint.save(header, path).await.ok();
+        }
+        res
+    }
+
+    /// 要求发送端重发校验失败的分块，写入文件中对应的位置
+    async fn repair_chunks(
+        &mut self,
+        send_stream: &mut (impl AsyncWrite + Unpin),
+        recv_stream: &mut (impl AsyncRead + Unpin),
+        header: &FileHeader,
+        file: &mut BufWriter<File>,
+        mut bad_chunks: Vec<u64>,
+    ) -> Result<()> {
+        let mut buffer = vec![0u8; header.chunk_size as usize];
+        let mut round = 0;
+        while !bad_chunks.is_empty() {
+            if round == self.repair_rounds {
+                return Err(IntegrityError(format!(
+                    "{} is corrupted: {} chunks failed verification",
+                    header.file_name,
+                    bad_chunks.len()
+                ))
+                .into());
+            }
+            round += 1;
+            Ack::Resend(bad_chunks.clone()).write_to(send_stream).await?;
+            for index in std::mem::take(&mut bad_chunks) {
+                let chunk = &mut buffer[..header.chunk_len(index)];
+                if read_chunk(recv_stream, chunk).await? {
+                    file.seek(SeekFrom::Start(header.chunk_offset(index))).await?;
+                    file.write_all(chunk).await?;
+                } else {
+                    bad_chunks.push(index);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 在控制流上按顺序接收分块并逐块校验，返回校验失败的分块
+    async fn receive_chunks(
+        &mut self,
+        recv_stream: &mut (impl AsyncRead + Unpin),
+        header: &FileHeader,
+        path: &Path,
+        file: &mut BufWriter<File>,
+        checkpoint: &mut Checkpoint,
+    ) -> Result<Vec<u64>> {
+        let mut buffer = vec![0u8; header.chunk_size as usize];
+        let mut bad_chunks = Vec::new();
+        let mut saved = checkpoint.offset;
+        for index in checkpoint.offset / header.chunk_size as u64..header.chunk_count() {
+            let chunk = &mut buffer[..header.chunk_len(index)];
+            let valid = read_chunk(recv_stream, chunk).await?;
+            file.write_all(chunk).await?;
+            self.received += chunk.len() as u64;
+            // 断点只记录连续校验通过的前缀
+            if !valid {
+                bad_chunks.push(index);
+            } else if bad_chunks.is_empty() {
+                checkpoint.offset += chunk.len() as u64;
+                checkpoint.hasher.update(chunk);
+                if checkpoint.offset - saved >= CHECKPOINT_BYTES {
+                    file.flush().await?;
+                    checkpoint.save(header, path).await?;
+                    saved = checkpoint.offset;
+                }
+            }
+        }
+        Ok(bad_chunks)
+    }
+
+    /// 接受发送端打开的数据流，每个数据流的一段分块直接写入文件中对应的位置，返回校验失败的分块
+    /// 结束或出错时把断点推进到所有段中连续校验通过的前缀
+    async fn receive_ranges(
+        &mut self,
+        data_streams: &mut DataStreams<'_>,
+        header: &FileHeader,
+        path: &Path,
+        checkpoint: &mut Checkpoint,
+    ) -> Result<Vec<u64>> {
+        let ranges = Arc::new(header.ranges(checkpoint.offset / header.chunk_size as u64));
+        let received = Arc::new(AtomicU64::new(0));
+        let mut tasks = JoinSet::new();
+        for _ in 0..ranges.len() {
+            let (data_header, recv_stream) = data_streams.next().await?;
+            tasks.spawn(receive_range(
+                data_header,
+                recv_stream,
+                header.clone(),
+                path.to_path_buf(),
+                ranges.clone(),
+                received.clone(),
+            ));
+        }
+        let mut verified = vec![None; ranges.len()];
+        let mut bad_chunks = Vec::new();
+        let mut error = None;
+        while let Some(res) = tasks.join_next().await {
+            let result = match res? {
+                std::result::Result::Ok(result) => result,
+                Err(e) => {
+                    error.get_or_insert(e);
+                    continue;
+                }
+            };
+            if verified[result.index].replace(result.verified).is_some() {
+                error.get_or_insert(anyhow::anyhow!("range {} was sent twice", result.index));
+            }
+            bad_chunks.extend(result.bad_chunks);
+            if let Some(e) = result.error {
+                error.get_or_insert(e);
+            }
+        }
+        self.received += received.load(Ordering::Relaxed);
+        let mut end = checkpoint.offset / header.chunk_size as u64;
+        for (range, verified) in ranges.iter().zip(&verified) {
+            let Some(verified) = *verified else { break };
+            end = verified;
+            if verified < range.end {
+                break;
+            }
+        }
+        checkpoint
+            .advance(path, header.chunk_offset(end).min(header.file_size), header.chunk_size as usize)
+            .await?;
+        match error {
+            Some(e) => Err(e),
+            None => {
+                bad_chunks.sort_unstable();
+                Ok(bad_chunks)
+            }
+        }
+    }
+}
+
+/// 并行发送时数据流的来源
+pub(crate) enum DataStreams<'a> {
+    /// 直接从连接上接受，连接上同时只有这一个文件
+    Connection(&'a Connection),
+    /// 由 [`receive_files`] 按文件名分发
+    Routed {
+        routes: Routes,
+        file_name: Option<String>,
+        receiver: Option<mpsc::UnboundedReceiver<(DataHeader, RecvStream)>>,
+    },
+}
+
+/// 正在接收的文件名到数据流通道的映射
+type Routes = Arc<Mutex<HashMap<String, mpsc::UnboundedSender<(DataHeader, RecvStream)>>>>;
+
+/// 连接上收到的目录清单，收到后该连接上只接收清单中的文件
+type SharedManifest = Arc<Mutex<Option<Arc<Manifest>>>>;
+
+impl DataStreams<'_> {
+    /// 知道文件名后开始接收这个文件的数据流，同一个文件不能同时接收两次
+    fn register(&mut self, name: &str) -> Result<()> {
+        if let DataStreams::Routed { routes, file_name, receiver } = self {
+            let mut routes = routes.lock().unwrap();
+            anyhow::ensure!(!routes.contains_key(name), "{name} is already being received");
+            let (sender, data_receiver) = mpsc::unbounded_channel();
+            routes.insert(name.to_string(), sender);
+            *file_name = Some(name.to_string());
+            *receiver = Some(data_receiver);
+        }
+        Ok(())
+    }
+
+    /// 下一个数据流和它的开头
+    async fn next(&mut self) -> Result<(DataHeader, RecvStream)> {
+        match self {
+            DataStreams::Connection(connection) => {
+                let (_send_stream, mut recv_stream) = connection.accept_bi().await?;
+                let data_header = DataHeader::read_from(&mut recv_stream).await?;
+                Ok((data_header, recv_stream))
+            }
+            DataStreams::Routed { receiver, .. } => {
+                let receiver = receiver.as_mut().ok_or_else(|| anyhow::anyhow!("data stream before file header"))?;
+                receiver.recv().await.ok_or_else(|| anyhow::anyhow!("connection closed"))
+            }
+        }
+    }
+}
+
+impl Drop for DataStreams<'_> {
+    fn drop(&mut self) {
+        if let DataStreams::Routed { routes, file_name: Some(name), .. } = self {
+            routes.lock().unwrap().remove(name);
+        }
+    }
+}
+
+/// 接收连接上的所有文件，直到连接关闭，每个文件的接收端和结果通过 results 发送
+/// 可以同时接收多个文件，每个文件使用一个 [`FileReceiver`]，并行发送的数据流按文件名分给对应的文件
+pub async fn receive_files(
+    connection: &Connection,
+    output_dir: impl AsRef<Path>,
+    results: mpsc::UnboundedSender<(FileReceiver, Result<PathBuf>)>,
+) -> Result<()> {
+    let routes = Routes::default();
+    let manifest = SharedManifest::default();
+    let mut tasks = JoinSet::new();
+    let res = loop {
+        let (send_stream, recv_stream) = match connection.accept_bi().await {
+            std::result::Result::Ok(streams) => streams,
+            Err(ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed) => break Ok(()),
+            Err(e) => break Err(e.into()),
+        };
+        let output_dir = output_dir.as_ref().to_path_buf();
+        let routes = routes.clone();
+        let manifest = manifest.clone();
+        let results = results.clone();
+        tasks.spawn(async move {
+            if let Some(result) = dispatch_stream(send_stream, recv_stream, output_dir, routes, manifest).await {
+                results.send(result).ok();
+            }
+        });
+    };
+    while tasks.join_next().await.is_some() {}
+    res
+}
+
+/// 按开头区分控制流、目录清单和数据流，控制流接收一个文件，目录清单创建目录，数据流交给正在接收的文件
+/// 收到目录清单后，之后的控制流只接收清单中的文件
+async fn dispatch_stream(
+    mut send_stream: SendStream,
+    mut recv_stream: RecvStream,
+    output_dir: PathBuf,
+    routes: Routes,
+    manifest: SharedManifest,
+) -> Option<(FileReceiver, Result<PathBuf>)> {
+    let mut magic = [0u8; 4];
+    recv_stream.read_exact(&mut magic).await.ok()?;
+    // 把读掉的开头接回去
+    let mut reader = (&magic[..]).chain(recv_stream);
+    if &magic == DATA_MAGIC {
+        let data_header = DataHeader::read_from(&mut reader).await.ok()?;
+        let (_, recv_stream) = reader.into_inner();
+        let sender = routes.lock().unwrap().get(&data_header.file_name).cloned();
+        if let Some(sender) = sender {
+            sender.send((data_header, recv_stream)).ok();
+        }
+        return None;
+    }
+    if &magic == MANIFEST_MAGIC {
+        // 回复之前保存清单，发送端收到回复后才会发送文件
+        accept_manifest(&mut send_stream, &mut reader, &output_dir, |accepted| {
+            let mut manifest = manifest.lock().unwrap();
+            anyhow::ensure!(manifest.is_none(), "manifest was already received on this connection");
+            *manifest = Some(Arc::new(accepted.clone()));
+            Ok(())
+        })
+        .await
+        .ok();
+        let (_, recv_stream) = reader.into_inner();
+        close_control_stream(send_stream, recv_stream).await;
+        return None;
+    }
+    let mut receiver = FileReceiver::new(output_dir);
+    receiver.manifest = manifest.lock().unwrap().clone();
+    let mut data_streams = DataStreams::Routed { routes, file_name: None, receiver: None };
+    let res = receiver.receive_on_stream(Some(&mut data_streams), &mut send_stream, &mut reader).await;
+    drop(data_streams);
+    let (_, recv_stream) = reader.into_inner();
+    close_control_stream(send_stream, recv_stream).await;
+    Some((receiver, res))
+}
+
+/// 读取目录清单，创建清单中的目录后回复发送端
+/// 回复前调用 accepted，返回错误时拒绝这份清单
+pub(crate) async fn accept_manifest(
+    send_stream: &mut (impl AsyncWrite + Unpin),
+    recv_stream: &mut (impl AsyncRead + Unpin),
+    output_dir: &Path,
+    accepted: impl FnOnce(&Manifest) -> Result<()>,
+) -> Result<Manifest> {
+    let res = async {
+        let manifest = Manifest::read_from(recv_stream).await?;
+        for entry in manifest.entries() {
+            if let Some(parent) = output_dir.join(&entry.path).parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+        }
+        accepted(&manifest)?;
+        Ok(manifest)
+    }
+    .await;
+    let ack = match &res {
+        std::result::Result::Ok(_) => Ack::Ok,
+        Err(e) => Ack::Error(format!("{e:#}")),
+    };
+    ack.write_to(send_stream).await?;
+    res
+}
+
+/// 回复发送后关闭控制流
+pub(crate) async fn close_control_stream(mut send_stream: SendStream, mut recv_stream: RecvStream) {
+    // 提前失败时发送端可能还在发送，让它停下来读取回复
+    recv_stream.stop(0u32.into()).ok();
+    // 等待发送端读完回复，发送端已经断开时只返回接收的结果
+    if send_stream.finish().is_ok() {
+        send_stream.stopped().await.ok();
+    }
+}
+
+/// 一个数据流的接收结果
+struct RangeResult {
+    /// 段序号
+    index: usize,
+    /// 从段开头起连续校验通过的分块到这里为止
+    verified: u64,
+    /// 校验失败的分块
+    bad_chunks: Vec<u64>,
+    /// 中途出错时保留已经写入的部分
+    error: Option<anyhow::Error>,
+}
+
+/// 接收一个数据流上的一段分块，写入文件中对应的位置
+async fn receive_range(
+    data_header: DataHeader,
+    mut recv_stream: RecvStream,
+    header: FileHeader,
+    path: PathBuf,
+    ranges: Arc<Vec<Range<u64>>>,
+    received: Arc<AtomicU64>,
+) -> Result<RangeResult> {
+    anyhow::ensure!(
+        data_header.file_name == header.file_name && data_header.hash == header.hash,
+        "data stream belongs to another file"
+    );
+    let index = data_header.range as usize;
+    let range = ranges.get(index).ok_or_else(|| anyhow::anyhow!("invalid range {index}"))?.clone();
+    let mut result = RangeResult { index, verified: range.start, bad_chunks: Vec::new(), error: None };
+    let mut file = OpenOptions::new().write(true).open(&path).await?;
+    file.seek(SeekFrom::Start(header.chunk_offset(range.start))).await?;
+    let mut file = BufWriter::new(file);
+    let mut buffer = vec![0u8; header.chunk_size as usize];
+    for index in range {
+        let chunk = &mut buffer[..header.chunk_len(index)];
+        let valid = match read_chunk(&mut recv_stream, chunk).await {
+            std::result::Result::Ok(valid) => valid,
+            Err(e) => {
+                result.error = Some(e);
+                break;
+            }
+        };
+        file.write_all(chunk).await?;
+        received.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        if !valid {
+            result.bad_chunks.push(index);
+        } else if result.bad_chunks.is_empty() {
+            result.verified = index + 1;
+        }
+    }
+    file.flush().await?;
+    Ok(result)
+}
+
+/// 断点，记录临时文件中已经校验过的前缀，保存在临时文件旁边的 `.resume` 文件
+/// 格式：文件哈希 | 文件大小 u64 | 分块大小 u32 | 已校验字节数 u64 | 前缀哈希
+#[derive(Debug, Clone, Default)]
+struct Checkpoint {
+    /// 已经校验的字节数，总是分块大小的整数倍
+    offset: u64,
+    /// 前缀的哈希状态，继续接收时接着计算整个文件的哈希
+    hasher: blake3::Hasher,
+}
+
+impl Checkpoint {
+    const LEN: usize = 32 + 8 + 4 + 8 + 32;
+
+    /// 从临时文件中读取断点之后到 to 为止的内容，接着计算哈希
+    async fn advance(&mut self, part_path: &Path, to: u64, buffer_size: usize) -> Result<()> {
+        if to <= self.offset {
+            return Ok(());
+        }
+        let mut file = File::open(part_path).await?;
+        file.seek(SeekFrom::Start(self.offset)).await?;
+        let mut reader = BufReader::new(file).take(to - self.offset);
+        let mut buffer = vec![0u8; buffer_size];
+        loop {
+            let bytes_read = reader.read(&mut buffer).await?;
+            if bytes_read == 0 {
+                break;
+            }
+            self.hasher.update(&buffer[..bytes_read]);
+            self.offset += bytes_read as u64;
+        }
+        anyhow::ensure!(self.offset == to, "partial file is shorter than expected");
+        Ok(())
+    }
+
+    fn path(part_path: &Path) -> PathBuf {
+        let mut path = part_path.as_os_str().to_owned();
+        path.push(".resume");
+        PathBuf::from(path)
+    }
+
+    async fn save(&self, header: &FileHeader, part_path: &Path) -> Result<()> {
+        let mut buf = Vec::with_capacity(Self::LEN);
+        buf.extend_from_slice(&header.hash);
+        buf.extend_from_slice(&header.file_size.to_be_bytes());
+        buf.extend_from_slice(&header.chunk_size.to_be_bytes());
+        buf.extend_from_slice(&self.offset.to_be_bytes());
+        buf.extend_from_slice(self.hasher.finalize().as_bytes());
+        tokio::fs::write(Self::path(part_path), buf).await?;
+        Ok(())
+    }
+
+    /// 读取断点，并重新计算临时文件前缀的哈希确认没有变化
+    async fn load(header: &FileHeader, part_path: &Path) -> Result<Self> {
+        let buf = tokio::fs::read(Self::path(part_path)).await?;
+        anyhow::ensure!(buf.len() == Self::LEN, "invalid resume file");
+        let (hash, rest) = buf.split_at(32);
+        let (file_size, rest) = rest.split_at(8);
+        let (chunk_size, rest) = rest.split_at(4);
+        let (offset, prefix_hash) = rest.split_at(8);
+        let offset = u64::from_be_bytes(offset.try_into()?);
+        anyhow::ensure!(
+            hash == header.hash
+                && u64::from_be_bytes(file_size.try_into()?) == header.file_size
+                && u32::from_be_bytes(chunk_size.try_into()?) == header.chunk_size,
+            "resume file is for another version of {}",
+            header.file_name
+        );
+        anyhow::ensure!(
+            offset <= header.file_size && offset.is_multiple_of(header.chunk_size as u64),
+            "invalid resume offset {offset}"
+        );
+        let (hasher, len) = hash_prefix(part_path, offset, header.chunk_size as usize).await?;
+        anyhow::ensure!(
+            len == offset && hasher.finalize().as_bytes() == prefix_hash,
+            "partial file of {} does not match the resume file",
+            header.file_name
+        );
+        Ok(Checkpoint { offset, hasher })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::{io, pin::Pin, task::{Context, Poll}};
+    use crate::file_transfer::{FailureReason, FileTransfer};
+
+    /// 模拟传输故障：在 corrupt_at 翻转一个字节（只损坏一次），写到 cut_at 时断开
+    struct FaultyWriter<W> {
+        inner: W,
+        written: u64,
+        corrupt_at: Option<u64>,
+        cut_at: Option<u64>,
+    }
+
+    impl<W> FaultyWriter<W> {
+        fn new(inner: W) -> Self {
+            Self { inner, written: 0, corrupt_at: None, cut_at: None }
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for FaultyWriter<W> {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            let buf = match this.cut_at.map(|cut| cut.saturating_sub(this.written)) {
+                Some(0) => {
+                    // 关闭底层的流，让对方读到结尾
+                    let _ = Pin::new(&mut this.inner).poll_shutdown(cx);
+                    return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+                }
+                Some(left) => &buf[..buf.len().min(left as usize)],
+                None => buf,
+            };
+            let pos = this.corrupt_at.and_then(|offset| offset.checked_sub(this.written));
+            let res = match pos.filter(|pos| *pos < buf.len() as u64) {
+                Some(pos) => {
+                    let mut data = buf.to_vec();
+                    data[pos as usize] ^= 0xff;
+                    Pin::new(&mut this.inner).poll_write(cx, &data)
+                }
+                None => Pin::new(&mut this.inner).poll_write(cx, buf),
+            };
+            if let Poll::Ready(std::result::Result::Ok(n)) = &res {
+                this.written += *n as u64;
+            }
+            res
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+        }
+    }
+
+    /// 文件头的长度
+    const HEADER_LEN: u64 = 4 + 1 + 2 + "source.bin".len() as u64 + 8 + 4 + 2 + 32;
+
+    /// 每个 64 字节的分块在流上的长度
+    const CHUNK_LEN: u64 = 32 + 64;
+
+    /// 创建 dir/source.bin 和输出目录 dir/out，返回文件内容
+    async fn create_source(dir: &Path) -> Vec<u8> {
+        tokio::fs::create_dir_all(dir.join("out")).await.unwrap();
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        tokio::fs::write(dir.join("source.bin"), &data).await.unwrap();
+        data
+    }
+
+    /// 通过内存中的流发送 dir/source.bin 到 dir/out，发送端写入的数据经过 fault
+    async fn send_through(
+        dir: &Path,
+        repair_rounds: u32,
+        fault: impl FnOnce(&mut FaultyWriter<tokio::io::WriteHalf<tokio::io::DuplexStream>>),
+    ) -> (FileTransfer, FileReceiver, Result<PathBuf>) {
+        let mut receiver = FileReceiver::new(dir.join("out"));
+        receiver.repair_rounds = repair_rounds;
+        send_to(dir, receiver, fault).await
+    }
+
+    /// 通过内存中的流发送 dir/source.bin 给 receiver
+    async fn send_to(
+        dir: &Path,
+        mut receiver: FileReceiver,
+        fault: impl FnOnce(&mut FaultyWriter<tokio::io::WriteHalf<tokio::io::DuplexStream>>),
+    ) -> (FileTransfer, FileReceiver, Result<PathBuf>) {
+        let mut transfer = FileTransfer::new(dir.join("source.bin"), 64);
+        let (client, server) = tokio::io::duplex(4096);
+        let (mut client_read, client_write) = tokio::io::split(client);
+        let (mut server_read, mut server_write) = tokio::io::split(server);
+        let mut client_write = FaultyWriter::new(client_write);
+        fault(&mut client_write);
+        let (sent, received) = tokio::join!(
+            transfer.send_on_stream(None, &mut client_write, &mut client_read),
+            receiver.receive_on_stream(None, &mut server_write, &mut server_read),
+        );
+        assert_eq!(sent.is_ok(), received.is_ok());
+        (transfer, receiver, received)
+    }
+
+    /// 发送一个文件，在第三个分块的内容里损坏一个字节
+    async fn send_corrupted(dir: &Path, repair_rounds: u32) -> (FileTransfer, FileReceiver, Result<PathBuf>) {
+        let data = create_source(dir).await;
+        let res = send_through(dir, repair_rounds, |w| w.corrupt_at = Some(HEADER_LEN + 2 * CHUNK_LEN + 32 + 5)).await;
+        if let std::result::Result::Ok(path) = &res.2 {
+            assert_eq!(tokio::fs::read(path).await.unwrap(), data);
+        }
+        res
+    }
+
+    #[tokio::test]
+    async fn test_repair_corrupted_chunk() {
+        let dir = std::env::temp_dir().join(format!("file-receiver-repair-{}", std::process::id()));
+        let (transfer, receiver, received) = send_corrupted(&dir, DEFAULT_REPAIR_ROUNDS).await;
+        assert!(received.is_ok());
+        assert_eq!(transfer.state(), TransferState::Finished);
+        assert_eq!(receiver.state, TransferState::Finished);
+        assert!(!dir.join("out/source.bin.part").exists());
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reject_corrupted_chunk() {
+        let dir = std::env::temp_dir().join(format!("file-receiver-reject-{}", std::process::id()));
+        let (transfer, receiver, received) = send_corrupted(&dir, 0).await;
+        let err = received.unwrap_err();
+        assert!(err.to_string().contains("1 chunks failed verification"), "{err:#}");
+        assert_eq!(transfer.state(), TransferState::Failed(FailureReason::Corrupted));
+        assert_eq!(receiver.state, TransferState::Failed(FailureReason::Corrupted));
+        // 校验失败时不留下任何文件
+        assert!(!dir.join("out/source.bin").exists());
+        assert!(!dir.join("out/source.bin.part").exists());
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_disconnect() {
+        let dir = std::env::temp_dir().join(format!("file-receiver-resume-{}", std::process::id()));
+        let data = create_source(&dir).await;
+        // 第六个分块发送到一半时断开
+        let cut_at = HEADER_LEN + 5 * CHUNK_LEN + 10;
+        let (transfer, receiver, received) = send_through(&dir, 0, |w| w.cut_at = Some(cut_at)).await;
+        assert!(received.is_err());
+        assert_eq!(transfer.state(), TransferState::Failed(FailureReason::Error));
+        assert_eq!(receiver.state, TransferState::Failed(FailureReason::Error));
+        assert!(dir.join("out/source.bin.part.resume").exists());
+
+        // 重新连接后从已经校验的 5 个分块之后继续
+        let (transfer, receiver, received) = send_through(&dir, 0, |_| {}).await;
+        assert_eq!(transfer.offset, 5 * 64);
+        assert_eq!(receiver.state, TransferState::Finished);
+        assert_eq!(tokio::fs::read(received.unwrap()).await.unwrap(), data);
+        assert!(!dir.join("out/source.bin.part").exists());
+        assert!(!dir.join("out/source.bin.part.resume").exists());
+
+        // 临时文件被改动过时从头开始
+        tokio::fs::remove_file(dir.join("out/source.bin")).await.unwrap();
+        assert!(send_through(&dir, 0, |w| w.cut_at = Some(cut_at)).await.2.is_err());
+        let part = dir.join("out/source.bin.part");
+        let mut partial = tokio::fs::read(&part).await.unwrap();
+        partial[100] ^= 0xff;
+        tokio::fs::write(&part, partial).await.unwrap();
+        let (transfer, _, received) = send_through(&dir, 0, |_| {}).await;
+        assert_eq!(transfer.offset, 0);
+        assert_eq!(tokio::fs::read(received.unwrap()).await.unwrap(), data);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_existing_target() {
+        let dir = std::env::temp_dir().join(format!("file-receiver-existing-{}", std::process::id()));
+        let data = create_source(&dir).await;
+        let target = dir.join("out/source.bin");
+        tokio::fs::write(&target, b"old").await.unwrap();
+
+        // 默认不覆盖已有的文件
+        let (transfer, _, received) = send_through(&dir, 0, |_| {}).await;
+        let err = received.unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{err:#}");
+        // 发送端收到拒绝，没有发送文件内容
+        assert_eq!(transfer.state(), TransferState::Failed(FailureReason::Rejected));
+        assert_eq!(tokio::fs::read(&target).await.unwrap(), b"old");
+        assert!(!dir.join("out/source.bin.part").exists());
+
+        let mut receiver = FileReceiver::new(dir.join("out"));
+        receiver.overwrite = true;
+        let (_, _, received) = send_to(&dir, receiver, |_| {}).await;
+        assert_eq!(received.unwrap(), target);
+        assert_eq!(tokio::fs::read(&target).await.unwrap(), data);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
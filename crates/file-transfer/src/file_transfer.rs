@@ -1,11 +1,16 @@
-use std::{ffi::OsStr, path::{Path, PathBuf}};
+use std::{ffi::OsStr, io::SeekFrom, ops::Range, path::{Path, PathBuf}, sync::Arc, time::SystemTime};
 use anyhow::{Ok, Result};
-use tokio::{fs::File, io::AsyncReadExt};
+use tokio::{fs::File, io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt}};
 use tokio::io::BufReader;
-use quinn::SendStream;
-use bytes::{Bytes, BytesMut};
-use std::net::{SocketAddr, IpAddr, Ipv4Addr};
-use quic_transport::quic_endpoint::ClientEndpoint;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use quinn::Connection;
+
+use crate::progress::{CancelledError, Control, Reporter, TransferEvent, TransferHandle};
+use crate::protocol::{
+    hash_file, hash_prefix, write_chunk, Ack, DataHeader, FileHeader, IntegrityError, RejectedError, Trailer,
+    MAX_CHUNK_SIZE, MAX_STREAMS,
+};
 
 
 
@@ -14,27 +19,110 @@ use quic_transport::quic_endpoint::ClientEndpoint;
 pub struct FileTransfer {
     /// 文件路径
     pub file_path: PathBuf,
-    /// 文件名
+    /// 文件名，发送目录时是包含目录名的相对路径
     pub file_name: String,
     /// 分块大小（字节）
     pub chunk_size: usize,
+    /// 并行发送分块的数据流数量，为 1 时在控制流上顺序发送
+    pub streams: usize,
     /// 文件大小（字节）
     pub file_size: usize,
-    /// 传输状态
-    pub state: TransferState,
+    /// 本次从哪个偏移开始发送，接收端已经有了前面的内容时不为 0
+    pub offset: u64,
+    /// 传输状态，与控制句柄共享，见 [`FileTransfer::state`]
+    state: watch::Sender<TransferState>,
+    /// 事件通道，见 [`FileTransfer::events`]
+    events: Option<mpsc::UnboundedSender<TransferEvent>>,
+    /// 控制句柄共用的命令
+    control: watch::Sender<Control>,
 }
 
-#[derive(Debug, PartialEq)]
+/// 传输状态
+/// Queued -> Transferring <-> Paused，Transferring 和 Paused 之后是 Finished、Failed 或者 Cancelled
+/// 还没开始时也可能失败或者取消，失败后可以重新排队或者直接重试
+#[derive(Debug, Clone, PartialEq)]
 pub enum TransferState {
     /// 等待开始
     Queued,
     /// 传输中
     Transferring,
+    /// 已暂停
+    Paused,
     /// 传输完成
     Finished,
     /// 传输失败
-    Failed,
+    Failed(FailureReason),
+    /// 已取消
+    Cancelled,
+}
+
+/// 传输失败的原因
+#[derive(Debug, Clone, PartialEq)]
+pub enum FailureReason {
+    /// 数据校验失败，文件内容损坏
+    Corrupted,
+    /// 接收端拒绝了文件
+    Rejected,
+    /// 连接断开
+    ConnectionLost,
+    /// 读写文件等其他错误
+    Error,
+}
+
+impl FailureReason {
+    /// 根据错误判断失败原因
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        if error.downcast_ref::<IntegrityError>().is_some() {
+            FailureReason::Corrupted
+        } else if error.downcast_ref::<RejectedError>().is_some() {
+            FailureReason::Rejected
+        } else if error.chain().any(is_connection_error) {
+            FailureReason::ConnectionLost
+        } else {
+            FailureReason::Error
+        }
+    }
+}
+
+/// 是否是 quinn 的连接或者流出错，读写流的错误会包装在 io::Error 中
+fn is_connection_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    let error = match error.downcast_ref::<std::io::Error>().and_then(|e| e.get_ref()) {
+        Some(inner) => inner as &(dyn std::error::Error + 'static),
+        None => error,
+    };
+    error.is::<quinn::ConnectionError>()
+        || error.is::<quinn::ReadError>()
+        || error.is::<quinn::ReadExactError>()
+        || error.is::<quinn::WriteError>()
+        || error.is::<quinn::ClosedStream>()
+}
+
+impl TransferState {
+    /// 根据传输结果得到结束时的状态
+    pub fn from_result<T>(res: &Result<T>) -> Self {
+        match res {
+            std::result::Result::Ok(_) => TransferState::Finished,
+            Err(e) if e.downcast_ref::<CancelledError>().is_some() => TransferState::Cancelled,
+            Err(e) => TransferState::Failed(FailureReason::from_error(e)),
+        }
+    }
+
+    /// 是否可以从当前状态变为 next
+    pub fn can_transition_to(&self, next: &TransferState) -> bool {
+        use TransferState::*;
+        matches!(
+            (self, next),
+            (Queued, Transferring | Failed(_) | Cancelled)
+                | (Transferring, Paused | Finished | Failed(_) | Cancelled)
+                | (Paused, Transferring | Finished | Failed(_) | Cancelled)
+                | (Failed(_), Queued | Transferring)
+        )
+    }
 
+    /// 是否已经结束，不会再变化
+    pub fn is_done(&self) -> bool {
+        matches!(self, TransferState::Finished | TransferState::Cancelled)
+    }
 }
 
 
@@ -45,12 +133,63 @@ impl FileTransfer {
         Self{
             file_path: path.to_path_buf(),
             file_name: path.file_name().unwrap_or(OsStr::new("未命名")).to_string_lossy().to_string(),
-            chunk_size: chunk_size,
+            chunk_size,
+            streams: 1,
             file_size: path.metadata().map(|m| m.len() as usize).unwrap_or(0),
-            state: TransferState::Queued,
+            offset: 0,
+            state: watch::Sender::new(TransferState::Queued),
+            events: None,
+            control: watch::Sender::new(Control::Running),
+        }
+    }
+
+    /// 订阅传输事件，包括状态变化和发送进度，只保留最后一次订阅
+    pub fn events(&mut self) -> mpsc::UnboundedReceiver<TransferEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.events = Some(sender);
+        receiver
+    }
+
+    /// 复制传输的参数，与原来的传输共享状态和控制命令，不复制事件通道
+    /// 原来的传输移到单独的任务中发送时留在原处，任务异常结束后用它继续
+    pub(crate) fn detach(&self) -> FileTransfer {
+        Self {
+            file_path: self.file_path.clone(),
+            file_name: self.file_name.clone(),
+            chunk_size: self.chunk_size,
+            streams: self.streams,
+            file_size: self.file_size,
+            offset: self.offset,
+            state: self.state.clone(),
+            events: None,
+            control: self.control.clone(),
         }
     }
 
+    /// 控制句柄，可以在其他任务中暂停、继续或者取消传输
+    pub fn handle(&self) -> TransferHandle {
+        TransferHandle { control: self.control.clone(), state: self.state.clone() }
+    }
+
+    /// 当前的传输状态，传输中可以用 [`TransferHandle::state`] 在其他任务中查看
+    pub fn state(&self) -> TransferState {
+        self.state.borrow().clone()
+    }
+
+    /// 变更状态并发送事件，不允许的变化不生效也不发送事件，返回 false
+    pub(crate) fn set_state(&mut self, state: TransferState) -> bool {
+        self.state.send_if_modified(|current| {
+            if !current.can_transition_to(&state) {
+                return false;
+            }
+            if let Some(events) = &self.events {
+                events.send(TransferEvent::State(state.clone())).ok();
+            }
+            *current = state;
+            true
+        })
+    }
+
     /// 打开文件
     async fn open_file(&self) -> Result<File> {
         // 打开文件
@@ -58,30 +197,173 @@ impl FileTransfer {
         Ok(file)
     }
 
-    /// 发送文件
-    async fn send_file(&self, mut send_stream: SendStream) -> Result<()> {
-        // 打开文件
-        let file = self.open_file().await?;
-        // 读取文件
-        let mut reader = BufReader::new(file);
-        // let mut buffer = vec![0u8; self.chunk_size];
-        let mut buffer = BytesMut::with_capacity(self.chunk_size);
+    /// 在连接上打开一个双向流发送文件，等待接收端确认已经保存
+    /// 发送文件头、文件内容和结尾，结束后更新传输状态
+    pub async fn send_file(&mut self, connection: &Connection) -> Result<()> {
+        let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+        self.send_on_stream(Some(connection), &mut send_stream, &mut recv_stream).await
+    }
+
+    /// 在已经打开的控制流上发送文件，校验失败时状态为 `Failed(Corrupted)`，取消时为 `Cancelled`
+    /// 使用多个数据流时需要 connection 打开数据流
+    pub(crate) async fn send_on_stream(
+        &mut self,
+        connection: Option<&Connection>,
+        send_stream: &mut (impl AsyncWrite + Unpin),
+        recv_stream: &mut (impl AsyncRead + Unpin),
+    ) -> Result<()> {
+        let state = self.state();
+        anyhow::ensure!(
+            state.can_transition_to(&TransferState::Transferring),
+            "cannot send {} in state {:?}",
+            self.file_name,
+            state
+        );
+        self.set_state(TransferState::Transferring);
+        let reporter = Arc::new(Reporter::new(self.events.clone(), self.control.subscribe(), self.state.clone()));
+        // 取消时立即停止，不等待当前的读写
+        let res = tokio::select! {
+            res = self.send_inner(connection, send_stream, recv_stream, &reporter) => res,
+            _ = reporter.cancelled() => Err(CancelledError.into()),
+        };
+        self.set_state(TransferState::from_result(&res));
+        res
+    }
+
+    async fn send_inner(
+        &mut self,
+        connection: Option<&Connection>,
+        send_stream: &mut (impl AsyncWrite + Unpin),
+        recv_stream: &mut (impl AsyncRead + Unpin),
+        reporter: &Arc<Reporter>,
+    ) -> Result<()> {
+        let chunk_size = u32::try_from(self.chunk_size)
+            .ok()
+            .filter(|size| *size > 0 && *size <= MAX_CHUNK_SIZE)
+            .ok_or_else(|| anyhow::anyhow!("invalid chunk size {}", self.chunk_size))?;
+        let streams = u16::try_from(self.streams)
+            .ok()
+            .filter(|streams| *streams > 0 && *streams <= MAX_STREAMS)
+            .ok_or_else(|| anyhow::anyhow!("invalid stream count {}", self.streams))?;
+        // 发送时重新获取大小，创建后文件可能发生变化
+        let version = self.file_version().await?;
+        self.file_size = version.0 as usize;
+        let header = FileHeader {
+            file_name: self.file_name.clone(),
+            file_size: self.file_size as u64,
+            chunk_size,
+            streams,
+            hash: hash_file(&self.file_path, self.chunk_size).await?,
+        };
+        header.write_to(send_stream).await?;
+        self.offset = match Ack::read_from(recv_stream).await? {
+            Ack::Resume(offset) => offset,
+            Ack::Error(message) => return Err(RejectedError(format!("receiver rejected {}: {message}", self.file_name)).into()),
+            ack => return Err(anyhow::anyhow!("unexpected reply from receiver: {ack:?}")),
+        };
+        anyhow::ensure!(
+            self.offset <= header.file_size && self.offset.is_multiple_of(chunk_size as u64),
+            "receiver requested invalid resume offset {}",
+            self.offset
+        );
+        reporter.start(self.offset, header.file_size);
+        let trailer = if streams > 1 {
+            let connection = connection.ok_or_else(|| anyhow::anyhow!("sending over several streams needs a connection"))?;
+            self.send_ranges(connection, &header, reporter).await?;
+            // 并行发送时不再计算整个文件的哈希，大小和修改时间不变时认为文件没有变化
+            anyhow::ensure!(
+                self.file_version().await? == version,
+                "{} changed while it was being sent",
+                self.file_name
+            );
+            Trailer { file_size: header.file_size, hash: header.hash }
+        } else {
+            self.send_data(send_stream, &header, reporter).await?
+        };
+        reporter.send_progress();
+        trailer.write_to(send_stream).await?;
+        // 接收端校验失败时会要求重发部分分块，直到确认或者拒绝
         loop {
-            // 重用缓冲区空间
-            buffer.clear();
-            let bytes_read = reader.read_buf(&mut buffer).await?;
-            if bytes_read == 0 {
-                break;
+            match Ack::read_from(recv_stream).await? {
+                Ack::Ok => break,
+                Ack::Resend(chunks) => self.resend_chunks(send_stream, &header, &chunks).await?,
+                Ack::Corrupted(message) => {
+                    return Err(IntegrityError(format!("receiver rejected {}: {message}", self.file_name)).into())
+                }
+                Ack::Error(message) => {
+                    return Err(RejectedError(format!("receiver rejected {}: {message}", self.file_name)).into())
+                }
+                ack => return Err(anyhow::anyhow!("unexpected reply from receiver: {ack:?}")),
             }
-            let chunk: Bytes = buffer.split().freeze();
-            send_stream.write_chunks(&mut [chunk]).await?;
-            // 发送数据
-            println!("发送数据: {:?}", &buffer[..bytes_read]);
         }
-        let finish = send_stream.finish();
-        if finish.is_err() {
-            println!("发送数据失败: {:?}", finish);
-            return Err(anyhow::anyhow!("发送文件失败"));
+        send_stream.shutdown().await.ok();
+        Ok(())
+    }
+
+    /// 从 offset 开始按分块发送文件内容，每块带 BLAKE3 哈希，返回文件大小和整个文件的哈希
+    async fn send_data(
+        &self,
+        send_stream: &mut (impl AsyncWrite + Unpin),
+        header: &FileHeader,
+        reporter: &Reporter,
+    ) -> Result<Trailer> {
+        // 跳过的部分只计算哈希
+        let (mut hasher, len) = hash_prefix(&self.file_path, self.offset, self.chunk_size).await?;
+        anyhow::ensure!(len == self.offset, "{} changed while it was being sent", self.file_name);
+        // 打开文件，从断点开始读取
+        let mut file = self.open_file().await?;
+        file.seek(SeekFrom::Start(self.offset)).await?;
+        let mut reader = BufReader::new(file);
+        let mut buffer = vec![0u8; self.chunk_size];
+        for index in self.offset / self.chunk_size as u64..header.chunk_count() {
+            reporter.wait_if_paused().await?;
+            let chunk = &mut buffer[..header.chunk_len(index)];
+            reader.read_exact(chunk).await?;
+            hasher.update(chunk);
+            write_chunk(send_stream, chunk).await?;
+            reporter.add(chunk.len() as u64);
+        }
+        Ok(Trailer { file_size: header.file_size, hash: *hasher.finalize().as_bytes() })
+    }
+
+    /// 把断点之后的分块分成几段，每段在单独的数据流上并行发送
+    async fn send_ranges(&self, connection: &Connection, header: &FileHeader, reporter: &Arc<Reporter>) -> Result<()> {
+        let mut tasks = JoinSet::new();
+        for (index, range) in header.ranges(self.offset / self.chunk_size as u64).into_iter().enumerate() {
+            let connection = connection.clone();
+            let path = self.file_path.clone();
+            let header = header.clone();
+            let reporter = reporter.clone();
+            tasks.spawn(async move { send_range(&connection, &path, &header, index as u16, range, &reporter).await });
+        }
+        // 任何一段失败时丢弃 tasks，取消其他段
+        while let Some(res) = tasks.join_next().await {
+            res??;
+        }
+        Ok(())
+    }
+
+    /// 文件的大小和修改时间
+    async fn file_version(&self) -> Result<(u64, Option<SystemTime>)> {
+        let metadata = self.open_file().await?.metadata().await?;
+        Ok((metadata.len(), metadata.modified().ok()))
+    }
+
+    /// 重新读取并发送校验失败的分块
+    async fn resend_chunks(
+        &self,
+        send_stream: &mut (impl AsyncWrite + Unpin),
+        header: &FileHeader,
+        chunks: &[u64],
+    ) -> Result<()> {
+        let mut file = self.open_file().await?;
+        let mut buffer = vec![0u8; self.chunk_size];
+        for &index in chunks {
+            anyhow::ensure!(index < header.chunk_count(), "receiver requested invalid chunk {index}");
+            let chunk = &mut buffer[..header.chunk_len(index)];
+            file.seek(SeekFrom::Start(header.chunk_offset(index))).await?;
+            file.read_exact(chunk).await?;
+            write_chunk(send_stream, chunk).await?;
         }
         Ok(())
     }
@@ -89,10 +371,42 @@ impl FileTransfer {
 }
 
 
+/// 打开一个数据流，发送文件的一段分块
+async fn send_range(
+    connection: &Connection,
+    path: &Path,
+    header: &FileHeader,
+    index: u16,
+    range: Range<u64>,
+    reporter: &Reporter,
+) -> Result<()> {
+    let (mut send_stream, _recv_stream) = connection.open_bi().await?;
+    let data_header = DataHeader { file_name: header.file_name.clone(), hash: header.hash, range: index };
+    data_header.write_to(&mut send_stream).await?;
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(header.chunk_offset(range.start))).await?;
+    let mut reader = BufReader::new(file);
+    let mut buffer = vec![0u8; header.chunk_size as usize];
+    for index in range {
+        reporter.wait_if_paused().await?;
+        let chunk = &mut buffer[..header.chunk_len(index)];
+        reader.read_exact(chunk).await?;
+        write_chunk(&mut send_stream, chunk).await?;
+        reporter.add(chunk.len() as u64);
+    }
+    send_stream.finish()?;
+    Ok(())
+}
+
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+    use quic_transport::quic_endpoint::{generate_self_signed, ClientEndpoint, ServerEndpointContainer};
+    use std::time::Duration;
+    use crate::file_receiver::FileReceiver;
 
     #[test]
     fn test_new_file_transfer() {
@@ -100,7 +414,7 @@ mod tests {
         println!("文件:{:?}", file_transfer);
         assert_eq!(file_transfer.file_name, "test.txt");
         assert_eq!(file_transfer.chunk_size, 1024);
-        assert_eq!(file_transfer.state, TransferState::Queued);
+        assert_eq!(file_transfer.state(), TransferState::Queued);
     }
 
     #[tokio::test]
@@ -113,18 +427,278 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    /// 在本机回环上创建服务器和客户端，把文件发送到 output_dir，返回接收端和保存的路径
+    async fn send_over_loopback(file_transfer: &mut FileTransfer, output_dir: &Path) -> (FileReceiver, Result<PathBuf>) {
+        let (cert, key) = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let server_endpoint = ServerEndpointContainer::with_cert(addr, cert.clone(), key).unwrap();
+        let client_endpoint = ClientEndpoint::with_cert(addr, cert).unwrap();
+        let server = server_endpoint.get_endpoint().clone();
+        let receiver_dir = output_dir.to_path_buf();
+        let receiver = tokio::spawn(async move {
+            let connection = server.accept().await.unwrap().await.unwrap();
+            let mut receiver = FileReceiver::new(receiver_dir);
+            let path = receiver.receive_file(&connection).await;
+            (receiver, path)
+        });
+        let connection = client_endpoint
+            .get_endpoint()
+            .connect(server_endpoint.get_bind_addr(), "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let result = file_transfer.send_file(&connection).await;
+        println!("发送文件结果:{:?}", result);
+        assert!(result.is_ok());
+        receiver.await.unwrap()
+    }
+
     #[tokio::test]
     async fn test_send_file() {
         // 读取文件
-        let file_transfer = FileTransfer::new("../../test.txt", 64);
+        let mut file_transfer = FileTransfer::new("../../test.txt", 64);
         println!("文件:{:?}", file_transfer);
-        // 创建客户端
-        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
-        ClientEndpoint::new(addr1, server_certs);
-        let result = file_transfer.send_file().await;
-        println!("发送文件结果:{:?}", result);
-    
-        assert!(result.is_ok());
+        let output_dir = std::env::temp_dir().join(format!("file-transfer-test-{}", std::process::id()));
+        tokio::fs::create_dir_all(&output_dir).await.unwrap();
+
+        let (receiver, path) = send_over_loopback(&mut file_transfer, &output_dir).await;
+        assert_eq!(file_transfer.state(), TransferState::Finished);
+        let path = path.unwrap();
+        assert_eq!(receiver.state, TransferState::Finished);
+        assert_eq!(receiver.received, file_transfer.file_size as u64);
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), tokio::fs::read("../../test.txt").await.unwrap());
+        tokio::fs::remove_dir_all(output_dir).await.unwrap();
+    }
+
+    /// 创建 dir/source.bin，返回文件内容
+    async fn create_source(dir: &Path, size: usize) -> Vec<u8> {
+        tokio::fs::create_dir_all(dir.join("out")).await.unwrap();
+        let data: Vec<u8> = (0..size).map(|i| (i * 31 / 7) as u8).collect();
+        tokio::fs::write(dir.join("source.bin"), &data).await.unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn test_send_file_streams() {
+        let dir = std::env::temp_dir().join(format!("file-transfer-streams-{}", std::process::id()));
+        // 最后一块不满
+        let data = create_source(&dir, 1024 * 1024 + 100).await;
+        let mut file_transfer = FileTransfer::new(dir.join("source.bin"), 16 * 1024);
+        file_transfer.streams = 4;
+        let (receiver, path) = send_over_loopback(&mut file_transfer, &dir.join("out")).await;
+        assert_eq!(file_transfer.state(), TransferState::Finished);
+        assert_eq!(receiver.state, TransferState::Finished);
+        assert_eq!(receiver.received, data.len() as u64);
+        assert_eq!(tokio::fs::read(path.unwrap()).await.unwrap(), data);
+        assert!(!dir.join("out/source.bin.part").exists());
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    /// 通过内存中的流发送 dir/source.bin 到 dir/out，同时运行 control 读取事件控制传输
+    async fn send_in_memory<T>(
+        mut file_transfer: FileTransfer,
+        dir: &Path,
+        control: impl std::future::Future<Output = T>,
+    ) -> (FileTransfer, Result<()>, Result<PathBuf>, T) {
+        let mut receiver = FileReceiver::new(dir.join("out"));
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let send = async move {
+            let (mut client_read, mut client_write) = tokio::io::split(client);
+            let res = file_transfer.send_on_stream(None, &mut client_write, &mut client_read).await;
+            // 结束后关闭流，让接收端读到结尾
+            (file_transfer, res)
+        };
+        let receive = async {
+            let (mut server_read, mut server_write) = tokio::io::split(server);
+            receiver.receive_on_stream(None, &mut server_write, &mut server_read).await
+        };
+        let ((file_transfer, sent), received, output) = tokio::join!(send, receive, control);
+        (file_transfer, sent, received, output)
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume() {
+        let dir = std::env::temp_dir().join(format!("file-transfer-pause-{}", std::process::id()));
+        let data = create_source(&dir, 1024 * 1024).await;
+        let mut file_transfer = FileTransfer::new(dir.join("source.bin"), 16 * 1024);
+        let mut events = file_transfer.events();
+        let handle = file_transfer.handle();
+        // 开始之前暂停，收到暂停事件后继续
+        handle.pause();
+        let control = async move {
+            let mut all = Vec::new();
+            while let Some(event) = events.recv().await {
+                if event == TransferEvent::State(TransferState::Paused) {
+                    handle.resume();
+                }
+                let done = matches!(&event, TransferEvent::State(state) if state.is_done());
+                all.push(event);
+                if done {
+                    break;
+                }
+            }
+            all
+        };
+        let (file_transfer, sent, received, events) = send_in_memory(file_transfer, &dir, control).await;
+        sent.unwrap();
+        assert_eq!(file_transfer.state(), TransferState::Finished);
+        assert_eq!(tokio::fs::read(received.unwrap()).await.unwrap(), data);
+        let states: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                TransferEvent::State(state) => Some(state.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            states,
+            vec![TransferState::Transferring, TransferState::Paused, TransferState::Transferring, TransferState::Finished]
+        );
+        let Some(TransferEvent::Progress(progress)) = events.iter().rev().nth(1) else {
+            panic!("no progress before finishing: {events:?}");
+        };
+        assert_eq!(progress.bytes, data.len() as u64);
+        assert_eq!(progress.total, data.len() as u64);
+        assert_eq!(progress.eta, Some(Duration::ZERO));
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_state_while_paused() {
+        let dir = std::env::temp_dir().join(format!("file-transfer-paused-state-{}", std::process::id()));
+        let data = create_source(&dir, 256 * 1024).await;
+        let mut file_transfer = FileTransfer::new(dir.join("source.bin"), 16 * 1024);
+        let mut events = file_transfer.events();
+        let handle = file_transfer.handle();
+        handle.pause();
+        let control = async move {
+            let mut states = Vec::new();
+            while let Some(event) = events.recv().await {
+                let TransferEvent::State(state) = event else { continue };
+                // 事件和状态一致，暂停中查看到的也是 Paused
+                states.push(handle.state());
+                assert_eq!(handle.state(), state);
+                if state == TransferState::Paused {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    assert_eq!(handle.state(), TransferState::Paused);
+                    handle.resume();
+                }
+                if state.is_done() {
+                    break;
+                }
+            }
+            states
+        };
+        let (file_transfer, sent, received, states) = send_in_memory(file_transfer, &dir, control).await;
+        sent.unwrap();
+        assert_eq!(tokio::fs::read(received.unwrap()).await.unwrap(), data);
+        assert_eq!(
+            states,
+            vec![TransferState::Transferring, TransferState::Paused, TransferState::Transferring, TransferState::Finished]
+        );
+        assert_eq!(file_transfer.state(), TransferState::Finished);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let dir = std::env::temp_dir().join(format!("file-transfer-cancel-{}", std::process::id()));
+        create_source(&dir, 1024 * 1024).await;
+        let mut file_transfer = FileTransfer::new(dir.join("source.bin"), 16 * 1024);
+        let mut events = file_transfer.events();
+        let handle = file_transfer.handle();
+        handle.pause();
+        // 暂停中取消
+        let control = async move {
+            while let Some(event) = events.recv().await {
+                match event {
+                    TransferEvent::State(TransferState::Paused) => handle.cancel(),
+                    TransferEvent::State(state) if state.is_done() => break,
+                    _ => {}
+                }
+            }
+        };
+        let (mut file_transfer, sent, received, ()) = send_in_memory(file_transfer, &dir, control).await;
+        assert!(sent.unwrap_err().is::<CancelledError>());
+        assert_eq!(file_transfer.state(), TransferState::Cancelled);
+        assert!(received.is_err());
+        assert!(!dir.join("out/source.bin").exists());
+        // 取消后不能再发送
+        let (client, _server) = tokio::io::duplex(1024);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        assert!(file_transfer.send_on_stream(None, &mut client_write, &mut client_read).await.is_err());
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[test]
+    fn test_failure_reason() {
+        let lost = anyhow::Error::from(std::io::Error::from(quinn::ReadError::ConnectionLost(
+            quinn::ConnectionError::TimedOut,
+        )));
+        assert_eq!(FailureReason::from_error(&lost), FailureReason::ConnectionLost);
+        let rejected = anyhow::Error::from(RejectedError("disk full".to_string()));
+        assert_eq!(FailureReason::from_error(&rejected), FailureReason::Rejected);
+        let corrupted = anyhow::Error::from(IntegrityError("hash mismatch".to_string())).context("receiving");
+        assert_eq!(FailureReason::from_error(&corrupted), FailureReason::Corrupted);
+        let other = anyhow::anyhow!("no such file");
+        assert_eq!(FailureReason::from_error(&other), FailureReason::Error);
+        assert!(TransferState::Failed(FailureReason::Error).can_transition_to(&TransferState::Queued));
+        assert!(!TransferState::Finished.can_transition_to(&TransferState::Transferring));
+        assert!(!TransferState::Queued.can_transition_to(&TransferState::Paused));
+    }
+
+    #[test]
+    fn test_rejected_transitions() {
+        let mut file_transfer = FileTransfer::new("test.txt", 1024);
+        let mut events = file_transfer.events();
+        let handle = file_transfer.handle();
+        // 还没开始时不能暂停或者完成
+        assert!(!file_transfer.set_state(TransferState::Paused));
+        assert!(!file_transfer.set_state(TransferState::Finished));
+        assert_eq!(handle.state(), TransferState::Queued);
+        assert!(file_transfer.set_state(TransferState::Transferring));
+        assert!(file_transfer.set_state(TransferState::Finished));
+        // 完成和取消之后不再变化，也不发送事件
+        assert!(!file_transfer.set_state(TransferState::Transferring));
+        assert!(!file_transfer.set_state(TransferState::Failed(FailureReason::Error)));
+        assert_eq!(handle.state(), TransferState::Finished);
+        let mut cancelled = FileTransfer::new("test.txt", 1024);
+        assert!(cancelled.set_state(TransferState::Cancelled));
+        assert!(!cancelled.set_state(TransferState::Queued));
+        assert!(!cancelled.set_state(TransferState::Transferring));
+        assert_eq!(cancelled.state(), TransferState::Cancelled);
+
+        let mut received = Vec::new();
+        while let std::result::Result::Ok(TransferEvent::State(state)) = events.try_recv() {
+            received.push(state);
+        }
+        assert_eq!(received, [TransferState::Transferring, TransferState::Finished]);
+    }
+
+    /// 比较不同分块大小下单个流和多个流的吞吐量
+    /// cargo test -p file-transfer --release -- --ignored --nocapture bench_streams
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_streams() {
+        let dir = std::env::temp_dir().join(format!("file-transfer-bench-{}", std::process::id()));
+        let size = 64 * 1024 * 1024;
+        create_source(&dir, size).await;
+        let mut results = Vec::new();
+        for chunk_size in [16 * 1024, 64 * 1024, 256 * 1024, 1024 * 1024] {
+            for streams in [1, 4, 8] {
+                let mut file_transfer = FileTransfer::new(dir.join("source.bin"), chunk_size);
+                file_transfer.streams = streams;
+                let start = std::time::Instant::now();
+                let (_, path) = send_over_loopback(&mut file_transfer, &dir.join("out")).await;
+                let elapsed = start.elapsed();
+                tokio::fs::remove_file(path.unwrap()).await.unwrap();
+                results.push((chunk_size, streams, size as f64 / elapsed.as_secs_f64() / 1024.0 / 1024.0));
+            }
+        }
+        for (chunk_size, streams, rate) in results {
+            eprintln!("chunk_size={:>7} streams={streams}: {rate:.1} MiB/s", chunk_size);
+        }
+        tokio::fs::remove_dir_all(dir).await.unwrap();
     }
 
 }
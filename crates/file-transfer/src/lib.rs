@@ -1 +1,6 @@
+pub mod directory;
+pub mod file_receiver;
 pub mod file_transfer;
+pub mod progress;
+pub mod protocol;
+pub mod transfer_queue;
@@ -0,0 +1,197 @@
+use std::sync::{atomic::{AtomicU64, Ordering}, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use tokio::sync::{mpsc, watch};
+
+use crate::file_transfer::TransferState;
+
+/// 两次进度事件之间的最小间隔
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 传输过程中的事件
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransferEvent {
+    /// 状态变化
+    State(TransferState),
+    /// 发送进度
+    Progress(Progress),
+}
+
+/// 发送进度
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// 已经发送的字节数，包括续传时接收端已经有的部分
+    pub bytes: u64,
+    /// 文件大小
+    pub total: u64,
+    /// 本次发送的平均速度（字节/秒）
+    pub rate: f64,
+    /// 预计剩余时间，还不知道速度时为 None
+    pub eta: Option<Duration>,
+}
+
+/// 控制命令
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Control {
+    Running,
+    Paused,
+    Cancelled,
+}
+
+/// 传输的控制句柄，可以在其他任务中暂停、继续或者取消传输，也可以查看传输状态
+#[derive(Debug, Clone)]
+pub struct TransferHandle {
+    pub(crate) control: watch::Sender<Control>,
+    pub(crate) state: watch::Sender<TransferState>,
+}
+
+impl TransferHandle {
+    /// 当前的传输状态，暂停后发送端停下来时为 Paused
+    pub fn state(&self) -> TransferState {
+        self.state.borrow().clone()
+    }
+
+    /// 暂停，当前分块发送完后停下来
+    pub fn pause(&self) {
+        self.control.send_if_modified(|control| {
+            let paused = *control == Control::Running;
+            if paused {
+                *control = Control::Paused;
+            }
+            paused
+        });
+    }
+
+    /// 继续暂停的传输
+    pub fn resume(&self) {
+        self.control.send_if_modified(|control| {
+            let resumed = *control == Control::Paused;
+            if resumed {
+                *control = Control::Running;
+            }
+            resumed
+        });
+    }
+
+    /// 取消传输，取消后不能再继续
+    pub fn cancel(&self) {
+        self.control.send_replace(Control::Cancelled);
+    }
+}
+
+/// 传输被取消的错误
+#[derive(Debug)]
+pub struct CancelledError;
+
+impl std::fmt::Display for CancelledError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("transfer cancelled")
+    }
+}
+
+impl std::error::Error for CancelledError {}
+
+/// 发送过程中共享的进度和控制，多个数据流共用
+#[derive(Debug)]
+pub(crate) struct Reporter {
+    events: Option<mpsc::UnboundedSender<TransferEvent>>,
+    control: watch::Receiver<Control>,
+    /// 与传输共享的状态，暂停和继续时更新
+    state: watch::Sender<TransferState>,
+    /// 续传时接收端已经有的字节数
+    offset: AtomicU64,
+    total: AtomicU64,
+    /// 本次发送的字节数
+    sent: AtomicU64,
+    start: Instant,
+    last: Mutex<Instant>,
+}
+
+impl Reporter {
+    pub(crate) fn new(
+        events: Option<mpsc::UnboundedSender<TransferEvent>>,
+        control: watch::Receiver<Control>,
+        state: watch::Sender<TransferState>,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            events,
+            control,
+            state,
+            offset: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            start: now,
+            last: Mutex::new(now),
+        }
+    }
+
+    /// 知道文件大小和续传的偏移后开始计算进度
+    pub(crate) fn start(&self, offset: u64, total: u64) {
+        self.offset.store(offset, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    /// 状态是 from 时变为 to 并发送事件，多个数据流同时暂停或继续时只变化一次
+    fn switch_state(&self, from: TransferState, to: TransferState) {
+        self.state.send_if_modified(|state| {
+            let switched = *state == from;
+            if switched {
+                if let Some(events) = &self.events {
+                    events.send(TransferEvent::State(to.clone())).ok();
+                }
+                *state = to;
+            }
+            switched
+        });
+    }
+
+    /// 记录发送的字节数，距离上次事件足够久时发送进度事件
+    pub(crate) fn add(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut last = self.last.lock().unwrap();
+        if now.duration_since(*last) >= PROGRESS_INTERVAL {
+            *last = now;
+            drop(last);
+            self.send_progress();
+        }
+    }
+
+    /// 发送当前的进度事件
+    pub(crate) fn send_progress(&self) {
+        let Some(events) = &self.events else { return };
+        let sent = self.sent.load(Ordering::Relaxed);
+        let bytes = self.offset.load(Ordering::Relaxed) + sent;
+        let total = self.total.load(Ordering::Relaxed);
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { sent as f64 / elapsed } else { 0.0 };
+        let eta = (rate > 0.0).then(|| Duration::from_secs_f64(total.saturating_sub(bytes) as f64 / rate));
+        events.send(TransferEvent::Progress(Progress { bytes, total, rate, eta })).ok();
+    }
+
+    /// 暂停时等待继续，取消时返回 [`CancelledError`]
+    pub(crate) async fn wait_if_paused(&self) -> Result<()> {
+        let mut control = self.control.clone();
+        loop {
+            let current = *control.borrow_and_update();
+            match current {
+                Control::Running => {
+                    self.switch_state(TransferState::Paused, TransferState::Transferring);
+                    return Ok(());
+                }
+                Control::Paused => self.switch_state(TransferState::Transferring, TransferState::Paused),
+                Control::Cancelled => return Err(CancelledError.into()),
+            }
+            control.changed().await?;
+        }
+    }
+
+    /// 等到传输被取消
+    pub(crate) async fn cancelled(&self) {
+        let mut control = self.control.clone();
+        if control.wait_for(|control| *control == Control::Cancelled).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
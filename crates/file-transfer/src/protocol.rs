@@ -0,0 +1,614 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use anyhow::{Ok, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// 协议开头，用于识别格式
+pub const MAGIC: &[u8; 4] = b"QSFT";
+
+/// 协议版本，格式不兼容时递增
+pub const VERSION: u8 = 7;
+
+/// 文件名的最大长度（字节）
+pub const MAX_NAME_LEN: usize = 4096;
+
+/// 分块的最大长度（字节）
+pub const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// 数据流开头，用于识别格式
+pub const DATA_MAGIC: &[u8; 4] = b"QSFD";
+
+/// 并行数据流的最大数量
+pub const MAX_STREAMS: u16 = 64;
+
+/// 目录清单开头，用于识别格式
+pub const MANIFEST_MAGIC: &[u8; 4] = b"QSFM";
+
+/// 目录清单中文件的最大数量
+pub const MAX_MANIFEST_ENTRIES: u32 = 1 << 20;
+
+/// 结尾的标记
+const TRAILER_TAG: &[u8; 4] = b"DONE";
+
+/// 文件头，在文件内容之前发送
+/// 格式：MAGIC | VERSION | 文件名长度 u16 | 文件名 | 文件大小 u64 | 分块大小 u32 | 数据流数量 u16 | BLAKE3 哈希
+/// 整数都使用大端序，数据流数量为 1 时之后按顺序发送每个分块，见 [`write_chunk`]
+/// 否则分块分成几段在单独的数据流上并行发送，见 [`DataHeader`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHeader {
+    /// 文件名，发送目录时是用 / 分隔的相对路径，见 [`validate_relative_path`]
+    pub file_name: String,
+    /// 文件大小（字节）
+    pub file_size: u64,
+    /// 分块大小（字节）
+    pub chunk_size: u32,
+    /// 并行发送分块的数据流数量
+    pub streams: u16,
+    /// 整个文件的 BLAKE3 哈希
+    pub hash: [u8; 32],
+}
+
+impl FileHeader {
+    /// 写入文件头
+    pub async fn write_to(&self, writer: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+        validate_relative_path(&self.file_name)?;
+        let mut buf = Vec::with_capacity(4 + 1 + 2 + self.file_name.len() + 8 + 4 + 2 + 32);
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        buf.extend_from_slice(&(self.file_name.len() as u16).to_be_bytes());
+        buf.extend_from_slice(self.file_name.as_bytes());
+        buf.extend_from_slice(&self.file_size.to_be_bytes());
+        buf.extend_from_slice(&self.chunk_size.to_be_bytes());
+        buf.extend_from_slice(&self.streams.to_be_bytes());
+        buf.extend_from_slice(&self.hash);
+        writer.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// 读取并检查文件头
+    pub async fn read_from(reader: &mut (impl AsyncRead + Unpin)) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).await?;
+        anyhow::ensure!(&magic == MAGIC, "not a file transfer stream");
+        let version = reader.read_u8().await?;
+        anyhow::ensure!(version == VERSION, "unsupported protocol version {version}, expected {VERSION}");
+        let file_name = read_file_name(reader).await?;
+        let file_size = reader.read_u64().await?;
+        let chunk_size = reader.read_u32().await?;
+        anyhow::ensure!(
+            chunk_size > 0 && chunk_size <= MAX_CHUNK_SIZE,
+            "invalid chunk size {chunk_size}"
+        );
+        let streams = reader.read_u16().await?;
+        anyhow::ensure!(streams > 0 && streams <= MAX_STREAMS, "invalid stream count {streams}");
+        let mut hash = [0u8; 32];
+        reader.read_exact(&mut hash).await?;
+        Ok(FileHeader { file_name, file_size, chunk_size, streams, hash })
+    }
+
+    /// 分块数量
+    pub fn chunk_count(&self) -> u64 {
+        self.file_size.div_ceil(self.chunk_size as u64)
+    }
+
+    /// 分块在文件中的偏移
+    pub fn chunk_offset(&self, index: u64) -> u64 {
+        index * self.chunk_size as u64
+    }
+
+    /// 分块长度，最后一块可能较短
+    pub fn chunk_len(&self, index: u64) -> usize {
+        (self.file_size - self.chunk_offset(index)).min(self.chunk_size as u64) as usize
+    }
+
+    /// 把从 first 开始的分块平均分成几段，每个数据流发送一段，每段至少一个分块
+    pub fn ranges(&self, first: u64) -> Vec<Range<u64>> {
+        let count = self.chunk_count().saturating_sub(first);
+        let streams = count.min(self.streams as u64);
+        let mut start = first;
+        (0..streams)
+            .map(|i| {
+                let len = count / streams + u64::from(i < count % streams);
+                start += len;
+                start - len..start
+            })
+            .collect()
+    }
+}
+
+/// 数据流的开头，说明这个流发送的是哪个文件的第几段分块
+/// 一个连接上同时发送多个文件时，接收端按文件名把数据流分给对应的文件
+/// 格式：DATA_MAGIC | 文件名长度 u16 | 文件名 | 文件哈希 | 段序号 u16，之后按顺序发送这一段的分块
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataHeader {
+    pub file_name: String,
+    pub hash: [u8; 32],
+    pub range: u16,
+}
+
+impl DataHeader {
+    pub async fn write_to(&self, writer: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+        validate_relative_path(&self.file_name)?;
+        let mut buf = Vec::with_capacity(4 + 2 + self.file_name.len() + 32 + 2);
+        buf.extend_from_slice(DATA_MAGIC);
+        buf.extend_from_slice(&(self.file_name.len() as u16).to_be_bytes());
+        buf.extend_from_slice(self.file_name.as_bytes());
+        buf.extend_from_slice(&self.hash);
+        buf.extend_from_slice(&self.range.to_be_bytes());
+        writer.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn read_from(reader: &mut (impl AsyncRead + Unpin)) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).await?;
+        anyhow::ensure!(&magic == DATA_MAGIC, "not a file data stream");
+        let file_name = read_file_name(reader).await?;
+        let mut hash = [0u8; 32];
+        reader.read_exact(&mut hash).await?;
+        let range = reader.read_u16().await?;
+        Ok(DataHeader { file_name, hash, range })
+    }
+}
+
+/// 目录清单中的一个文件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// 包含目录名的相对路径，用 / 分隔
+    pub path: String,
+    /// 文件大小（字节）
+    pub size: u64,
+}
+
+/// 目录清单，发送目录时在所有文件之前发送，接收端检查路径并创建目录
+/// 格式：MANIFEST_MAGIC | VERSION | 根目录名长度 u16 | 根目录名 | 文件数量 u32 | (路径长度 u16 | 路径 | 文件大小 u64)...
+/// 之后每个文件按 [`FileHeader`] 单独发送，文件名为清单中的路径
+/// 创建时检查所有路径和总大小，同时按路径建立索引，接收每个文件时按路径查找
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Manifest {
+    /// 发送的目录名，所有路径都在它下面；发送单个文件时是文件名
+    root: String,
+    entries: Vec<ManifestEntry>,
+    /// 路径到文件大小
+    index: HashMap<String, u64>,
+    total_size: u64,
+}
+
+impl Manifest {
+    /// 检查清单并建立索引
+    pub fn new(root: impl Into<String>, entries: Vec<ManifestEntry>) -> Result<Self> {
+        let root = root.into();
+        let total_size = validate_manifest(&root, &entries)?;
+        let index = entries.iter().map(|entry| (entry.path.clone(), entry.size)).collect();
+        Ok(Manifest { root, entries, index, total_size })
+    }
+
+    /// 发送的目录名，空目录也有
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// 清单中的文件，和发送的顺序相同
+    pub fn entries(&self) -> &[ManifestEntry] {
+        &self.entries
+    }
+
+    pub async fn write_to(&self, writer: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MANIFEST_MAGIC);
+        buf.push(VERSION);
+        buf.extend_from_slice(&(self.root.len() as u16).to_be_bytes());
+        buf.extend_from_slice(self.root.as_bytes());
+        buf.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for entry in &self.entries {
+            buf.extend_from_slice(&(entry.path.len() as u16).to_be_bytes());
+            buf.extend_from_slice(entry.path.as_bytes());
+            buf.extend_from_slice(&entry.size.to_be_bytes());
+        }
+        writer.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// 读取并检查目录清单
+    pub async fn read_from(reader: &mut (impl AsyncRead + Unpin)) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).await?;
+        anyhow::ensure!(&magic == MANIFEST_MAGIC, "not a directory manifest");
+        let version = reader.read_u8().await?;
+        anyhow::ensure!(version == VERSION, "unsupported protocol version {version}, expected {VERSION}");
+        let root = read_file_name(reader).await?;
+        let count = reader.read_u32().await?;
+        anyhow::ensure!(count <= MAX_MANIFEST_ENTRIES, "too many files in manifest: {count}");
+        let mut entries = Vec::with_capacity(count.min(1024) as usize);
+        for _ in 0..count {
+            let path = read_file_name(reader).await?;
+            let size = reader.read_u64().await?;
+            entries.push(ManifestEntry { path, size });
+        }
+        Manifest::new(root, entries)
+    }
+
+    /// 清单中的文件大小，不在清单中时为 None
+    pub fn size_of(&self, path: &str) -> Option<u64> {
+        self.index.get(path).copied()
+    }
+
+    /// 所有文件的总大小
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+}
+
+/// 检查所有路径，同一个路径不能出现两次，也不能既是文件又是目录，返回文件总大小
+/// 所有路径都在根目录下，只有发送单个文件时路径就是根目录名
+/// 大小写不敏感的文件系统（Windows、macOS）上只有大小写不同的路径是同一个文件，也算重复
+/// 文件大小来自发送端，总大小超出 u64 时拒绝
+fn validate_manifest(root: &str, entries: &[ManifestEntry]) -> Result<u64> {
+    anyhow::ensure!(entries.len() <= MAX_MANIFEST_ENTRIES as usize, "too many files in manifest");
+    validate_file_name(root).map_err(|e| anyhow::anyhow!("invalid manifest root: {e}"))?;
+    let single_file = matches!(entries, [entry] if entry.path == root);
+    let mut paths = HashSet::new();
+    let mut total_size = 0u64;
+    for entry in entries {
+        validate_relative_path(&entry.path)?;
+        anyhow::ensure!(
+            single_file || entry.path.strip_prefix(root).is_some_and(|rest| rest.starts_with('/')),
+            "path {:?} is not under the manifest root {root:?}",
+            entry.path
+        );
+        anyhow::ensure!(paths.insert(entry.path.to_lowercase()), "duplicate path {:?}", entry.path);
+        total_size = total_size
+            .checked_add(entry.size)
+            .ok_or_else(|| anyhow::anyhow!("total size of the manifest overflows"))?;
+    }
+    for entry in entries {
+        let path = entry.path.to_lowercase();
+        let mut parent = path.as_str();
+        while let Some((dir, _)) = parent.rsplit_once('/') {
+            anyhow::ensure!(!paths.contains(dir), "{dir:?} is both a file and a directory");
+            parent = dir;
+        }
+    }
+    Ok(total_size)
+}
+
+/// 读取文件名长度和文件名，并检查文件名或相对路径
+async fn read_file_name(reader: &mut (impl AsyncRead + Unpin)) -> Result<String> {
+    let name_len = reader.read_u16().await? as usize;
+    anyhow::ensure!(name_len <= MAX_NAME_LEN, "file name is too long: {name_len} bytes");
+    let mut name = vec![0u8; name_len];
+    reader.read_exact(&mut name).await?;
+    let file_name = String::from_utf8(name).map_err(|_| anyhow::anyhow!("file name is not valid UTF-8"))?;
+    validate_relative_path(&file_name)?;
+    Ok(file_name)
+}
+
+/// 写入一个分块
+/// 格式：BLAKE3 哈希 | 内容，内容长度由文件头推算
+pub async fn write_chunk(writer: &mut (impl AsyncWrite + Unpin), data: &[u8]) -> Result<()> {
+    writer.write_all(blake3::hash(data).as_bytes()).await?;
+    writer.write_all(data).await?;
+    Ok(())
+}
+
+/// 读取一个分块到 buf，返回内容和哈希是否一致
+pub async fn read_chunk(reader: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> Result<bool> {
+    let mut hash = [0u8; 32];
+    reader.read_exact(&mut hash).await?;
+    reader.read_exact(buf).await?;
+    Ok(blake3::hash(buf) == hash)
+}
+
+/// 结尾，在文件内容之后发送，记录实际发送的字节数和哈希
+/// 格式：TRAILER_TAG | 字节数 u64 | BLAKE3 哈希
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trailer {
+    pub file_size: u64,
+    pub hash: [u8; 32],
+}
+
+impl Trailer {
+    pub async fn write_to(&self, writer: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+        let mut buf = Vec::with_capacity(4 + 8 + 32);
+        buf.extend_from_slice(TRAILER_TAG);
+        buf.extend_from_slice(&self.file_size.to_be_bytes());
+        buf.extend_from_slice(&self.hash);
+        writer.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn read_from(reader: &mut (impl AsyncRead + Unpin)) -> Result<Self> {
+        let mut tag = [0u8; 4];
+        reader.read_exact(&mut tag).await?;
+        anyhow::ensure!(&tag == TRAILER_TAG, "missing trailer after file data");
+        let file_size = reader.read_u64().await?;
+        let mut hash = [0u8; 32];
+        reader.read_exact(&mut hash).await?;
+        Ok(Trailer { file_size, hash })
+    }
+}
+
+/// 接收端的回复，告诉发送端从哪里开始发送，文件是否已经完整保存，或者需要重发哪些分块
+/// 格式：状态 u8 | 消息长度 u16 | 消息，重发时为：状态 u8 | 分块数量 u32 | 分块序号 u64...
+/// 续传时为：状态 u8 | 偏移 u64
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ack {
+    Ok,
+    Error(String),
+    /// 校验失败，文件已损坏
+    Corrupted(String),
+    /// 这些分块校验失败，需要按顺序重发
+    Resend(Vec<u64>),
+    /// 收到文件头后回复，接收端已经有了这个偏移之前的内容，从这里开始发送
+    Resume(u64),
+}
+
+impl Ack {
+    pub async fn write_to(&self, writer: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+        let (status, message) = match self {
+            Ack::Ok => (0u8, ""),
+            Ack::Error(message) => (1u8, message.as_str()),
+            Ack::Corrupted(message) => (2u8, message.as_str()),
+            Ack::Resend(chunks) => {
+                let mut buf = Vec::with_capacity(1 + 4 + chunks.len() * 8);
+                buf.push(3u8);
+                buf.extend_from_slice(&(chunks.len() as u32).to_be_bytes());
+                for index in chunks {
+                    buf.extend_from_slice(&index.to_be_bytes());
+                }
+                writer.write_all(&buf).await?;
+                return Ok(());
+            }
+            Ack::Resume(offset) => {
+                let mut buf = vec![4u8];
+                buf.extend_from_slice(&offset.to_be_bytes());
+                writer.write_all(&buf).await?;
+                return Ok(());
+            }
+        };
+        // 消息过长时截断
+        let mut end = message.len().min(u16::MAX as usize);
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut buf = vec![status];
+        buf.extend_from_slice(&(end as u16).to_be_bytes());
+        buf.extend_from_slice(&message.as_bytes()[..end]);
+        writer.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn read_from(reader: &mut (impl AsyncRead + Unpin)) -> Result<Self> {
+        let status = reader.read_u8().await?;
+        if status == 3 {
+            let count = reader.read_u32().await? as usize;
+            let mut chunks = Vec::with_capacity(count.min(1024));
+            for _ in 0..count {
+                chunks.push(reader.read_u64().await?);
+            }
+            return Ok(Ack::Resend(chunks));
+        }
+        if status == 4 {
+            return Ok(Ack::Resume(reader.read_u64().await?));
+        }
+        let len = reader.read_u16().await? as usize;
+        let mut message = vec![0u8; len];
+        reader.read_exact(&mut message).await?;
+        match status {
+            0 => Ok(Ack::Ok),
+            2 => Ok(Ack::Corrupted(String::from_utf8_lossy(&message).into_owned())),
+            _ => Ok(Ack::Error(String::from_utf8_lossy(&message).into_owned())),
+        }
+    }
+}
+
+/// 计算文件的 BLAKE3 哈希
+pub(crate) async fn hash_file(path: &std::path::Path, buffer_size: usize) -> Result<[u8; 32]> {
+    let (hasher, _) = hash_prefix(path, u64::MAX, buffer_size).await?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// 计算文件前 len 字节的 BLAKE3 哈希，文件较短时读到结尾，返回哈希状态和实际读取的字节数
+pub(crate) async fn hash_prefix(
+    path: &std::path::Path,
+    len: u64,
+    buffer_size: usize,
+) -> Result<(blake3::Hasher, u64)> {
+    let mut reader = tokio::io::BufReader::new(tokio::fs::File::open(path).await?).take(len);
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; buffer_size];
+    let mut total = 0;
+    loop {
+        let bytes_read = reader.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+        total += bytes_read as u64;
+    }
+    Ok((hasher, total))
+}
+
+/// 数据校验失败的错误，用于和连接、读写等其他错误区分
+#[derive(Debug)]
+pub struct IntegrityError(pub String);
+
+impl std::fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// 接收端拒绝文件的错误，例如文件名不合法或者磁盘已满
+#[derive(Debug)]
+pub struct RejectedError(pub String);
+
+impl std::fmt::Display for RejectedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for RejectedError {}
+
+/// 检查相对路径，用 / 分隔，每一段都是合法的文件名，不能是绝对路径，也不能包含 ..
+pub fn validate_relative_path(path: &str) -> Result<()> {
+    anyhow::ensure!(path.len() <= MAX_NAME_LEN, "path is too long: {} bytes", path.len());
+    for component in path.split('/') {
+        validate_file_name(component).map_err(|e| anyhow::anyhow!("invalid path {path:?}: {e}"))?;
+    }
+    Ok(())
+}
+
+/// 检查文件名，只能是单独的文件名，不能包含路径
+pub fn validate_file_name(name: &str) -> Result<()> {
+    anyhow::ensure!(!name.is_empty(), "file name is empty");
+    anyhow::ensure!(name.len() <= MAX_NAME_LEN, "file name is too long: {} bytes", name.len());
+    anyhow::ensure!(name != "." && name != "..", "invalid file name {name:?}");
+    anyhow::ensure!(
+        !name.contains(['/', '\\', '\0']),
+        "file name must not contain path separators: {name:?}"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_header_roundtrip() {
+        let header = FileHeader {
+            file_name: "报告.pdf".to_string(),
+            file_size: 123_456_789,
+            chunk_size: 64 * 1024,
+            streams: 4,
+            hash: *blake3::hash(b"content").as_bytes(),
+        };
+        let trailer = Trailer { file_size: header.file_size, hash: header.hash };
+        let mut buf = Vec::new();
+        header.write_to(&mut buf).await.unwrap();
+        trailer.write_to(&mut buf).await.unwrap();
+        Ack::Error("disk full".to_string()).write_to(&mut buf).await.unwrap();
+        Ack::Resend(vec![3, 7]).write_to(&mut buf).await.unwrap();
+        Ack::Resume(1 << 40).write_to(&mut buf).await.unwrap();
+        write_chunk(&mut buf, b"chunk").await.unwrap();
+
+        let mut reader = buf.as_slice();
+        assert_eq!(FileHeader::read_from(&mut reader).await.unwrap(), header);
+        assert_eq!(Trailer::read_from(&mut reader).await.unwrap(), trailer);
+        assert_eq!(Ack::read_from(&mut reader).await.unwrap(), Ack::Error("disk full".to_string()));
+        assert_eq!(Ack::read_from(&mut reader).await.unwrap(), Ack::Resend(vec![3, 7]));
+        assert_eq!(Ack::read_from(&mut reader).await.unwrap(), Ack::Resume(1 << 40));
+        let mut chunk = [0u8; 5];
+        assert!(read_chunk(&mut reader, &mut chunk).await.unwrap());
+        assert_eq!(&chunk, b"chunk");
+        assert!(reader.is_empty());
+
+        // 分块内容损坏时校验失败
+        let mut buf = Vec::new();
+        write_chunk(&mut buf, b"chunk").await.unwrap();
+        buf[33] ^= 0xff;
+        assert!(!read_chunk(&mut buf.as_slice(), &mut chunk).await.unwrap());
+        assert_eq!(header.chunk_count(), 1884);
+        assert_eq!(header.chunk_len(1883), (123_456_789 - 1883 * 64 * 1024) as usize);
+        assert_eq!(header.ranges(0), vec![0..471, 471..942, 942..1413, 1413..1884]);
+        assert_eq!(header.ranges(1882), vec![1882..1883, 1883..1884]);
+        assert!(header.ranges(1884).is_empty());
+
+        // 版本不同时拒绝
+        let mut buf = Vec::new();
+        header.write_to(&mut buf).await.unwrap();
+        buf[4] = VERSION + 1;
+        let err = FileHeader::read_from(&mut buf.as_slice()).await.unwrap_err();
+        assert!(err.to_string().contains("unsupported protocol version"));
+    }
+
+    #[test]
+    fn test_validate_file_name() {
+        assert!(validate_file_name("test.txt").is_ok());
+        assert!(validate_file_name("").is_err());
+        assert!(validate_file_name("..").is_err());
+        assert!(validate_file_name("../etc/passwd").is_err());
+        assert!(validate_file_name("a\\b").is_err());
+        assert!(validate_relative_path("photos/2024/a.jpg").is_ok());
+        assert!(validate_relative_path("/etc/passwd").is_err());
+        assert!(validate_relative_path("photos/../../etc").is_err());
+        assert!(validate_relative_path("photos//a.jpg").is_err());
+        assert!(validate_relative_path("photos/").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_manifest_roundtrip() {
+        let entry = |path: &str, size| ManifestEntry { path: path.to_string(), size };
+        let manifest = Manifest::new("photos", vec![entry("photos/a.jpg", 10), entry("photos/2024/b.jpg", 0)]).unwrap();
+        let mut buf = Vec::new();
+        manifest.write_to(&mut buf).await.unwrap();
+        assert_eq!(Manifest::read_from(&mut buf.as_slice()).await.unwrap(), manifest);
+        assert_eq!(manifest.size_of("photos/a.jpg"), Some(10));
+        assert_eq!(manifest.size_of("photos/c.jpg"), None);
+        assert_eq!(manifest.total_size(), 10);
+
+        let duplicate = Manifest::new("a", vec![entry("a/b", 1), entry("a/b", 2)]);
+        assert!(duplicate.unwrap_err().to_string().contains("duplicate path"));
+        // 只有大小写不同的路径在部分文件系统上是同一个文件
+        let case = Manifest::new("a", vec![entry("a/Photo.jpg", 1), entry("a/photo.JPG", 2)]);
+        assert!(case.unwrap_err().to_string().contains("duplicate path"));
+        assert!(Manifest::new("a", vec![entry("a/b", 1), entry("a/b/c", 2)]).is_err());
+        assert!(Manifest::new("a", vec![entry("a/B", 1), entry("a/b/c", 2)]).is_err());
+        // 发送端不检查时，接收端读取清单时拒绝重复的路径
+        let mut buf = Vec::new();
+        Manifest::new("a", vec![entry("a/x", 1), entry("a/y", 2)]).unwrap().write_to(&mut buf).await.unwrap();
+        let pos = buf.len() - 8 - 1;
+        buf[pos] = b'x';
+        let err = Manifest::read_from(&mut buf.as_slice()).await.unwrap_err();
+        assert!(err.to_string().contains("duplicate path"), "{err:#}");
+        // 读取时也检查路径
+        let mut buf = Vec::new();
+        Manifest::new("a", vec![entry("a/xx", 1)]).unwrap().write_to(&mut buf).await.unwrap();
+        let pos = buf.len() - 8 - 2;
+        buf[pos..pos + 2].copy_from_slice(b"..");
+        assert!(Manifest::read_from(&mut buf.as_slice()).await.is_err());
+    }
+
+    #[test]
+    fn test_manifest_root() {
+        let entry = |path: &str| ManifestEntry { path: path.to_string(), size: 1 };
+        // 空目录只有根目录名
+        let empty = Manifest::new("photos", Vec::new()).unwrap();
+        assert_eq!((empty.root(), empty.entries().len()), ("photos", 0));
+        // 单个文件的路径就是根目录名
+        assert!(Manifest::new("a.jpg", vec![entry("a.jpg")]).is_ok());
+        let err = Manifest::new("photos", vec![entry("photos/a.jpg"), entry("other/b.jpg")]).unwrap_err();
+        assert!(err.to_string().contains("not under the manifest root"), "{err:#}");
+        assert!(Manifest::new("photos", vec![entry("photos2/a.jpg")]).is_err());
+        assert!(Manifest::new("photos", vec![entry("photos"), entry("photos/a.jpg")]).is_err());
+        assert!(Manifest::new("a/b", vec![entry("a/b/c")]).is_err());
+        assert!(Manifest::new("..", Vec::new()).is_err());
+        assert!(Manifest::new("", Vec::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_manifest_size_overflow() {
+        // 每个文件的大小都合法，总大小超出 u64
+        let half = u64::MAX / 2 + 1;
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MANIFEST_MAGIC);
+        buf.push(VERSION);
+        buf.extend_from_slice(&1u16.to_be_bytes());
+        buf.extend_from_slice(b"a");
+        buf.extend_from_slice(&2u32.to_be_bytes());
+        for path in ["a/x", "a/y"] {
+            buf.extend_from_slice(&(path.len() as u16).to_be_bytes());
+            buf.extend_from_slice(path.as_bytes());
+            buf.extend_from_slice(&half.to_be_bytes());
+        }
+        let err = Manifest::read_from(&mut buf.as_slice()).await.unwrap_err();
+        assert!(err.to_string().contains("overflows"), "{err:#}");
+        let manifest = Manifest::new("a", vec![ManifestEntry { path: "a/x".to_string(), size: u64::MAX }]).unwrap();
+        assert_eq!(manifest.total_size(), u64::MAX);
+    }
+}
@@ -0,0 +1,469 @@
+use std::{collections::HashMap, path::Path, time::{Duration, Instant}};
+use anyhow::{Context, Ok, Result};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use quinn::Connection;
+
+use crate::directory::walk_dir;
+use crate::file_transfer::{FailureReason, FileTransfer, TransferState};
+use crate::progress::{CancelledError, TransferEvent};
+
+/// 默认同时进行的传输数量
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// 默认失败后最多重试几次
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// 默认第一次重试前等待的时间，之后每次加倍
+pub const DEFAULT_BACKOFF: Duration = Duration::from_millis(500);
+
+/// 同一优先级的文件按什么顺序发送
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    /// 按加入的顺序
+    Fifo,
+    /// 小文件优先
+    SmallestFirst,
+    /// 大文件优先
+    LargestFirst,
+}
+
+/// 队列中的一个文件
+#[derive(Debug)]
+pub struct QueueItem {
+    /// 文件的传输，结束后保存最后的状态
+    pub transfer: FileTransfer,
+    /// 优先级，越大越先发送
+    pub priority: i32,
+    /// 已经尝试的次数
+    pub attempts: u32,
+    /// 最后一次失败的原因
+    pub error: Option<String>,
+    /// 重试时等到这个时间再开始
+    ready_at: Option<Instant>,
+}
+
+/// 队列的事件
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueueEvent {
+    /// 某个文件的状态变化，index 是加入队列的顺序
+    State { index: usize, state: TransferState },
+    /// 所有文件的总进度
+    Progress(QueueProgress),
+}
+
+/// 所有文件的总进度
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueProgress {
+    /// 已经发送的字节数
+    pub bytes: u64,
+    /// 所有文件的大小
+    pub total: u64,
+    /// 已经完成的文件数
+    pub finished: usize,
+    /// 已经放弃的文件数
+    pub failed: usize,
+    /// 文件总数
+    pub files: usize,
+    /// 开始以来的平均速度（字节/秒）
+    pub rate: f64,
+    /// 预计剩余时间，还不知道速度时为 None
+    pub eta: Option<Duration>,
+}
+
+/// 传输队列，在一个或者多个连接上同时发送多个文件
+/// 按优先级选择下一个文件，失败的文件等待一段时间后重试，每次等待的时间加倍
+#[derive(Debug)]
+pub struct TransferQueue {
+    connections: Vec<Connection>,
+    /// 加入队列的文件，按加入的顺序
+    pub items: Vec<QueueItem>,
+    /// 同时进行的传输数量
+    pub concurrency: usize,
+    /// 同一优先级的文件的顺序
+    pub order: Order,
+    /// 失败后最多重试几次，接收端拒绝或者取消的文件不重试
+    pub max_retries: u32,
+    /// 第一次重试前等待的时间
+    pub backoff: Duration,
+    /// 新加入的文件的分块大小
+    pub chunk_size: usize,
+    /// 新加入的文件的并行数据流数量
+    pub streams: usize,
+    events: Option<mpsc::UnboundedSender<QueueEvent>>,
+}
+
+impl TransferQueue {
+    /// 创建传输队列，文件轮流使用这些连接
+    pub fn new(connections: Vec<Connection>, chunk_size: usize) -> Self {
+        Self {
+            connections,
+            items: Vec::new(),
+            concurrency: DEFAULT_CONCURRENCY,
+            order: Order::Fifo,
+            max_retries: DEFAULT_MAX_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            chunk_size,
+            streams: 1,
+            events: None,
+        }
+    }
+
+    /// 订阅队列事件，只保留最后一次订阅
+    pub fn events(&mut self) -> mpsc::UnboundedReceiver<QueueEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.events = Some(sender);
+        receiver
+    }
+
+    /// 加入一个文件或者目录，目录中的文件都加入队列，返回加入的文件数
+    /// 和 [`DirectoryTransfer`](crate::directory::DirectoryTransfer) 一样，目录中的文件名是包含目录名的相对路径
+    pub fn add(&mut self, path: impl AsRef<Path>, priority: i32) -> Result<usize> {
+        let path = path.as_ref();
+        let files = if path.is_dir() {
+            let name = path.file_name().context("path has no name")?.to_str()
+                .with_context(|| format!("{} is not valid UTF-8", path.display()))?;
+            let mut files = Vec::new();
+            walk_dir(path, name, self.chunk_size, &mut files)?;
+            files
+        } else {
+            anyhow::ensure!(path.is_file(), "{} is not a file or directory", path.display());
+            vec![FileTransfer::new(path, self.chunk_size)]
+        };
+        let count = files.len();
+        for mut transfer in files {
+            transfer.streams = self.streams;
+            self.items.push(QueueItem { transfer, priority, attempts: 0, error: None, ready_at: None });
+        }
+        Ok(count)
+    }
+
+    /// 发送队列中还没有完成的文件，直到全部完成或者放弃，有文件失败时返回错误
+    pub async fn run(&mut self) -> Result<()> {
+        anyhow::ensure!(!self.connections.is_empty(), "no connection to send files on");
+        let start = Instant::now();
+        let total: u64 = self.items.iter().map(|item| item.transfer.file_size as u64).sum();
+        let mut sent: HashMap<usize, u64> = HashMap::new();
+        let mut pending: Vec<usize> = (0..self.items.len()).filter(|&i| !self.items[i].transfer.state().is_done()).collect();
+        // 上次运行中途被取消时，正在发送的文件停在传输中，当作失败后重新发送
+        for &index in &pending {
+            let transfer = &mut self.items[index].transfer;
+            if matches!(transfer.state(), TransferState::Transferring | TransferState::Paused) {
+                transfer.set_state(TransferState::Failed(FailureReason::Error));
+            }
+        }
+        let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+        let mut tasks = JoinSet::new();
+        let mut running = HashMap::new();
+        let mut next_connection = 0;
+        loop {
+            // 空出的位置按优先级填上已经可以开始的文件
+            let now = Instant::now();
+            while tasks.len() < self.concurrency.max(1) {
+                let Some(pos) = self.next_ready(&pending, now) else { break };
+                let index = pending.remove(pos);
+                let item = &mut self.items[index];
+                item.attempts += 1;
+                // 传输在单独的任务中进行，原处留一份共享状态的副本，结束后放回来
+                let detached = item.transfer.detach();
+                let mut transfer = std::mem::replace(&mut item.transfer, detached);
+                let mut events = transfer.events();
+                let forward = event_sender.clone();
+                tokio::spawn(async move {
+                    while let Some(event) = events.recv().await {
+                        if forward.send((index, event)).is_err() {
+                            break;
+                        }
+                    }
+                });
+                let connection = self.next_connection(&mut next_connection);
+                let task = tasks.spawn(async move {
+                    let res = transfer.send_file(&connection).await;
+                    (transfer, res)
+                });
+                running.insert(task.id(), index);
+            }
+            if tasks.is_empty() && pending.is_empty() {
+                break;
+            }
+            let retry_at = pending.iter().filter_map(|&i| self.items[i].ready_at).min();
+            tokio::select! {
+                Some(joined) = tasks.join_next_with_id() => {
+                    match joined {
+                        std::result::Result::Ok((id, (transfer, res))) => {
+                            let index = running.remove(&id).context("unknown transfer task")?;
+                            self.finish_item(index, transfer, res, &mut pending);
+                        }
+                        // 任务 panic 时只算这个文件失败，用留在原处的副本重试
+                        Err(e) => {
+                            let index = running.remove(&e.id()).context("unknown transfer task")?;
+                            let mut transfer = self.items[index].transfer.detach();
+                            transfer.set_state(TransferState::Failed(FailureReason::Error));
+                            self.finish_item(index, transfer, Err(anyhow::anyhow!("transfer task failed: {e}")), &mut pending);
+                        }
+                    }
+                    self.send_progress(&sent, total, start);
+                }
+                Some((index, event)) = event_receiver.recv() => match event {
+                    TransferEvent::State(state) => self.send_event(QueueEvent::State { index, state }),
+                    TransferEvent::Progress(progress) => {
+                        sent.insert(index, progress.bytes);
+                        self.send_progress(&sent, total, start);
+                    }
+                },
+                _ = sleep_until(retry_at) => {}
+            }
+        }
+        let failed = self.items.iter().filter(|item| item.transfer.state() != TransferState::Finished).count();
+        anyhow::ensure!(failed == 0, "{failed} of {} files failed", self.items.len());
+        Ok(())
+    }
+
+    /// 轮流使用连接，跳过已经关闭的连接
+    fn next_connection(&self, next: &mut usize) -> Connection {
+        let count = self.connections.len();
+        let pos = (*next..*next + count)
+            .find(|i| self.connections[i % count].close_reason().is_none())
+            .unwrap_or(*next);
+        *next = pos + 1;
+        self.connections[pos % count].clone()
+    }
+
+    /// 已经可以开始的文件中优先级最高的，返回在 pending 中的位置
+    fn next_ready(&self, pending: &[usize], now: Instant) -> Option<usize> {
+        let key = |index: usize| {
+            let item = &self.items[index];
+            let size = item.transfer.file_size as i64;
+            let order = match self.order {
+                Order::Fifo => 0,
+                Order::SmallestFirst => -size,
+                Order::LargestFirst => size,
+            };
+            (item.priority, order, std::cmp::Reverse(index))
+        };
+        pending
+            .iter()
+            .enumerate()
+            .filter(|(_, &index)| self.items[index].ready_at.is_none_or(|at| at <= now))
+            .max_by_key(|(_, &index)| key(index))
+            .map(|(pos, _)| pos)
+    }
+
+    /// 传输结束后放回队列，可以重试的失败等待一段时间后重新排队
+    fn finish_item(&mut self, index: usize, mut transfer: FileTransfer, res: Result<()>, pending: &mut Vec<usize>) {
+        let item = &mut self.items[index];
+        item.ready_at = None;
+        if let Err(e) = &res {
+            item.error = Some(format!("{e:#}"));
+            let retry = match transfer.state() {
+                TransferState::Failed(FailureReason::Rejected) => false,
+                TransferState::Failed(_) => e.downcast_ref::<CancelledError>().is_none(),
+                _ => false,
+            };
+            if retry && item.attempts <= self.max_retries {
+                transfer.set_state(TransferState::Queued);
+                item.ready_at = Some(Instant::now() + self.backoff * 2u32.saturating_pow(item.attempts - 1));
+                pending.push(index);
+                self.send_event(QueueEvent::State { index, state: TransferState::Queued });
+            }
+        }
+        self.items[index].transfer = transfer;
+    }
+
+    fn send_event(&self, event: QueueEvent) {
+        if let Some(events) = &self.events {
+            events.send(event).ok();
+        }
+    }
+
+    fn send_progress(&self, sent: &HashMap<usize, u64>, total: u64, start: Instant) {
+        let bytes = sent.values().sum::<u64>().min(total);
+        let finished = self.items.iter().filter(|item| item.transfer.state() == TransferState::Finished).count();
+        let failed = self.items.iter().filter(|item| matches!(item.transfer.state(), TransferState::Failed(_) | TransferState::Cancelled)).count();
+        let elapsed = start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { bytes as f64 / elapsed } else { 0.0 };
+        let eta = (rate > 0.0).then(|| Duration::from_secs_f64(total.saturating_sub(bytes) as f64 / rate));
+        self.send_event(QueueEvent::Progress(QueueProgress {
+            bytes,
+            total,
+            finished,
+            failed,
+            files: self.items.len(),
+            rate,
+            eta,
+        }));
+    }
+}
+
+/// 等到 at，没有时间时一直等待
+async fn sleep_until(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use quic_transport::quic_endpoint::{generate_self_signed, ClientEndpoint, ServerEndpointContainer};
+    use crate::file_receiver::receive_files;
+
+    #[test]
+    fn test_add_relative_names() {
+        let dir = std::env::temp_dir().join(format!("transfer-queue-names-{}", std::process::id()));
+        for name in ["a.bin", "sub/a.bin"] {
+            let path = dir.join("src").join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, name).unwrap();
+        }
+        let mut queue = TransferQueue::new(Vec::new(), 1024);
+        assert_eq!(queue.add(dir.join("src"), 0).unwrap(), 2);
+        assert_eq!(queue.add(dir.join("src/sub/a.bin"), 0).unwrap(), 1);
+        // 同名的文件按相对路径区分，单独加入的文件只有文件名
+        let names: Vec<_> = queue.items.iter().map(|item| item.transfer.file_name.as_str()).collect();
+        assert_eq!(names, ["src/a.bin", "src/sub/a.bin", "a.bin"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_queue() {
+        let dir = std::env::temp_dir().join(format!("transfer-queue-test-{}", std::process::id()));
+        let files = [("a.bin", 30_000), ("b.bin", 100), ("c.bin", 5_000), ("sub/d.bin", 70_000)];
+        for (name, size) in files {
+            let path = dir.join("src").join(name);
+            tokio::fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            tokio::fs::write(path, data).await.unwrap();
+        }
+        let output_dir = dir.join("out");
+        tokio::fs::create_dir_all(&output_dir).await.unwrap();
+
+        let (cert, key) = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let server_endpoint = ServerEndpointContainer::with_cert(addr, cert.clone(), key).unwrap();
+        let client_endpoint = ClientEndpoint::with_cert(addr, cert).unwrap();
+        let server = server_endpoint.get_endpoint().clone();
+        let receiver_dir = output_dir.clone();
+        let (results, mut received) = mpsc::unbounded_channel();
+        let server_task = tokio::spawn(async move {
+            // 第一个连接收到文件后断开，文件要在第二个连接上重试
+            let broken = server.accept().await.unwrap().await.unwrap();
+            tokio::spawn(async move {
+                let _streams = broken.accept_bi().await;
+                broken.close(0u32.into(), b"crash");
+            });
+            let connection = server.accept().await.unwrap().await.unwrap();
+            receive_files(&connection, receiver_dir, results).await
+        });
+        let mut connections = Vec::new();
+        for _ in 0..2 {
+            let connecting = client_endpoint.get_endpoint().connect(server_endpoint.get_bind_addr(), "localhost");
+            connections.push(connecting.unwrap().await.unwrap());
+        }
+
+        let mut queue = TransferQueue::new(connections.clone(), 16 * 1024);
+        queue.concurrency = 2;
+        queue.order = Order::SmallestFirst;
+        queue.backoff = Duration::from_millis(10);
+        queue.streams = 2;
+        assert_eq!(queue.add(dir.join("src"), 0).unwrap(), 4);
+        let mut events = queue.events();
+        queue.run().await.unwrap();
+
+        // 文件名是包含目录名的相对路径，按相对路径保存
+        for (item, (name, _)) in queue.items.iter().zip(files) {
+            assert_eq!(item.transfer.state(), TransferState::Finished);
+            assert_eq!(item.transfer.file_name, format!("src/{name}"));
+            let sent = tokio::fs::read(dir.join("src").join(name)).await.unwrap();
+            assert_eq!(tokio::fs::read(output_dir.join("src").join(name)).await.unwrap(), sent);
+        }
+        assert!(queue.items.iter().any(|item| item.attempts == 2 && item.error.is_some()));
+
+        let mut started = Vec::new();
+        let mut last_progress = None;
+        while let std::result::Result::Ok(event) = events.try_recv() {
+            match event {
+                QueueEvent::State { index, state: TransferState::Transferring } => started.push(index),
+                QueueEvent::Progress(progress) => last_progress = Some(progress),
+                _ => {}
+            }
+        }
+        // 小文件优先
+        started.truncate(2);
+        started.sort();
+        assert_eq!(started, vec![1, 2]);
+        let progress = last_progress.unwrap();
+        assert_eq!((progress.bytes, progress.total), (105_100, 105_100));
+        assert_eq!((progress.finished, progress.failed, progress.files), (4, 0, 4));
+
+        for connection in connections {
+            connection.close(0u32.into(), b"done");
+        }
+        server_task.await.unwrap().unwrap();
+        let mut count = 0;
+        while let Some((_, res)) = received.recv().await {
+            res.unwrap();
+            count += 1;
+        }
+        assert_eq!(count, 4);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_after_cancel() {
+        let dir = std::env::temp_dir().join(format!("transfer-queue-cancel-{}", std::process::id()));
+        let files = [("a.bin", 20_000), ("b.bin", 3_000)];
+        for (name, size) in files {
+            let path = dir.join("src").join(name);
+            tokio::fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            tokio::fs::write(path, vec![7u8; size]).await.unwrap();
+        }
+        let output_dir = dir.join("out");
+        tokio::fs::create_dir_all(&output_dir).await.unwrap();
+
+        let (cert, key) = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let server_endpoint = ServerEndpointContainer::with_cert(addr, cert.clone(), key).unwrap();
+        let client_endpoint = ClientEndpoint::with_cert(addr, cert).unwrap();
+        let server = server_endpoint.get_endpoint().clone();
+        let receiver_dir = output_dir.clone();
+        let (results, _received) = mpsc::unbounded_channel();
+        let server_task = tokio::spawn(async move {
+            // 第一个连接收到文件后不再回应，发送会一直等待
+            let stalled = server.accept().await.unwrap().await.unwrap();
+            let mut streams = Vec::new();
+            while let std::result::Result::Ok(stream) = stalled.accept_bi().await {
+                streams.push(stream);
+            }
+            let connection = server.accept().await.unwrap().await.unwrap();
+            receive_files(&connection, receiver_dir, results).await
+        });
+        let connect = || client_endpoint.get_endpoint().connect(server_endpoint.get_bind_addr(), "localhost").unwrap();
+        let stalled = connect().await.unwrap();
+
+        let mut queue = TransferQueue::new(vec![stalled.clone()], 16 * 1024);
+        assert_eq!(queue.add(dir.join("src"), 0).unwrap(), 2);
+        // 运行中途被取消，正在发送的文件保留原来的参数
+        assert!(tokio::time::timeout(Duration::from_millis(300), queue.run()).await.is_err());
+        for (item, (name, _)) in queue.items.iter().zip(files) {
+            assert_eq!(item.transfer.file_name, format!("src/{name}"));
+            assert_eq!(item.transfer.state(), TransferState::Transferring);
+        }
+        stalled.close(0u32.into(), b"stalled");
+
+        let connection = connect().await.unwrap();
+        queue.connections = vec![connection.clone()];
+        queue.run().await.unwrap();
+        for (item, (name, _)) in queue.items.iter().zip(files) {
+            assert_eq!(item.transfer.state(), TransferState::Finished);
+            let sent = tokio::fs::read(dir.join("src").join(name)).await.unwrap();
+            assert_eq!(tokio::fs::read(output_dir.join("src").join(name)).await.unwrap(), sent);
+        }
+        connection.close(0u32.into(), b"done");
+        server_task.await.unwrap().unwrap();
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
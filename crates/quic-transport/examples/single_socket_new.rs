@@ -1,6 +1,7 @@
 use anyhow::Result;
 use quic_transport::quic_endpoint::ServerEndpointContainer;
 use quic_transport::quic_endpoint::ClientEndpoint;
+use quic_transport::known_hosts::KnownHosts;
 use std::net::Ipv4Addr;
 use std::net::SocketAddr;
 use std::net::IpAddr;
@@ -9,11 +10,11 @@ use std::path::Path;
 #[tokio::main]
 async fn main() -> Result<()> {
 
-    // 提前生成证书文件
-    // 1 个独立服务器：监听 5000 端口
+    // 1 个独立服务器：监听 5000 端口，第一次运行时生成证书文件
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
     let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
-    let server_endpoint = ServerEndpointContainer::new(addr, Path::new("server.cert"), Path::new("server.key"))?;
+    let server_endpoint = ServerEndpointContainer::load_or_generate(addr, Path::new("."), vec!["localhost".to_string()])?;
+    println!("[server] certificate fingerprint: {}", server_endpoint.fingerprint());
     tokio::spawn(async move {
         let connection = server_endpoint.get_endpoint().accept().await.unwrap().await.unwrap();
         println!(
@@ -31,11 +32,11 @@ async fn main() -> Result<()> {
         }
     });
 
-    let client_endpoint = ClientEndpoint::new(client_addr, Path::new("server.cert"))?;
+    // 第一次连接时记录服务器证书，之后证书变化时拒绝连接
+    let client_endpoint = ClientEndpoint::with_known_hosts(client_addr, KnownHosts::load("known_hosts")?)?;
+    let connection = client_endpoint.connect(addr, "localhost").await?;
     let client_endpoint = client_endpoint.get_endpoint();
 
-    let connecting = client_endpoint.connect(addr, "localhost").unwrap();
-    let connection = connecting.await.unwrap();
     println!("[client] connected: addr={}", connection.remote_address());
 
     let (mut send, mut recv) = connection.open_bi().await?;
@@ -0,0 +1,112 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use anyhow::Result;
+use quinn::Connection;
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{DigitallySignedStruct, DistinguishedName, SignatureScheme};
+
+use crate::known_hosts::fingerprint;
+
+/// 服务器如何验证客户端
+#[derive(Debug, Clone, Default)]
+pub enum ClientAuth {
+    /// 不要求客户端证书，接受所有客户端
+    #[default]
+    None,
+    /// 要求由这些 CA 签发的客户端证书
+    Ca(Vec<CertificateDer<'static>>),
+    /// 只接受这些指纹的客户端证书，见 [`fingerprint`]
+    Pinned(Vec<String>),
+}
+
+impl ClientAuth {
+    /// 对应的 rustls 客户端验证，不要求客户端证书时为 None
+    pub(crate) fn verifier(&self, provider: Arc<CryptoProvider>) -> Result<Option<Arc<dyn ClientCertVerifier>>> {
+        let verifier: Arc<dyn ClientCertVerifier> = match self {
+            ClientAuth::None => return Ok(None),
+            ClientAuth::Ca(certs) => {
+                anyhow::ensure!(!certs.is_empty(), "no CA certificate for client authentication");
+                let mut roots = rustls::RootCertStore::empty();
+                for cert in certs {
+                    roots.add(cert.clone())?;
+                }
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?
+            }
+            ClientAuth::Pinned(fingerprints) => {
+                anyhow::ensure!(!fingerprints.is_empty(), "no pinned client certificate");
+                Arc::new(PinnedClientVerifier { fingerprints: fingerprints.iter().cloned().collect(), provider })
+            }
+        };
+        Ok(Some(verifier))
+    }
+}
+
+/// 只接受指定指纹的客户端证书，不检查证书链和有效期
+#[derive(Debug)]
+struct PinnedClientVerifier {
+    fingerprints: BTreeSet<String>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ClientCertVerifier for PinnedClientVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let actual = fingerprint(end_entity);
+        match self.fingerprints.contains(&actual) {
+            true => Ok(ClientCertVerified::assertion()),
+            false => Err(rustls::Error::General(format!("client certificate {actual} is not allowed"))),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// 通过验证的客户端身份，服务器可以据此决定客户端能做什么
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// 客户端证书的指纹
+    pub fingerprint: String,
+    /// 客户端证书，之后是证书链中的其他证书
+    pub certs: Vec<CertificateDer<'static>>,
+}
+
+impl ClientIdentity {
+    /// 连接上客户端出示的证书，服务器不要求客户端证书时为 None
+    pub fn from_connection(connection: &Connection) -> Option<Self> {
+        let certs = connection.peer_identity()?.downcast::<Vec<CertificateDer<'static>>>().ok()?;
+        let fingerprint = fingerprint(certs.first()?);
+        Some(Self { fingerprint, certs: *certs })
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use anyhow::{Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+
+/// 首次连接时的确认，参数是主机和证书指纹，返回 false 时拒绝连接
+pub type FirstUse = dyn Fn(&str, &str) -> bool + Send + Sync;
+
+/// 证书的 SHA-256 指纹，格式为 sha256:十六进制
+pub fn fingerprint(cert: &[u8]) -> String {
+    let digest = Sha256::digest(cert);
+    let hex: String = digest.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("sha256:{hex}")
+}
+
+/// known_hosts 中的主机名，同一个名称在不同地址上的服务器分开记录
+/// 文件中主机和指纹用空白分隔，名称不能包含空白
+pub fn host_key(addr: SocketAddr, server_name: &str) -> Result<String> {
+    anyhow::ensure!(
+        !server_name.is_empty() && !server_name.contains(char::is_whitespace),
+        "invalid server name {server_name:?}"
+    );
+    Ok(format!("{server_name}@{addr}"))
+}
+
+/// 信任的服务器证书指纹，类似 ssh 的 known_hosts
+/// 第一次连接时记录证书指纹，之后证书变化时拒绝连接
+/// 文件每行一个主机：主机 指纹，# 开头的行是注释
+pub struct KnownHosts {
+    path: Option<PathBuf>,
+    hosts: Mutex<BTreeMap<String, String>>,
+    first_use: Box<FirstUse>,
+}
+
+impl fmt::Debug for KnownHosts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KnownHosts").field("path", &self.path).field("hosts", &self.hosts).finish()
+    }
+}
+
+impl KnownHosts {
+    /// 读取 known_hosts 文件，不存在时为空，第一次记录主机时创建
+    /// 第一次连接时打印指纹并信任，可以用 [`KnownHosts::on_first_use`] 修改
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+        };
+        let mut hosts = BTreeMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (host, fingerprint) = line
+                .split_once(char::is_whitespace)
+                .with_context(|| format!("{}:{}: expected host and fingerprint", path.display(), number + 1))?;
+            hosts.insert(host.to_string(), fingerprint.trim().to_string());
+        }
+        Ok(Self { path: Some(path.to_path_buf()), ..Self::in_memory() }.with_hosts(hosts))
+    }
+
+    /// 不保存到文件的 known_hosts
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            hosts: Mutex::new(BTreeMap::new()),
+            first_use: Box::new(|host, fingerprint| {
+                eprintln!("first connection to {host}, certificate fingerprint is {fingerprint}");
+                eprintln!("trusting it from now on, connections with a different certificate will be refused");
+                true
+            }),
+        }
+    }
+
+    fn with_hosts(self, hosts: BTreeMap<String, String>) -> Self {
+        *self.hosts.lock().unwrap() = hosts;
+        self
+    }
+
+    /// 设置第一次连接时的确认，例如询问用户，返回 false 时拒绝连接
+    pub fn on_first_use(mut self, first_use: impl Fn(&str, &str) -> bool + Send + Sync + 'static) -> Self {
+        self.first_use = Box::new(first_use);
+        self
+    }
+
+    /// 记录的证书指纹
+    pub fn get(&self, host: &str) -> Option<String> {
+        self.hosts.lock().unwrap().get(host).cloned()
+    }
+
+    /// 信任主机的证书指纹，替换之前的记录
+    pub fn insert(&self, host: &str, fingerprint: &str) -> Result<()> {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.insert(host.to_string(), fingerprint.to_string());
+        self.save(&hosts)
+    }
+
+    /// 删除主机的记录，服务器确实更换了证书时使用，返回是否有记录
+    pub fn remove(&self, host: &str) -> Result<bool> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let removed = hosts.remove(host).is_some();
+        self.save(&hosts)?;
+        Ok(removed)
+    }
+
+    fn save(&self, hosts: &BTreeMap<String, String>) -> Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let text: String = hosts.iter().map(|(host, fingerprint)| format!("{host} {fingerprint}\n")).collect();
+        // 先写临时文件再替换，中途失败时不会留下不完整的文件
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        std::fs::write(&temp, text).with_context(|| format!("cannot write {}", path.display()))?;
+        std::fs::rename(&temp, path).with_context(|| format!("cannot write {}", path.display()))
+    }
+
+    /// 只查找记录，证书与记录不同时返回错误，没有记录时返回 false，不记录新的主机
+    /// 在握手中使用，这时服务器还没有证明持有证书的私钥
+    pub fn lookup(&self, host: &str, cert: &[u8]) -> Result<bool> {
+        let actual = fingerprint(cert);
+        match self.get(host) {
+            Some(expected) if expected == actual => Ok(true),
+            Some(expected) => anyhow::bail!(
+                "certificate of {host} has changed, expected {expected} but got {actual}; \
+                 if the server really changed its certificate, remove {host} from known hosts"
+            ),
+            None => Ok(false),
+        }
+    }
+
+    /// 检查主机的证书，第一次连接时确认后记录
+    /// 需要在握手完成后调用，否则可能记录一个没有私钥的服务器重放的证书
+    pub fn check(&self, host: &str, cert: &[u8]) -> Result<()> {
+        if self.lookup(host, cert)? {
+            return Ok(());
+        }
+        let actual = fingerprint(cert);
+        anyhow::ensure!((self.first_use)(host, &actual), "certificate of {host} ({actual}) was not trusted");
+        self.insert(host, &actual)
+    }
+}
+
+/// 按 known_hosts 检查服务器证书，不检查证书链和有效期，只比较指纹
+/// 没有记录的主机在这里放行，握手完成后由 [`crate::quic_endpoint::ClientEndpoint::connect`] 确认并记录
+#[derive(Debug)]
+pub(crate) struct TofuVerifier {
+    known_hosts: Arc<KnownHosts>,
+    host: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl TofuVerifier {
+    pub(crate) fn new(known_hosts: Arc<KnownHosts>, host: String, provider: Arc<CryptoProvider>) -> Self {
+        Self { known_hosts, host, provider }
+    }
+}
+
+impl ServerCertVerifier for TofuVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.known_hosts
+            .lookup(&self.host, end_entity)
+            .map(|_| ServerCertVerified::assertion())
+            .map_err(|e| rustls::Error::General(e.to_string()))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_known_hosts() {
+        let path = std::env::temp_dir().join(format!("known-hosts-test-{}", std::process::id()));
+        let known_hosts = KnownHosts::load(&path).unwrap();
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let host = host_key(addr, "localhost").unwrap();
+        assert_eq!(host, "localhost@127.0.0.1:5000");
+        // 名称中的空白会破坏文件格式
+        assert!(host_key(addr, "evil host").is_err());
+        assert!(host_key(addr, "").is_err());
+
+        // 查找不会记录新的主机
+        assert!(!known_hosts.lookup(&host, b"first cert").unwrap());
+        assert!(known_hosts.get(&host).is_none());
+
+        // 第一次连接时记录，之后同一个证书可以连接，不同的证书拒绝
+        known_hosts.check(&host, b"first cert").unwrap();
+        assert!(known_hosts.lookup(&host, b"first cert").unwrap());
+        known_hosts.check(&host, b"first cert").unwrap();
+        let err = known_hosts.check(&host, b"second cert").unwrap_err();
+        assert!(err.to_string().contains("has changed"), "{err}");
+
+        // 重新读取文件后仍然记得
+        let reloaded = KnownHosts::load(&path).unwrap().on_first_use(|_, _| false);
+        assert_eq!(reloaded.get(&host), Some(fingerprint(b"first cert")));
+        assert!(reloaded.check(&host, b"second cert").is_err());
+        assert!(reloaded.check("other@127.0.0.1:5001", b"cert").is_err());
+        assert!(reloaded.remove(&host).unwrap());
+        assert!(KnownHosts::load(&path).unwrap().get(&host).is_none());
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        assert!(!Path::new(&temp).exists());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1 +1,3 @@
+pub mod client_auth;
+pub mod known_hosts;
 pub mod quic_endpoint;
\ No newline at end of file
@@ -1,44 +1,170 @@
 use anyhow::Ok;
-use quinn::{Endpoint, ClientConfig, ServerConfig};
+use quinn::{Connection, Endpoint, ClientConfig, ServerConfig, TransportConfig};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
 use rustls::pki_types::pem::PemObject;
 use rustls::pki_types::CertificateDer;
-use rustls::pki_types::PrivatePkcs8KeyDer;
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
 use std::net::SocketAddr;
 use anyhow::Result;
 use std::sync::Arc;
-use std::net::IpAddr;
-use std::net::Ipv4Addr;
 use std::path::Path;
+use std::time::Duration;
+
+use crate::client_auth::ClientAuth;
+use crate::known_hosts::{fingerprint, host_key, KnownHosts, TofuVerifier};
+
+/// 客户端发送 keep-alive 的间隔，小于默认的空闲超时
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 生成自签名证书，返回 DER 格式的证书和私钥
+/// 不写入文件，用于测试或者临时的服务器
+pub fn generate_self_signed(subject_alt_names: Vec<String>) -> Result<(CertificateDer<'static>, PrivatePkcs8KeyDer<'static>)> {
+    let certified_key = rcgen::generate_simple_self_signed(subject_alt_names)?;
+    let cert_der = certified_key.cert.der().clone();
+    let priv_key = PrivatePkcs8KeyDer::from(certified_key.key_pair.serialize_der());
+    Ok((cert_der, priv_key))
+}
+
+/// 客户端证书和私钥
+type Identity = (CertificateDer<'static>, PrivatePkcs8KeyDer<'static>);
+
+/// 服务器证书文件名
+pub const CERT_FILE: &str = "server.cert";
+
+/// 服务器私钥文件名
+pub const KEY_FILE: &str = "server.key";
 
 #[derive(Debug)]
 pub struct ClientEndpoint {
     endpoint: Endpoint,
     bind_addr: SocketAddr,
+    /// 使用 known_hosts 检查服务器证书时才有
+    known_hosts: Option<Arc<KnownHosts>>,
+    /// 只信任这个服务器证书时才有
+    server_cert: Option<CertificateDer<'static>>,
+    /// 服务器要求客户端证书时出示
+    identity: Option<Identity>,
 }
 
 impl ClientEndpoint {
 
     pub fn new(bind_addr: SocketAddr, server_cert_path: &Path) -> Result<Self> {
-        let config = Self::generate_client_config(server_cert_path)?;
+        Self::with_cert(bind_addr, CertificateDer::from_pem_file(server_cert_path)?)
+    }
+
+    /// 使用内存中的服务器证书创建客户端
+    pub fn with_cert(bind_addr: SocketAddr, server_cert: CertificateDer<'static>) -> Result<Self> {
+        let config = Self::generate_client_config(server_cert.clone(), None)?;
         let mut endpoint = Endpoint::client(bind_addr)?;
         endpoint.set_default_client_config(config);
         Ok(ClientEndpoint {
-            endpoint: endpoint,
-            bind_addr: bind_addr,
+            endpoint,
+            bind_addr,
+            known_hosts: None,
+            server_cert: Some(server_cert),
+            identity: None,
         })
     }
+
+    /// 不需要提前拿到服务器证书，第一次连接时信任服务器的证书，之后证书变化时拒绝连接
+    /// 需要使用 [`ClientEndpoint::connect`] 连接
+    pub fn with_known_hosts(bind_addr: SocketAddr, known_hosts: KnownHosts) -> Result<Self> {
+        let endpoint = Endpoint::client(bind_addr)?;
+        Ok(ClientEndpoint {
+            endpoint,
+            bind_addr,
+            known_hosts: Some(Arc::new(known_hosts)),
+            server_cert: None,
+            identity: None,
+        })
+    }
+
+    /// 服务器要求客户端证书时使用这个证书和私钥，见 [`ClientAuth`]
+    pub fn with_identity(mut self, cert: CertificateDer<'static>, key: PrivatePkcs8KeyDer<'static>) -> Result<Self> {
+        let identity = (cert, key);
+        if let Some(server_cert) = &self.server_cert {
+            let config = Self::generate_client_config(server_cert.clone(), Some(&identity))?;
+            self.endpoint.set_default_client_config(config);
+        }
+        self.identity = Some(identity);
+        Ok(self)
+    }
+
+    /// 连接服务器，使用 known_hosts 时按地址和名称检查证书
+    /// 第一次连接的服务器在握手完成、证明持有证书的私钥之后才确认并记录，不信任时关闭连接
+    pub async fn connect(&self, addr: SocketAddr, server_name: &str) -> Result<Connection> {
+        let Some(known_hosts) = &self.known_hosts else {
+            return Ok(self.endpoint.connect(addr, server_name)?.await?);
+        };
+        let host = host_key(addr, server_name)?;
+        let config = Self::generate_tofu_config(known_hosts.clone(), host.clone(), self.identity.as_ref())?;
+        let connection = self.endpoint.connect_with(config, addr, server_name)?.await?;
+        let trusted = connection
+            .peer_identity()
+            .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+            .and_then(|certs| certs.into_iter().next())
+            .ok_or_else(|| anyhow::anyhow!("server did not present a certificate"))
+            .and_then(|cert| known_hosts.check(&host, &cert));
+        if let Err(e) = trusted {
+            connection.close(0u32.into(), b"untrusted certificate");
+            return Err(e);
+        }
+        Ok(connection)
+    }
+
+    /// 信任的服务器证书，没有使用 known_hosts 时为 None
+    pub fn known_hosts(&self) -> Option<&KnownHosts> {
+        self.known_hosts.as_deref()
+    }
     
     /// Constructs a QUIC endpoint configured for use a client only.
-    fn generate_client_config(server_cert_path: &Path) -> Result<ClientConfig> {
+    fn generate_client_config(server_cert: CertificateDer<'static>, identity: Option<&Identity>) -> Result<ClientConfig> {
         let mut certs = rustls::RootCertStore::empty();
-        certs.add(CertificateDer::from_pem_file(server_cert_path)?)?;
-        ClientConfig::with_platform_verifier();
-        Ok(ClientConfig::with_root_certificates(Arc::new(certs))?)
+        certs.add(server_cert)?;
+        let builder = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_root_certificates(certs);
+        Self::finish_client_config(builder, identity)
+    }
+
+    /// 按 known_hosts 中 host 的记录检查证书的客户端配置
+    fn generate_tofu_config(known_hosts: Arc<KnownHosts>, host: String, identity: Option<&Identity>) -> Result<ClientConfig> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = TofuVerifier::new(known_hosts, host, provider.clone());
+        let builder = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier));
+        Self::finish_client_config(builder, identity)
+    }
+
+    /// 加上客户端证书和传输配置
+    fn finish_client_config(
+        builder: rustls::ConfigBuilder<rustls::ClientConfig, rustls::client::WantsClientCert>,
+        identity: Option<&Identity>,
+    ) -> Result<ClientConfig> {
+        let crypto = match identity {
+            Some((cert, key)) => builder.with_client_auth_cert(vec![cert.clone()], key.clone_key().into())?,
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+        config.transport_config(Self::transport_config());
+        Ok(config)
+    }
+
+    /// 传输暂停时连接上没有数据，定时发送 keep-alive 避免空闲超时
+    fn transport_config() -> Arc<TransportConfig> {
+        let mut transport = TransportConfig::default();
+        transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+        Arc::new(transport)
     }
 
     pub fn get_endpoint(&self) -> &Endpoint {
         &self.endpoint
     }
+    pub fn get_bind_addr(&self) -> SocketAddr {
+        self.bind_addr
+    }
 
     
 
@@ -50,27 +176,70 @@ impl ClientEndpoint {
 pub struct ServerEndpointContainer {
     endpoint: Endpoint,
     bind_addr: SocketAddr,
+    cert_der: CertificateDer<'static>,
+    priv_key: PrivatePkcs8KeyDer<'static>,
 }
 
 impl ServerEndpointContainer {
 
     pub fn new(bind_addr: SocketAddr, cert_path: &Path, server_key_path: &Path) -> Result<Self> {
-        let server_config = Self::generate_server_config(cert_path, server_key_path)?;
+        let cert_der = CertificateDer::from_pem_file(cert_path)?;
+        let priv_key = PrivatePkcs8KeyDer::from_pem_file(server_key_path)?;
+        Self::with_cert(bind_addr, cert_der, priv_key)
+    }
+
+    /// 使用 dir 中的证书和私钥创建服务器，不存在时生成自签名证书并保存
+    /// 证书保持不变，客户端第一次连接时记录证书指纹，之后可以发现服务器被替换
+    pub fn load_or_generate(bind_addr: SocketAddr, dir: &Path, subject_alt_names: Vec<String>) -> Result<Self> {
+        let (cert_path, key_path) = (dir.join(CERT_FILE), dir.join(KEY_FILE));
+        if !cert_path.exists() || !key_path.exists() {
+            let certified_key = rcgen::generate_simple_self_signed(subject_alt_names)?;
+            std::fs::create_dir_all(dir)?;
+            write_private(&key_path, certified_key.key_pair.serialize_pem().as_bytes())?;
+            std::fs::write(&cert_path, certified_key.cert.pem())?;
+        }
+        Self::new(bind_addr, &cert_path, &key_path)
+    }
+
+    /// 使用内存中的证书和私钥创建服务器，绑定端口为 0 时使用系统分配的端口
+    pub fn with_cert(bind_addr: SocketAddr, cert_der: CertificateDer<'static>, priv_key: PrivatePkcs8KeyDer<'static>) -> Result<Self> {
+        let server_config = Self::generate_server_config(cert_der.clone(), priv_key.clone_key().into(), &ClientAuth::None)?;
         let endpoint = Endpoint::server(server_config, bind_addr)?;
+        // 记录实际监听的地址
+        let bind_addr = endpoint.local_addr()?;
         Ok(ServerEndpointContainer {
-            endpoint: endpoint,
-            bind_addr: bind_addr,
+            endpoint,
+            bind_addr,
+            cert_der,
+            priv_key,
         })
     }
+
+    /// 设置如何验证客户端，之后的连接生效
+    /// 要求客户端证书时用 [`crate::client_auth::ClientIdentity::from_connection`] 得到连接的客户端
+    pub fn set_client_auth(&self, client_auth: &ClientAuth) -> Result<()> {
+        let server_config = Self::generate_server_config(self.cert_der.clone(), self.priv_key.clone_key().into(), client_auth)?;
+        self.endpoint.set_server_config(Some(server_config));
+        Ok(())
+    }
     
     /// Constructs a QUIC endpoint configured for use a server only.
-    fn generate_server_config(cert_path: &Path, server_key_path: &Path) -> Result<ServerConfig> {
-        // 使用 rcgen 生成自签名证书
-        //let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-        let cert_der = CertificateDer::from_pem_file(cert_path)?;
-        let priv_key = PrivatePkcs8KeyDer::from_pem_file(server_key_path)?;
-        let mut server_config =
-            ServerConfig::with_single_cert(vec![cert_der.clone()], priv_key.into())?;
+    fn generate_server_config(
+        cert_der: CertificateDer<'static>,
+        priv_key: PrivateKeyDer<'static>,
+        client_auth: &ClientAuth,
+    ) -> Result<ServerConfig> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut server_config = match client_auth.verifier(provider.clone())? {
+            None => ServerConfig::with_single_cert(vec![cert_der], priv_key)?,
+            Some(verifier) => {
+                let crypto = rustls::ServerConfig::builder_with_provider(provider)
+                    .with_protocol_versions(&[&rustls::version::TLS13])?
+                    .with_client_cert_verifier(verifier)
+                    .with_single_cert(vec![cert_der], priv_key)?;
+                ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?))
+            }
+        };
         let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
         transport_config.max_concurrent_uni_streams(0_u8.into());
 
@@ -83,26 +252,195 @@ impl ServerEndpointContainer {
     pub fn get_bind_addr(&self) -> SocketAddr {
         self.bind_addr
     }
+
+    /// 服务器证书的指纹，客户端第一次连接时显示同样的指纹，可以用来核对
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.cert_der)
+    }
     
 }
 
-/// Attempt QUIC connection with the given server address.
-async fn run_client(endpoint: &Endpoint, server_addr: SocketAddr) {
-    let connect = endpoint.connect(server_addr, "localhost").unwrap();
-    let connection = connect.await.unwrap();
-    println!("[client] connected: addr={}", connection.remote_address());
+/// 写入只有自己可以读取的文件，用于私钥
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, contents)?;
+    Ok(())
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::client_auth::ClientIdentity;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    /// Attempt QUIC connection with the given server address.
+    async fn run_client(endpoint: &Endpoint, server_addr: SocketAddr) {
+        let connect = endpoint.connect(server_addr, "localhost").unwrap();
+        let connection = connect.await.unwrap();
+        println!("[client] connected: addr={}", connection.remote_address());
+    }
 
     #[tokio::test]
     async fn test_client_endpoint() {
-        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
-        let server_endpoint = ServerEndpointContainer::new(addr1, Path::new("server.cert"), Path::new("server.key")).unwrap();
+        let (cert, key) = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let server_endpoint = ServerEndpointContainer::with_cert(addr, cert.clone(), key).unwrap();
         println!("[server] listening on: addr={:?}", server_endpoint.bind_addr);
-        let client_endpoint = ClientEndpoint::new(addr1, Path::new("server.cert")).unwrap();
+        let client_endpoint = ClientEndpoint::with_cert(addr, cert).unwrap();
         println!("[client] listening on: addr={:?}", client_endpoint.endpoint.local_addr());
 
+        let server = server_endpoint.get_endpoint().clone();
+        let accept = tokio::spawn(async move { server.accept().await.unwrap().await.unwrap() });
+        run_client(client_endpoint.get_endpoint(), server_endpoint.get_bind_addr()).await;
+        accept.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_trust_on_first_use() {
+        let dir = std::env::temp_dir().join(format!("quic-tofu-test-{}", std::process::id()));
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let names = vec!["localhost".to_string()];
+        let server_endpoint = ServerEndpointContainer::load_or_generate(addr, &dir, names.clone()).unwrap();
+        let server_addr = server_endpoint.get_bind_addr();
+        let fingerprint = server_endpoint.fingerprint();
+        // 重新启动后使用保存的证书
+        assert_eq!(ServerEndpointContainer::load_or_generate(addr, &dir, names.clone()).unwrap().fingerprint(), fingerprint);
+
+        let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let client_endpoint = ClientEndpoint::with_known_hosts(client_addr, KnownHosts::in_memory()).unwrap();
+        let server = server_endpoint.get_endpoint().clone();
+        let accept = tokio::spawn(async move { server.accept().await.unwrap().await.unwrap() });
+        client_endpoint.connect(server_addr, "localhost").await.unwrap();
+        accept.await.unwrap();
+        let host = host_key(server_addr, "localhost").unwrap();
+        assert_eq!(client_endpoint.known_hosts().unwrap().get(&host), Some(fingerprint.clone()));
+
+        // 换了证书的服务器被拒绝，这里在 known_hosts 中把新服务器记为原来的证书
+        std::fs::remove_dir_all(&dir).unwrap();
+        let replaced = ServerEndpointContainer::load_or_generate(addr, &dir, names).unwrap();
+        let replaced_addr = replaced.get_bind_addr();
+        let known_hosts = client_endpoint.known_hosts().unwrap();
+        known_hosts.insert(&host_key(replaced_addr, "localhost").unwrap(), &fingerprint).unwrap();
+        let server = replaced.get_endpoint().clone();
+        tokio::spawn(async move { server.accept().await.unwrap().await });
+        let err = client_endpoint.connect(replaced_addr, "localhost").await.unwrap_err();
+        assert!(err.to_string().contains("has changed"), "{err}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// 出示其他服务器证书的服务器，没有这个证书的私钥，握手时签名验证失败
+    #[derive(Debug)]
+    struct ReplayedCert(Arc<rustls::sign::CertifiedKey>);
+
+    impl rustls::server::ResolvesServerCert for ReplayedCert {
+        fn resolve(&self, _client_hello: rustls::server::ClientHello<'_>) -> Option<Arc<rustls::sign::CertifiedKey>> {
+            Some(self.0.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_handshake_not_trusted() {
+        let (cert, _) = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (_, other_key) = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let signing_key = provider.key_provider.load_private_key(other_key.into()).unwrap();
+        let replayed = ReplayedCert(Arc::new(rustls::sign::CertifiedKey::new(vec![cert.clone()], signing_key)));
+        let crypto = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(replayed));
+        let server_config = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto).unwrap()));
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let server = Endpoint::server(server_config, addr).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.accept().await.unwrap().await });
+
+        // 握手失败时不记录服务器的证书
+        let client_endpoint = ClientEndpoint::with_known_hosts(addr, KnownHosts::in_memory()).unwrap();
+        assert!(client_endpoint.connect(server_addr, "localhost").await.is_err());
+        let host = host_key(server_addr, "localhost").unwrap();
+        assert_eq!(client_endpoint.known_hosts().unwrap().get(&host), None);
+    }
+
+    /// 客户端连接后服务器看到的客户端身份，服务器拒绝客户端时为 Err
+    /// TLS 1.3 中客户端可能在服务器验证客户端证书前就完成握手，所以在服务器端检查
+    async fn accept_client(server: &ServerEndpointContainer, client: &ClientEndpoint) -> Result<Option<ClientIdentity>> {
+        let endpoint = server.get_endpoint().clone();
+        let accept = tokio::spawn(async move { endpoint.accept().await.unwrap().await });
+        let (accepted, _) = tokio::join!(accept, client.connect(server.get_bind_addr(), "localhost"));
+        let connection = accepted??;
+        Ok(ClientIdentity::from_connection(&connection))
+    }
+
+    fn local_client(server_cert: &CertificateDer<'static>) -> ClientEndpoint {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        ClientEndpoint::with_cert(addr, server_cert.clone()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_pinned_client_auth() {
+        let (cert, key) = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let server = ServerEndpointContainer::with_cert(addr, cert.clone(), key).unwrap();
+        let (client_cert, client_key) = generate_self_signed(vec!["alice".to_string()]).unwrap();
+        let (other_cert, other_key) = generate_self_signed(vec!["bob".to_string()]).unwrap();
+
+        // 默认不要求客户端证书
+        assert_eq!(accept_client(&server, &local_client(&cert)).await.unwrap(), None);
+
+        server.set_client_auth(&ClientAuth::Pinned(vec![fingerprint(&client_cert)])).unwrap();
+        let client = local_client(&cert).with_identity(client_cert.clone(), client_key).unwrap();
+        let identity = accept_client(&server, &client).await.unwrap().unwrap();
+        assert_eq!(identity.fingerprint, fingerprint(&client_cert));
+        assert_eq!(identity.certs, vec![client_cert]);
+
+        // 没有证书或证书不在列表中的客户端被拒绝
+        assert!(accept_client(&server, &local_client(&cert)).await.is_err());
+        let other = local_client(&cert).with_identity(other_cert, other_key).unwrap();
+        assert!(accept_client(&server, &other).await.is_err());
     }
-}
\ No newline at end of file
+
+    /// 由 CA 签发的客户端证书
+    fn issue_client_cert(ca_cert: &rcgen::Certificate, ca_key: &rcgen::KeyPair, name: &str) -> (CertificateDer<'static>, PrivatePkcs8KeyDer<'static>) {
+        let mut params = rcgen::CertificateParams::new(vec![name.to_string()]).unwrap();
+        params.distinguished_name.push(rcgen::DnType::CommonName, name);
+        params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, ca_cert, ca_key).unwrap();
+        (cert.into(), PrivatePkcs8KeyDer::from(key.serialize_der()))
+    }
+
+    #[tokio::test]
+    async fn test_ca_client_auth() {
+        let (cert, key) = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let server = ServerEndpointContainer::with_cert(addr, cert.clone(), key).unwrap();
+
+        let new_ca = |name: &str| {
+            let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.distinguished_name.push(rcgen::DnType::CommonName, name);
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            let key = rcgen::KeyPair::generate().unwrap();
+            (params.self_signed(&key).unwrap(), key)
+        };
+        let (ca_cert, ca_key) = new_ca("test ca");
+        let (other_ca_cert, other_ca_key) = new_ca("other ca");
+        server.set_client_auth(&ClientAuth::Ca(vec![ca_cert.der().clone()])).unwrap();
+
+        let (client_cert, client_key) = issue_client_cert(&ca_cert, &ca_key, "alice");
+        let client = local_client(&cert).with_identity(client_cert.clone(), client_key).unwrap();
+        let identity = accept_client(&server, &client).await.unwrap().unwrap();
+        assert_eq!(identity.fingerprint, fingerprint(&client_cert));
+
+        // 其他 CA 签发的证书和没有证书的客户端被拒绝
+        let (other_cert, other_key) = issue_client_cert(&other_ca_cert, &other_ca_key, "bob");
+        let other = local_client(&cert).with_identity(other_cert, other_key).unwrap();
+        assert!(accept_client(&server, &other).await.is_err());
+        assert!(accept_client(&server, &local_client(&cert)).await.is_err());
+        assert!(server.set_client_auth(&ClientAuth::Ca(Vec::new())).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    sync::{Arc, RwLock},
+};
+
+use futures::future::BoxFuture;
+use iroh::{endpoint::Connection, protocol::ProtocolHandler};
+use iroh_blobs::{
+    format::collection::Collection,
+    net_protocol::Blobs,
+    provider::handle_connection,
+    store::{Map, Store},
+    Hash,
+};
+
+use crate::{
+    limit::{RateLimiter, ThrottledEntry, UploadLimits},
+    ticket::unix_now,
+};
+
+/// 一次分享的访问规则
+#[derive(Debug, Default)]
+struct ShareRule {
+    /// 过期时间（unix 秒）
+    expires: Option<u64>,
+}
+
+impl ShareRule {
+    fn is_valid(&self) -> bool {
+        self.expires.is_none_or(|expires| unix_now() < expires)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    shares: BTreeMap<u64, ShareRule>,
+    /// 每个哈希属于哪些分享，集合为空时这个哈希的分享都已撤销，仍然拒绝提供
+    owners: BTreeMap<Hash, BTreeSet<u64>>,
+}
+
+impl Rules {
+    /// 删除分享的规则，它的哈希不再属于这个分享
+    fn remove(&mut self, ids: &BTreeSet<u64>) {
+        if ids.is_empty() {
+            return;
+        }
+        self.shares.retain(|id, _| !ids.contains(id));
+        for owners in self.owners.values_mut() {
+            owners.retain(|id| !ids.contains(id));
+        }
+    }
+}
+
+/// 分享的访问控制：按分享编号记录集合中的全部哈希，撤销或过期的分享不再提供
+/// 同样的内容属于多个分享时，只要还有一个分享有效就继续提供
+/// 不属于任何分享的数据不受限制，撤销的分享中的数据在存储删除之前一直拒绝提供
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    rules: Arc<RwLock<Rules>>,
+}
+
+impl AccessList {
+    /// 记录分享 id 的全部哈希，expires（unix 秒）后不再提供
+    pub fn add(&self, id: u64, hashes: impl IntoIterator<Item = Hash>, expires: Option<u64>) {
+        let mut rules = self.rules.write().unwrap();
+        rules.shares.insert(id, ShareRule { expires });
+        for hash in hashes {
+            rules.owners.entry(hash).or_default().insert(id);
+        }
+    }
+
+    /// 立即停止提供分享 id 并删除它的规则，其他分享中相同的内容不受影响
+    /// 不再属于任何分享的哈希仍然拒绝提供，存储删除数据后用 [`AccessList::forget`] 删除
+    pub fn revoke(&self, id: u64) {
+        self.rules.write().unwrap().remove(&BTreeSet::from([id]));
+    }
+
+    /// 删除已经过期的分享的规则，与撤销相同
+    pub fn remove_expired(&self) {
+        let mut rules = self.rules.write().unwrap();
+        let expired = rules
+            .shares
+            .iter()
+            .filter(|(_, rule)| !rule.is_valid())
+            .map(|(id, _)| *id)
+            .collect();
+        rules.remove(&expired);
+    }
+
+    /// 拒绝提供不属于任何分享的哈希，例如下载到存储中的数据，已经属于分享的哈希不受影响
+    /// 存储删除数据后同样用 [`AccessList::forget`] 删除
+    pub fn hide(&self, hashes: impl IntoIterator<Item = Hash>) {
+        let mut rules = self.rules.write().unwrap();
+        for hash in hashes {
+            rules.owners.entry(hash).or_default();
+        }
+    }
+
+    /// 不再属于任何分享、仍然拒绝提供的哈希
+    pub fn orphans(&self) -> Vec<Hash> {
+        let rules = self.rules.read().unwrap();
+        rules
+            .owners
+            .iter()
+            .filter(|(_, owners)| owners.is_empty())
+            .map(|(hash, _)| *hash)
+            .collect()
+    }
+
+    /// 存储已经删除这些哈希的数据后不再记录，之后又被分享的哈希不受影响
+    pub fn forget(&self, hashes: impl IntoIterator<Item = Hash>) {
+        let mut rules = self.rules.write().unwrap();
+        for hash in hashes {
+            if rules.owners.get(&hash).is_some_and(BTreeSet::is_empty) {
+                rules.owners.remove(&hash);
+            }
+        }
+    }
+
+    pub fn is_allowed(&self, hash: &Hash) -> bool {
+        let rules = self.rules.read().unwrap();
+        match rules.owners.get(hash) {
+            Some(owners) => owners
+                .iter()
+                .any(|id| rules.shares.get(id).is_some_and(ShareRule::is_valid)),
+            None => true,
+        }
+    }
+}
+
+/// 集合涉及的全部哈希：哈希序列、文件名列表和全部条目
+pub fn collection_hashes(collection: &Collection) -> BTreeSet<Hash> {
+    let mut hashes = collection.iter().map(|(_, hash)| *hash).collect::<BTreeSet<_>>();
+    hashes.extend(collection.to_blobs().map(Hash::new));
+    hashes
+}
+
+/// 按访问控制过滤的存储，被拦截的数据视为不存在
+/// 读取的数据按连接对应的限速器限速
+#[derive(Debug, Clone)]
+struct FilteredStore<D> {
+    db: D,
+    access: AccessList,
+    limiters: Arc<[RateLimiter]>,
+}
+
+impl<D: Map> Map for FilteredStore<D> {
+    type Entry = ThrottledEntry<D::Entry>;
+
+    fn get(&self, hash: &Hash) -> impl Future<Output = std::io::Result<Option<Self::Entry>>> + Send {
+        let allowed = self.access.is_allowed(hash);
+        let db = self.db.clone();
+        let limiters = self.limiters.clone();
+        let hash = *hash;
+        async move {
+            match allowed {
+                true => Ok(db.get(&hash).await?.map(|entry| ThrottledEntry::new(entry, limiters))),
+                false => Ok(None),
+            }
+        }
+    }
+}
+
+/// 带访问控制的 blobs 协议，代替 Blobs 注册到 Router
+#[derive(Debug, Clone)]
+pub struct AccessProtocol<S> {
+    blobs: Blobs<S>,
+    access: AccessList,
+    limits: UploadLimits,
+}
+
+impl<S: Store> AccessProtocol<S> {
+    pub fn new(blobs: Blobs<S>, access: AccessList) -> Self {
+        Self {
+            blobs,
+            access,
+            limits: UploadLimits::default(),
+        }
+    }
+
+    /// 限制提供数据的速度
+    pub fn with_limits(mut self, limits: UploadLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+}
+
+impl<S: Store> ProtocolHandler for AccessProtocol<S> {
+    fn accept(&self, connection: Connection) -> BoxFuture<'static, anyhow::Result<()>> {
+        let db = self.blobs.store().clone();
+        let access = self.access.clone();
+        let limits = self.limits.clone();
+        let events = self.blobs.events().clone();
+        let rt = self.blobs.rt().clone();
+        Box::pin(async move {
+            // 同一节点的连接共享限速，连接结束后释放
+            let peer = limits.for_peer(connection.remote_node_id()?);
+            let db = FilteredStore { db, access, limiters: peer.limiters() };
+            handle_connection(connection, db, events, rt).await;
+            drop(peer);
+            Ok(())
+        })
+    }
+
+    fn shutdown(&self) -> BoxFuture<'static, ()> {
+        self.blobs.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_access_list() {
+        let access = AccessList::default();
+        let collection = [("a.txt", "a"), ("b.txt", "b")]
+            .into_iter()
+            .map(|(name, content)| (name.to_string(), Hash::new(content)))
+            .collect::<Collection>();
+        let hashes = collection_hashes(&collection);
+        // 哈希序列、文件名列表和两个文件
+        assert_eq!(hashes.len(), 4);
+        let child = Hash::new("b");
+        let open = Hash::new(b"open");
+        access.add(1, hashes.clone(), Some(unix_now() + 3600));
+        assert!(access.is_allowed(&child));
+        assert!(access.is_allowed(&open));
+
+        // 撤销后集合中的文件也不再提供
+        access.revoke(1);
+        assert!(hashes.iter().all(|hash| !access.is_allowed(hash)));
+        assert!(access.is_allowed(&open));
+
+        // 相同内容的另一个分享仍然有效
+        access.add(2, [child], None);
+        assert!(access.is_allowed(&child));
+        assert!(!access.is_allowed(&Hash::new("a")));
+
+        // 过期后不再提供
+        access.add(3, [open], Some(unix_now() - 1));
+        assert!(!access.is_allowed(&open));
+
+        // 撤销和过期的分享不再保留规则，只剩下拒绝提供的哈希
+        access.remove_expired();
+        access.revoke(2);
+        {
+            let rules = access.rules.read().unwrap();
+            assert!(rules.shares.is_empty());
+            assert!(rules.owners.values().all(BTreeSet::is_empty));
+        }
+        let orphans = access.orphans();
+        assert_eq!(orphans.len(), 5);
+        assert!(orphans.iter().all(|hash| !access.is_allowed(hash)));
+        // 数据删除后不再记录
+        access.forget(orphans);
+        assert!(access.rules.read().unwrap().owners.is_empty());
+        assert!(access.orphans().is_empty());
+
+        // 下载的数据不提供，之后分享时按分享的规则提供
+        let downloaded = Hash::new("downloaded");
+        access.hide([downloaded]);
+        assert!(!access.is_allowed(&downloaded));
+        access.add(4, [downloaded], None);
+        access.hide([downloaded]);
+        assert!(access.is_allowed(&downloaded));
+    }
+}
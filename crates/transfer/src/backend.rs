@@ -0,0 +1,413 @@
+//! 可替换的传输后端
+//! 发送端用 [`Backend::offer`] 分享文件或目录得到分享码，接收端用 [`Backend::connect`] 连接发送端，
+//! 再用 [`Backend::fetch`] 下载，两个后端使用相同的流程，见测试中的 conformance
+//! libp2p 的 ping 示例不传输文件，没有对应的后端
+use std::{future::Future, net::{Ipv4Addr, SocketAddr, UdpSocket}, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use anyhow::{Context, Result};
+use file_transfer::{directory::{DirectoryReceiver, DirectoryTransfer}, progress::Progress};
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use iroh::{protocol::Router, Endpoint, NodeAddr, SecretKey};
+use iroh_blobs::{net_protocol::Blobs, ticket::BlobTicket, BlobFormat, HashAndFormat, TempTag};
+use quic_transport::{known_hosts::{host_key, KnownHosts}, quic_endpoint::{generate_self_signed, ClientEndpoint, ServerEndpointContainer}};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::warn;
+
+use crate::{
+    cli::{Commands, ReceiveArgs, SendArgs},
+    collection::{DEFAULT_MAX_ENTRIES, DEFAULT_MAX_NAMES_SIZE},
+    fetch::{FetchOptions, DEFAULT_PARALLEL},
+    meta::ShareMeta,
+    signature::SignaturePolicy,
+    ticket::{QuicTicket, ShareTicket},
+    transfer::{download, import, resolve_ticket},
+    trust::TrustStore,
+};
+
+/// quinn 后端默认的分块大小
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// 传输后端
+pub trait Backend {
+    /// 接收端和发送端之间的连接
+    type Session;
+
+    /// 开始分享文件或目录，返回接收端使用的分享码
+    fn offer(&mut self, path: &Path) -> impl Future<Output = Result<ShareTicket>>;
+
+    /// 连接分享码指向的发送端
+    fn connect(&self, ticket: ShareTicket) -> impl Future<Output = Result<Self::Session>>;
+
+    /// 下载分享的内容到 root，返回保存的文件或目录
+    /// 下载进度通过 progress 发送，最后一次的字节数等于总大小
+    fn fetch(
+        &self,
+        session: Self::Session,
+        root: &Path,
+        progress: Option<mpsc::UnboundedSender<Progress>>,
+    ) -> impl Future<Output = Result<PathBuf>>;
+
+    /// 停止分享并释放资源
+    fn shutdown(self) -> impl Future<Output = Result<()>>;
+}
+
+/// iroh-blobs 后端，分享的内容导入 data_dir 中的存储，集合使用 signer 签名
+#[derive(Debug)]
+pub struct IrohBackend {
+    router: Router,
+    blobs: Blobs<iroh_blobs::store::fs::Store>,
+    data_dir: PathBuf,
+    signer: SecretKey,
+    trust: TrustStore,
+    tags: Vec<TempTag>,
+}
+
+/// iroh 后端的连接，下载时再连接提供者
+#[derive(Debug)]
+pub struct IrohSession {
+    addr: NodeAddr,
+    hash_and_format: HashAndFormat,
+}
+
+impl IrohBackend {
+    /// 在 endpoint 上提供分享，data_dir 在关闭时删除
+    pub async fn new(endpoint: Endpoint, data_dir: PathBuf, signer: SecretKey, trust: TrustStore) -> Result<Self> {
+        let blobs = Blobs::persistent(&data_dir).await?.build(&endpoint);
+        let router = Router::builder(endpoint).accept(iroh_blobs::ALPN, blobs.clone()).spawn();
+        Ok(Self { router, blobs, data_dir, signer, trust, tags: Vec::new() })
+    }
+}
+
+impl Backend for IrohBackend {
+    type Session = IrohSession;
+
+    async fn offer(&mut self, path: &Path) -> Result<ShareTicket> {
+        let meta = ShareMeta::default();
+        let (tag, _, _) = import(path.to_path_buf(), self.blobs.store().clone(), &meta, &self.signer, false).await?;
+        let addr = self.router.endpoint().node_addr().await?;
+        let ticket = BlobTicket::new(addr, *tag.hash(), BlobFormat::HashSeq)?;
+        // 分享期间保留导入的内容
+        self.tags.push(tag);
+        Ok(ShareTicket::Blob(ticket))
+    }
+
+    async fn connect(&self, ticket: ShareTicket) -> Result<IrohSession> {
+        let endpoint = self.router.endpoint();
+        let (addr, hash_and_format) = resolve_ticket(endpoint, ticket).await?;
+        anyhow::ensure!(hash_and_format.format == BlobFormat::HashSeq, "ticket does not point to a collection");
+        // 先连接一次，连接不上时尽早报错
+        let connection = endpoint.connect(addr.clone(), iroh_blobs::ALPN).await?;
+        connection.close(0u32.into(), b"done");
+        Ok(IrohSession { addr, hash_and_format })
+    }
+
+    /// 下载时转发 download 的进度，完成后按保存的文件再发送一次
+    async fn fetch(
+        &self,
+        session: IrohSession,
+        root: &Path,
+        progress: Option<mpsc::UnboundedSender<Progress>>,
+    ) -> Result<PathBuf> {
+        let start = Instant::now();
+        let policy = SignaturePolicy { author: None, require_trusted: false, trust: self.trust.clone() };
+        let endpoint = self.router.endpoint();
+        let providers = vec![session.addr];
+        let options = FetchOptions { progress: progress.as_ref(), ..Default::default() };
+        let downloaded = download(endpoint, providers, session.hash_and_format, root, &policy, &self.signer, options).await?;
+        let files = downloaded.files().await;
+        downloaded.cleanup().await?;
+        let files = files?;
+        let mut total = 0;
+        for name in &files {
+            total += tokio::fs::metadata(root.join(name)).await?.len();
+        }
+        if let Some(progress) = progress {
+            let rate = total as f64 / start.elapsed().as_secs_f64().max(f64::EPSILON);
+            progress.send(Progress { bytes: total, total, rate, eta: Some(Duration::ZERO) }).ok();
+        }
+        let first = files.first().and_then(|name| name.split('/').next()).context("share is empty")?;
+        Ok(root.join(first))
+    }
+
+    async fn shutdown(self) -> Result<()> {
+        drop(self.tags);
+        tokio::time::timeout(Duration::from_secs(2), self.router.shutdown()).await??;
+        tokio::fs::remove_dir_all(self.data_dir).await?;
+        Ok(())
+    }
+}
+
+/// quinn 后端，每次分享在新的端口上用自签名证书监听，分享码中包含地址和证书指纹
+/// 接收端连接后发送端发送目录清单和所有文件，见 [`DirectoryTransfer`]
+#[derive(Debug)]
+pub struct QuinnBackend {
+    bind_addr: SocketAddr,
+    /// 发送文件的分块大小
+    pub chunk_size: usize,
+    servers: Vec<(ServerEndpointContainer, JoinHandle<()>)>,
+}
+
+/// quinn 后端的连接
+#[derive(Debug)]
+pub struct QuinnSession {
+    connection: quinn::Connection,
+    /// 连接使用的端点，需要在连接期间保留
+    _endpoint: ClientEndpoint,
+}
+
+impl QuinnBackend {
+    /// 分享时监听 bind_addr，端口为 0 时使用系统分配的端口
+    pub fn new(bind_addr: SocketAddr) -> Self {
+        Self { bind_addr, chunk_size: DEFAULT_CHUNK_SIZE, servers: Vec::new() }
+    }
+}
+
+impl Backend for QuinnBackend {
+    type Session = QuinnSession;
+
+    async fn offer(&mut self, path: &Path) -> Result<ShareTicket> {
+        // 先检查一次路径，之后每个接收端重新遍历
+        DirectoryTransfer::new(path, self.chunk_size)?;
+        let (cert, key) = generate_self_signed(vec!["localhost".to_string()])?;
+        let server = ServerEndpointContainer::with_cert(self.bind_addr, cert, key)?;
+        let endpoint = server.get_endpoint().clone();
+        let (path, chunk_size) = (path.to_path_buf(), self.chunk_size);
+        let task = tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let path = path.clone();
+                tokio::spawn(async move {
+                    let res = async {
+                        let connection = incoming.await?;
+                        let mut transfer = DirectoryTransfer::new(&path, chunk_size)?;
+                        transfer.send_dir(&connection).await?;
+                        // 等接收端读完最后的回复后关闭连接
+                        connection.closed().await;
+                        anyhow::Ok(())
+                    };
+                    if let Err(e) = res.await {
+                        warn!("failed to send {}: {e:#}", path.display());
+                    }
+                });
+            }
+        });
+        let ticket = QuicTicket { addr: advertised_addr(server.get_bind_addr()), fingerprint: server.fingerprint() };
+        self.servers.push((server, task));
+        Ok(ShareTicket::Quic(ticket))
+    }
+
+    async fn connect(&self, ticket: ShareTicket) -> Result<QuinnSession> {
+        let ShareTicket::Quic(ticket) = ticket else {
+            anyhow::bail!("this ticket was not created by the quinn backend, use --backend iroh");
+        };
+        let bind_addr = match ticket.addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        // 只信任分享码中的证书
+        let known_hosts = KnownHosts::in_memory().on_first_use(|_, _| false);
+        known_hosts.insert(&host_key(ticket.addr, "localhost")?, &ticket.fingerprint)?;
+        let endpoint = ClientEndpoint::with_known_hosts(bind_addr, known_hosts)?;
+        let connection = endpoint.connect(ticket.addr, "localhost").await?;
+        Ok(QuinnSession { connection, _endpoint: endpoint })
+    }
+
+    async fn fetch(
+        &self,
+        session: QuinnSession,
+        root: &Path,
+        progress: Option<mpsc::UnboundedSender<Progress>>,
+    ) -> Result<PathBuf> {
+        let mut receiver = DirectoryReceiver::new(root);
+        let mut events = receiver.events();
+        let forward = tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                if let Some(progress) = &progress {
+                    progress.send(event).ok();
+                }
+            }
+        });
+        let res = receiver.receive_dir(&session.connection).await;
+        drop(receiver);
+        forward.await?;
+        session.connection.close(0u32.into(), b"done");
+        res
+    }
+
+    async fn shutdown(self) -> Result<()> {
+        for (server, task) in self.servers {
+            task.abort();
+            server.get_endpoint().close(0u32.into(), b"shutdown");
+            server.get_endpoint().wait_idle().await;
+        }
+        Ok(())
+    }
+}
+
+/// 分享码中使用的地址，监听所有地址时使用访问外网时的本机地址
+fn advertised_addr(addr: SocketAddr) -> SocketAddr {
+    if !addr.ip().is_unspecified() {
+        return addr;
+    }
+    // UDP 的 connect 只选择路由，不发送数据
+    let ip = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| {
+            socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9))?;
+            socket.local_addr()
+        })
+        .map(|local| local.ip())
+        .unwrap_or(Ipv4Addr::LOCALHOST.into());
+    SocketAddr::new(ip, addr.port())
+}
+
+/// 使用 quinn 后端执行发送或接收，iroh 后端的命令由默认的流程执行
+pub async fn run_quinn(command: Commands) -> Result<()> {
+    let backend = QuinnBackend::new(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+    match command {
+        Commands::Send(args) => {
+            let path = check_send_args(args)?;
+            send_with(backend, &path).await
+        }
+        Commands::Receive(args) => {
+            let ticket = check_receive_args(args)?;
+            receive_with(backend, ticket, &std::env::current_dir()?).await
+        }
+        command => anyhow::bail!("{command:?} is only supported by the default backend"),
+    }
+}
+
+/// 后端只支持基本的发送，其他选项需要默认的流程
+fn check_send_args(args: SendArgs) -> Result<PathBuf> {
+    anyhow::ensure!(
+        args.watch.is_none()
+            && args.to.is_none()
+            && args.message.is_none()
+            && args.labels.is_empty()
+            && args.expires.is_none()
+            && args.recipients.is_empty()
+            && !args.compress
+            && args.limit_upload.is_none()
+            && args.limit_per_peer.is_none(),
+        "--backend only supports sending a path without other options"
+    );
+    args.path.context("missing path to send")
+}
+
+fn check_receive_args(args: ReceiveArgs) -> Result<ShareTicket> {
+    anyhow::ensure!(
+        args.author.is_none()
+            && !args.require_trusted
+            && args.limit_download.is_none()
+            && args.providers.is_empty()
+            && !args.seed
+            && args.follow.is_none()
+            && args.parallel == DEFAULT_PARALLEL
+            && args.max_entries == DEFAULT_MAX_ENTRIES
+            && args.max_names_size == DEFAULT_MAX_NAMES_SIZE,
+        "--backend only supports receiving a ticket without other options"
+    );
+    Ok(args.code)
+}
+
+/// 分享 path，直到按下 ctrl-c
+pub async fn send_with(mut backend: impl Backend, path: &Path) -> Result<()> {
+    let ticket = backend.offer(path).await?;
+    println!("to get this data, use");
+    println!("transfer receive --code {ticket}");
+    tokio::signal::ctrl_c().await?;
+    println!("shutting down");
+    backend.shutdown().await
+}
+
+/// 下载分享码指向的内容到 root，显示下载进度
+pub async fn receive_with(backend: impl Backend, ticket: ShareTicket, root: &Path) -> Result<()> {
+    let start = Instant::now();
+    let session = backend.connect(ticket).await?;
+    let bar = ProgressBar::new(0);
+    bar.set_style(ProgressStyle::with_template("{bar:40.cyan/blue} {bytes}/{total_bytes} {bytes_per_sec} {msg}")?);
+    let (sender, mut events) = mpsc::unbounded_channel::<Progress>();
+    let show = {
+        let bar = bar.clone();
+        tokio::spawn(async move {
+            while let Some(progress) = events.recv().await {
+                bar.set_length(progress.total);
+                bar.set_position(progress.bytes);
+            }
+        })
+    };
+    let res = backend.fetch(session, root, Some(sender)).await;
+    show.await?;
+    bar.finish_and_clear();
+    let path = res?;
+    println!(
+        "downloaded {}, {}. took {}",
+        path.display(),
+        HumanBytes(bar.length().unwrap_or_default()),
+        HumanDuration(start.elapsed())
+    );
+    backend.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use iroh::RelayMode;
+
+    /// 所有后端都要通过的测试：分享一个目录，在另一个后端实例上下载，检查内容和进度
+    async fn conformance(mut sender: impl Backend, receiver: impl Backend, dir: &Path) {
+        let files = [("share/a.txt", 10), ("share/sub/b.bin", 200_000), ("share/sub/empty", 0)];
+        for (name, size) in files {
+            let path = dir.join("src").join(name);
+            tokio::fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            tokio::fs::write(path, data).await.unwrap();
+        }
+        let output_dir = dir.join("out");
+        tokio::fs::create_dir_all(&output_dir).await.unwrap();
+        assert!(sender.offer(&dir.join("missing")).await.is_err());
+
+        let ticket = sender.offer(&dir.join("src/share")).await.unwrap();
+        let ticket: ShareTicket = ticket.to_string().parse().unwrap();
+        let session = receiver.connect(ticket).await.unwrap();
+        let (progress, mut events) = mpsc::unbounded_channel();
+        let path = receiver.fetch(session, &output_dir, Some(progress)).await.unwrap();
+        assert_eq!(path, output_dir.join("share"));
+        for (name, _) in files {
+            let sent = tokio::fs::read(dir.join("src").join(name)).await.unwrap();
+            assert_eq!(tokio::fs::read(output_dir.join(name)).await.unwrap(), sent, "{name}");
+        }
+        let mut received = Vec::new();
+        while let Some(event) = events.recv().await {
+            received.push(event);
+        }
+        // 下载过程中有进度，最后一次是总大小
+        assert!(received.iter().any(|event| event.bytes < event.total), "{received:?}");
+        let last = received.last().unwrap();
+        assert_eq!((last.bytes, last.total), (200_010, 200_010));
+
+        sender.shutdown().await.unwrap();
+        receiver.shutdown().await.unwrap();
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    async fn iroh_backend(dir: &Path) -> IrohBackend {
+        let endpoint = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await.unwrap();
+        let signer = SecretKey::generate(rand::rngs::OsRng);
+        IrohBackend::new(endpoint, dir.to_path_buf(), signer, TrustStore::default()).await.unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_iroh_backend() {
+        let dir = std::env::temp_dir().join(format!("backend-iroh-test-{}", std::process::id()));
+        let sender = iroh_backend(&dir.join("sender-data")).await;
+        let receiver = iroh_backend(&dir.join("receiver-data")).await;
+        conformance(sender, receiver, &dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_quinn_backend() {
+        let dir = std::env::temp_dir().join(format!("backend-quinn-test-{}", std::process::id()));
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        conformance(QuinnBackend::new(addr), QuinnBackend::new(addr), &dir).await;
+    }
+}
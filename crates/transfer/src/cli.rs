@@ -1,7 +1,13 @@
 use clap::Parser;
 use clap::Subcommand;
-use iroh_blobs::ticket::BlobTicket;
-use std::path::PathBuf;
+use iroh::NodeId;
+use std::{path::PathBuf, time::Duration};
+
+use crate::{
+    collection::{DEFAULT_MAX_ENTRIES, DEFAULT_MAX_NAMES_SIZE},
+    fetch::DEFAULT_PARALLEL,
+    ticket::ShareTicket,
+};
 
 /// parser cli command for send and receive file
 #[derive(Parser, Debug, Clone)]
@@ -10,23 +16,91 @@ pub struct Args {
 
     #[clap(subcommand)]
     pub command: Commands,
+
+    // 守护进程的数据目录，默认 ~/.transfer
+    #[clap(long, global = true, value_name = "DIR")]
+    pub daemon_dir: Option<PathBuf>,
+
+    // 发送和接收使用的传输后端，quinn 后端只支持基本的发送和接收
+    #[clap(long, global = true, value_enum, default_value_t = BackendKind::Iroh)]
+    pub backend: BackendKind,
     
 }
 
+/// 传输后端，见 [`crate::backend::Backend`]
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    // iroh-blobs，支持全部功能
+    Iroh,
+    // 直接使用 quinn，分享码中包含发送端的地址和证书
+    Quinn,
+}
+
 #[derive(Subcommand, Debug, Clone)]
 pub enum Commands {
     // send file
     Send(SendArgs),
     // receive file
     Receive(ReceiveArgs),
+    // show message, labels and files of a share without downloading
+    Inspect(InspectArgs),
+    // wait for other nodes to push files
+    Listen(ListenArgs),
+    // run a long-running node serving many shares
+    Daemon(DaemonArgs),
+    // list shares served by the daemon
+    List,
+    // stop serving a share, its tickets stop working immediately
+    #[clap(visible_alias = "revoke")]
+    Unshare(UnshareArgs),
+    // print the node id used to sign shares
+    Id,
+    // manage nodes trusted to sign shares
+    Trust(TrustArgs),
 }
 
 #[derive(Parser, Debug, Clone)]
 pub struct SendArgs {
 
     // 文件路径
-    #[clap(short, long, value_parser, default_value = None)]
-    pub path: PathBuf,
+    #[clap(short, long, value_parser, default_value = None, required_unless_present = "watch")]
+    pub path: Option<PathBuf>,
+
+    // 持续分享目录，目录变化后重新发布
+    #[clap(long, value_name = "DIR", conflicts_with = "path")]
+    pub watch: Option<PathBuf>,
+
+    // 直接推送给正在 listen 的接收端：节点分享码、节点 id 或设备名
+    #[clap(long, value_name = "PEER", conflicts_with = "watch")]
+    pub to: Option<String>,
+
+    // 附带给接收端的消息，接收端下载前可以看到
+    #[clap(long)]
+    pub message: Option<String>,
+
+    // 附带的标签，格式为 key=value，可以重复使用
+    #[clap(long = "label", value_name = "KEY=VALUE", value_parser = parse_label)]
+    pub labels: Vec<(String, String)>,
+
+    // 分享码的有效期，例如 30m、2h、7d，过期后发送端不再提供
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration, conflicts_with = "watch")]
+    pub expires: Option<Duration>,
+
+    // 加密文件内容，只有这些节点（见 id 命令）可以解密，可以指定多个
+    #[clap(long = "encrypt-to", value_name = "NODE_ID", num_args = 1.., conflicts_with = "watch")]
+    pub recipients: Vec<NodeId>,
+
+    // 使用 zstd 压缩文本等可压缩的文件，接收端自动解压
+    #[clap(long, conflicts_with = "watch")]
+    pub compress: bool,
+
+    // 上传总速率，例如 5MiB/s，所有接收端共享
+    #[clap(long, value_name = "RATE", value_parser = parse_rate)]
+    pub limit_upload: Option<u64>,
+
+    // 每个接收端的上传速率
+    #[clap(long, value_name = "RATE", value_parser = parse_rate)]
+    pub limit_per_peer: Option<u64>,
 
 }
 
@@ -34,5 +108,231 @@ pub struct SendArgs {
 pub struct ReceiveArgs {
     // 文件分享码
     #[clap(short, long, value_parser, default_value = None)]
-    pub code: BlobTicket,
-}
\ No newline at end of file
+    pub code: ShareTicket,
+
+    // 要求分享由该节点签名，否则拒绝下载
+    #[clap(long, value_name = "NODE_ID")]
+    pub author: Option<NodeId>,
+
+    // 只下载信任列表中的节点签名的分享
+    #[clap(long)]
+    pub require_trusted: bool,
+
+    // 下载速率，例如 5MiB/s
+    #[clap(long, value_name = "RATE", value_parser = parse_rate)]
+    pub limit_download: Option<u64>,
+
+    // 同时从其他已有该集合的节点下载：分享码、节点 id 或局域网内的设备名，可以重复使用
+    #[clap(long = "from", value_name = "PEER")]
+    pub providers: Vec<String>,
+
+    // 下载完成后继续提供该集合，之后的接收端可以从这里下载
+    #[clap(long)]
+    pub seed: bool,
+
+    // 使用节点分享码时持续跟随发送端发布的新版本，每个版本下载后替换该目录的内容
+    #[clap(long, value_name = "DIR", conflicts_with_all = ["seed", "providers"])]
+    pub follow: Option<PathBuf>,
+
+    // 每个提供者同时进行的请求数，小文件很多时调大
+    #[clap(long, value_name = "N", default_value_t = DEFAULT_PARALLEL)]
+    pub parallel: usize,
+
+    // 集合最多允许的文件数，超过时拒绝下载
+    #[clap(long, value_name = "N", default_value_t = DEFAULT_MAX_ENTRIES)]
+    pub max_entries: u64,
+
+    // 文件名列表最多允许的字节数，例如 64MiB，超过时拒绝下载
+    #[clap(long, value_name = "SIZE", value_parser = parse_size, default_value_t = DEFAULT_MAX_NAMES_SIZE)]
+    pub max_names_size: u64,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct InspectArgs {
+    // 文件分享码
+    #[clap(short, long, value_parser, default_value = None)]
+    pub code: ShareTicket,
+
+    // 以 JSON 格式输出，方便脚本处理
+    #[clap(long)]
+    pub json: bool,
+
+    // 集合最多允许的文件数，超过时不获取文件列表
+    #[clap(long, value_name = "N", default_value_t = DEFAULT_MAX_ENTRIES)]
+    pub max_entries: u64,
+
+    // 文件名列表最多允许的字节数，超过时不获取文件列表
+    #[clap(long, value_name = "SIZE", value_parser = parse_size, default_value_t = DEFAULT_MAX_NAMES_SIZE)]
+    pub max_names_size: u64,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ListenArgs {
+    // 保存接收文件的目录
+    #[clap(long, value_name = "DIR")]
+    pub inbox: PathBuf,
+
+    // 自动接受的最大大小，例如 500MiB
+    #[clap(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_size: Option<u64>,
+
+    // 自动接受的文件类型，例如 pdf,txt
+    #[clap(long = "allow-ext", value_name = "EXT", value_delimiter = ',')]
+    pub extensions: Vec<String>,
+
+    // 无人值守：不符合规则的推送直接拒绝，不再询问
+    #[clap(long)]
+    pub unattended: bool,
+
+    // 下载速率，例如 5MiB/s
+    #[clap(long, value_name = "RATE", value_parser = parse_rate)]
+    pub limit_download: Option<u64>,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct DaemonArgs {
+    // 上传总速率，例如 5MiB/s，所有分享和接收端共享
+    #[clap(long, value_name = "RATE", value_parser = parse_rate)]
+    pub limit_upload: Option<u64>,
+
+    // 每个接收端的上传速率
+    #[clap(long, value_name = "RATE", value_parser = parse_rate)]
+    pub limit_per_peer: Option<u64>,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct UnshareArgs {
+    // 分享编号，见 list 命令
+    pub id: u64,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct TrustArgs {
+    #[clap(subcommand)]
+    pub command: TrustCommands,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum TrustCommands {
+    // trust shares signed by a node
+    Add {
+        node_id: NodeId,
+        // 展示用的名称
+        #[clap(long)]
+        name: Option<String>,
+    },
+    // stop trusting a node
+    Remove { node_id: NodeId },
+    // list trusted nodes
+    List,
+}
+
+/// 解析 key=value 格式的标签
+pub fn parse_label(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid label {s}, expected key=value"))?;
+    if key.is_empty() {
+        return Err(format!("invalid label {s}, key must not be empty"));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// 解析带单位的时长，例如 90、90s、30m、2h、7d
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {s}"))?;
+    let seconds: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" | "min" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        unit => return Err(format!("unknown duration unit: {unit}")),
+    };
+    number
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration is too long: {s}"))
+}
+
+/// 解析带单位的大小，例如 512、10KB、5MiB、1.5GiB
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size: {s}"))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "kib" => 1 << 10,
+        "m" | "mb" => 1000 * 1000,
+        "mib" => 1 << 20,
+        "g" | "gb" => 1000 * 1000 * 1000,
+        "gib" => 1 << 30,
+        "t" | "tb" => 1000 * 1000 * 1000 * 1000,
+        "tib" => 1 << 40,
+        unit => return Err(format!("unknown size unit: {unit}")),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// 解析每秒字节数，例如 500KB/s、5MiB/s，也可以省略 /s
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    match parse_size(s.strip_suffix("/s").unwrap_or(s))? {
+        0 => Err(format!("rate must be greater than zero: {s}")),
+        rate => Ok(rate),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("10KB"), Ok(10_000));
+        assert_eq!(parse_size("5MiB"), Ok(5 * 1024 * 1024));
+        assert_eq!(parse_size("1.5 GiB"), Ok(3 * 512 * 1024 * 1024));
+        assert!(parse_size("5 parsecs").is_err());
+        assert!(parse_size("MiB").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("5MiB/s"), Ok(5 * 1024 * 1024));
+        assert_eq!(parse_rate("500KB/s"), Ok(500_000));
+        assert_eq!(parse_rate("2048"), Ok(2048));
+        assert!(parse_rate("0/s").is_err());
+        assert!(parse_rate("5MiB/h").is_err());
+    }
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(parse_label("build=1234"), Ok(("build".to_string(), "1234".to_string())));
+        assert_eq!(parse_label("note=a=b"), Ok(("note".to_string(), "a=b".to_string())));
+        assert!(parse_label("build").is_err());
+        assert!(parse_label("=1234").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(2 * 3600)));
+        assert_eq!(parse_duration("7D"), Ok(Duration::from_secs(7 * 86400)));
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("3 weeks").is_err());
+    }
+}
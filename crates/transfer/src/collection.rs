@@ -0,0 +1,435 @@
+//! 存储中的集合：哈希序列和文件名列表边接收边写入存储，之后按顺序从存储读取条目
+//! 条目很多的集合也不会把文件名、哈希和大小全部读入内存
+use std::collections::BTreeSet;
+
+use anyhow::Context;
+use bytes::{Bytes, BytesMut};
+use iroh::endpoint::Connection;
+use iroh_blobs::{
+    format::collection::Collection,
+    get::fsm,
+    protocol::{GetRequest, RangeSpecSeq},
+    store::{
+        bao_tree::{io::fsm::AsyncSliceReader, ChunkNum, ChunkRanges},
+        fs::{Entry, Store as FsStore},
+        BaoBatchWriter, EntryStatus, Map, MapEntry, MapMut,
+    },
+    Hash,
+};
+
+use crate::meta::is_internal;
+
+/// 默认允许的集合条目数，可以通过 --max-entries 调整
+pub const DEFAULT_MAX_ENTRIES: u64 = 10_000_000;
+
+/// 默认允许的文件名列表大小，可以通过 --max-names-size 调整
+pub const DEFAULT_MAX_NAMES_SIZE: u64 = 1 << 30;
+
+/// 文件名的最大长度
+const MAX_NAME_LEN: u64 = 4096;
+
+/// 内部条目（签名、分享信息）只会出现在集合开头的这几个位置
+const INTERNAL_ENTRIES: usize = 2;
+
+/// 获取文件列表时同时获取子节点开头的 16 KiB（一个 chunk 组），不超过这个大小的文件这时就已经完整
+const HEAD_CHUNKS: u64 = 16;
+
+/// 每次从存储读取的字节数
+const READ_BATCH: usize = 64 * 1024;
+
+/// 接收集合时的限制，超过时在接收前返回错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CollectionLimits {
+    /// 最多允许的条目数
+    pub max_entries: u64,
+    /// 文件名列表最多允许的字节数
+    pub max_names_size: u64,
+}
+
+impl Default for CollectionLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: DEFAULT_MAX_ENTRIES,
+            max_names_size: DEFAULT_MAX_NAMES_SIZE,
+        }
+    }
+}
+
+/// 获取文件列表时统计的集合概况
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CollectionSummary {
+    /// 条目数，包括内部条目
+    pub entries: u64,
+    /// 文件数，不包括内部条目
+    pub files: u64,
+    /// 文件的总大小
+    pub size: u64,
+    /// 全部条目的总大小
+    pub total: u64,
+}
+
+/// 按顺序读取存储中的 blob，每次只读取一段
+struct BlobCursor {
+    entry: Entry,
+    /// buf 开头在 blob 中的位置
+    offset: u64,
+    buf: Bytes,
+}
+
+impl BlobCursor {
+    fn new(entry: Entry, offset: u64) -> Self {
+        Self {
+            entry,
+            offset,
+            buf: Bytes::new(),
+        }
+    }
+
+    /// 读取接下来的 len 字节，blob 不够长时返回错误
+    async fn read(&mut self, len: usize) -> anyhow::Result<Bytes> {
+        if self.buf.len() < len {
+            let end = self.offset + self.buf.len() as u64;
+            let more = self.entry.data_reader().read_at(end, len.max(READ_BATCH)).await?;
+            let mut buf = BytesMut::with_capacity(self.buf.len() + more.len());
+            buf.extend_from_slice(&self.buf);
+            buf.extend_from_slice(&more);
+            self.buf = buf.freeze();
+            anyhow::ensure!(self.buf.len() >= len, "unexpected end of blob");
+        }
+        self.offset += len as u64;
+        Ok(self.buf.split_to(len))
+    }
+
+    /// 读取 postcard 编码的变长整数
+    async fn varint(&mut self) -> anyhow::Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read(1).await?[0];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        anyhow::bail!("invalid varint")
+    }
+
+    async fn hash(&mut self) -> anyhow::Result<Hash> {
+        let bytes = self.read(32).await?;
+        Ok(Hash::from_bytes(bytes[..].try_into().expect("32 bytes")))
+    }
+}
+
+/// postcard 编码的变长整数，与集合的文件名列表使用的编码相同
+pub(crate) fn varint(mut value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// 按顺序读取集合的条目
+pub struct Entries {
+    hashes: BlobCursor,
+    names: BlobCursor,
+    remaining: u64,
+}
+
+impl Entries {
+    /// 下一个条目的名称和哈希，读完后返回 None
+    pub async fn next(&mut self) -> anyhow::Result<Option<(String, Hash)>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let len = self.names.varint().await?;
+        anyhow::ensure!(len <= MAX_NAME_LEN, "file name is too long: {len} bytes");
+        let name = self.names.read(len as usize).await?;
+        let name = String::from_utf8(name.to_vec()).context("file name is not valid utf-8")?;
+        Ok(Some((name, self.hashes.hash().await?)))
+    }
+}
+
+/// 存储中的集合，哈希序列和文件名列表需要完整
+/// 条目从存储中按顺序读取，子节点可以是完整的，也可以只有开头和最后一段，见 [`fetch_collection`]
+#[derive(Debug, Clone)]
+pub struct StoredCollection {
+    db: FsStore,
+    hash: Hash,
+    names: Hash,
+    len: u64,
+}
+
+impl StoredCollection {
+    /// 打开存储中的集合
+    pub async fn open(db: FsStore, hash: Hash) -> anyhow::Result<Self> {
+        let entry = db
+            .get(&hash)
+            .await?
+            .filter(|entry| entry.is_complete())
+            .with_context(|| format!("collection {hash} is not in the store"))?;
+        let size = entry.size().value();
+        anyhow::ensure!(size % 32 == 0 && size > 0, "{hash} is not a collection");
+        let names = BlobCursor::new(entry, 0).hash().await?;
+        Ok(Self {
+            db,
+            hash,
+            names,
+            len: size / 32 - 1,
+        })
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    pub fn db(&self) -> &FsStore {
+        &self.db
+    }
+
+    /// 条目数，包括内部条目
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 从头开始按顺序读取条目
+    pub async fn entries(&self) -> anyhow::Result<Entries> {
+        let hashes = self.db.get(&self.hash).await?.context("hash sequence not found")?;
+        let names = self
+            .db
+            .get(&self.names)
+            .await?
+            .filter(|entry| entry.is_complete())
+            .context("file names of the collection not found")?;
+        let mut names = BlobCursor::new(names, 0);
+        let header = names.read(Collection::HEADER.len()).await?;
+        anyhow::ensure!(&header[..] == Collection::HEADER, "invalid collection header");
+        let count = names.varint().await?;
+        anyhow::ensure!(count == self.len, "collection has {} entries but {count} file names", self.len);
+        Ok(Entries {
+            // 哈希序列的第一个是文件名列表
+            hashes: BlobCursor::new(hashes, 32),
+            names,
+            remaining: self.len,
+        })
+    }
+
+    /// 集合涉及的全部哈希：哈希序列、文件名列表和全部条目
+    pub async fn hashes(&self) -> anyhow::Result<BTreeSet<Hash>> {
+        let mut hashes = BTreeSet::from([self.hash, self.names]);
+        let mut entries = self.entries().await?;
+        while let Some((_, hash)) = entries.next().await? {
+            hashes.insert(hash);
+        }
+        Ok(hashes)
+    }
+
+    /// 查找内部条目，内部条目只会在集合的开头
+    pub async fn find_internal(&self, name: &str) -> anyhow::Result<Option<Hash>> {
+        let mut entries = self.entries().await?;
+        for _ in 0..INTERNAL_ENTRIES {
+            match entries.next().await? {
+                Some((found, hash)) if found == name => return Ok(Some(hash)),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// 子节点的大小，子节点需要完整或者已经获取了最后一段
+    pub async fn size_of(&self, hash: &Hash) -> anyhow::Result<u64> {
+        let entry = self
+            .db
+            .get(hash)
+            .await?
+            .with_context(|| format!("size of {hash} is unknown"))?;
+        Ok(entry.size().value())
+    }
+}
+
+/// 把响应中的 blob 边接收边写入 db，写完后标记为完整
+pub(crate) async fn write_blob(db: &FsStore, content: fsm::AtBlobContent, size: u64) -> anyhow::Result<fsm::AtEndBlob> {
+    let entry = db.get_or_create(content.hash(), size).await?;
+    let mut writer = entry.writer();
+    let end = content.write_all_batch(&mut writer).await?;
+    writer.sync().await?;
+    drop(writer);
+    db.insert_complete(entry).await?;
+    Ok(end)
+}
+
+/// 把子节点的开头和最后一段写入 db，之后可以从 db 读取它的大小
+/// 不超过 HEAD_CHUNKS 的子节点这时已经完整，校验后标记为完整
+async fn write_head_and_tail(db: &FsStore, content: fsm::AtBlobContent, size: u64) -> anyhow::Result<fsm::AtEndBlob> {
+    let hash = content.hash();
+    let entry = db.get_or_create(hash, size).await?;
+    let mut writer = entry.writer();
+    let end = content.write_all_batch(&mut writer).await?;
+    writer.sync().await?;
+    drop(writer);
+    if size <= HEAD_CHUNKS * 1024 {
+        let data = entry.data_reader().read_at(0, size as usize).await?;
+        if data.len() as u64 == size && Hash::new(&data) == hash {
+            db.insert_complete(entry).await?;
+        }
+    }
+    Ok(end)
+}
+
+/// 读取 db 中完整的 blob，没有时从连接上获取并写入 db，之后的下载不再重复获取
+pub(crate) async fn load_blob(connection: &Connection, db: &FsStore, hash: Hash, max_size: u64) -> anyhow::Result<Bytes> {
+    if db.entry_status(&hash).await? != EntryStatus::Complete {
+        let request = GetRequest::single(hash);
+        let connected = fsm::start(connection.clone(), request).next().await?;
+        let fsm::ConnectedNext::StartRoot(start) = connected.next().await? else {
+            anyhow::bail!("request does not include the root blob");
+        };
+        let (content, size) = start.next().next().await?;
+        anyhow::ensure!(size <= max_size, "blob {hash} is too large: {size} bytes");
+        let closing = match write_blob(db, content, size).await?.next() {
+            fsm::EndBlobNext::MoreChildren(more) => more.finish(),
+            fsm::EndBlobNext::Closing(closing) => closing,
+        };
+        closing.next().await?;
+    }
+    read_blob(db, hash, max_size).await
+}
+
+/// 从存储读取完整的 blob，超过 max_size 时返回错误
+pub(crate) async fn read_blob(db: &FsStore, hash: Hash, max_size: u64) -> anyhow::Result<Bytes> {
+    let entry = db.get(&hash).await?.context("blob not found")?;
+    let size = entry.size().value();
+    anyhow::ensure!(size <= max_size, "blob {hash} is too large: {size} bytes");
+    Ok(entry.data_reader().read_at(0, size as usize).await?)
+}
+
+/// 获取集合的哈希序列和文件名列表写入 db，同时获取每个子节点的开头和最后一段，不下载其余内容
+/// 最后一段验证了子节点的大小并写入 db，之后从 db 读取；不超过 16 KiB 的小文件这时已经下载完成
+/// 条目数或文件名列表超过 limits 时在接收前返回错误
+pub async fn fetch_collection(
+    connection: &Connection,
+    db: &FsStore,
+    hash: Hash,
+    limits: CollectionLimits,
+) -> anyhow::Result<(StoredCollection, CollectionSummary)> {
+    let request = GetRequest::new(
+        hash,
+        RangeSpecSeq::from_ranges_infinite([
+            ChunkRanges::all(),
+            ChunkRanges::all(),
+            &ChunkRanges::from(ChunkNum(0)..ChunkNum(HEAD_CHUNKS)) | &ChunkRanges::from(ChunkNum(u64::MAX)..),
+        ]),
+    );
+    let connected = fsm::start(connection.clone(), request).next().await?;
+    let fsm::ConnectedNext::StartRoot(start) = connected.next().await? else {
+        anyhow::bail!("request does not include the root blob");
+    };
+    let (content, size) = start.next().next().await?;
+    anyhow::ensure!(size % 32 == 0 && size > 0, "{hash} is not a collection");
+    // 第一个子节点是文件名列表
+    let entries = size / 32 - 1;
+    anyhow::ensure!(
+        entries <= limits.max_entries,
+        "collection has {entries} entries, more than the limit of {}, use --max-entries to raise it",
+        limits.max_entries
+    );
+    let mut next = write_blob(db, content, size).await?.next();
+    let collection = StoredCollection::open(db.clone(), hash).await?;
+    let mut children = None;
+    let mut summary = CollectionSummary {
+        entries,
+        ..Default::default()
+    };
+    let closing = loop {
+        let child = match next {
+            fsm::EndBlobNext::MoreChildren(child) => child,
+            fsm::EndBlobNext::Closing(closing) => break closing,
+        };
+        let Some(children) = &mut children else {
+            // 文件名列表写入 db 后才能逐个读取条目
+            let (content, size) = child.next(collection.names).next().await?;
+            anyhow::ensure!(
+                size <= limits.max_names_size,
+                "file names of the collection take {size} bytes, more than the limit of {}, use --max-names-size to raise it",
+                limits.max_names_size
+            );
+            anyhow::ensure!(
+                size <= 64 + entries * (MAX_NAME_LEN + 2),
+                "file names of the collection are too large: {size} bytes for {entries} entries"
+            );
+            next = write_blob(db, content, size).await?.next();
+            children = Some(collection.entries().await?);
+            continue;
+        };
+        let Some((name, child_hash)) = children.next().await? else {
+            break child.finish();
+        };
+        let (content, size) = child.next(child_hash).next().await?;
+        // 之前已经下载完成的只验证大小
+        let end = match db.entry_status(&child_hash).await? {
+            EntryStatus::Complete => content.drain().await?,
+            _ => write_head_and_tail(db, content, size).await?,
+        };
+        summary.total += size;
+        if !is_internal(&name) {
+            summary.files += 1;
+            summary.size += size;
+        }
+        next = end.next();
+    };
+    closing.next().await?;
+    let received = children.map(|children| entries - children.remaining).unwrap_or_default();
+    anyhow::ensure!(received == entries, "provider did not send the sizes of all entries");
+    Ok((collection, summary))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+
+    use super::*;
+
+    /// 测试用的临时存储目录，调用方用完后删除
+    pub(crate) async fn temp_store(name: &str) -> anyhow::Result<(FsStore, std::path::PathBuf)> {
+        let suffix = rand::random::<u64>();
+        let dir = std::env::temp_dir().join(format!("transfer-{name}-{}-{suffix:x}", std::process::id()));
+        Ok((FsStore::load(&dir).await?, dir))
+    }
+
+    #[test]
+    fn test_varint() {
+        for value in [0, 1, 127, 128, 300, 16384, u32::MAX as u64, u64::MAX] {
+            assert_eq!(varint(value), postcard::to_stdvec(&(value as usize)).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stored_collection() -> anyhow::Result<()> {
+        let (db, dir) = temp_store("stored").await?;
+        let collection = (0..3000)
+            .map(|index| (format!("dir/{index}-{}", "x".repeat(index % 50)), Hash::new(index.to_string())))
+            .collect::<Collection>();
+        let tag = collection.clone().store(&db).await?;
+        let stored = StoredCollection::open(db.clone(), *tag.hash()).await?;
+        assert_eq!(stored.len(), 3000);
+        let mut entries = stored.entries().await?;
+        for expected in collection.iter() {
+            assert_eq!(entries.next().await?.as_ref(), Some(expected));
+        }
+        assert_eq!(entries.next().await?, None);
+        assert_eq!(stored.find_internal("dir/0-").await?, Some(Hash::new("0")));
+        assert_eq!(stored.find_internal("dir/5-xxxxx").await?, None);
+        drop((tag, stored, db));
+        tokio::fs::remove_dir_all(dir).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+/// 压缩级别，兼顾速度和压缩率
+const LEVEL: i32 = 3;
+
+/// 已经压缩过的格式，再压缩几乎没有收益
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg", "lz4", "mkv", "mov",
+    "mp3", "mp4", "ogg", "pdf", "png", "pptx", "rar", "tgz", "webm", "webp", "whl", "woff2", "xlsx", "xz", "zip",
+    "zst",
+];
+
+/// 根据扩展名判断文件是否值得压缩
+pub fn is_compressible(name: &str) -> bool {
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    !matches!(extension, Some(extension) if COMPRESSED_EXTENSIONS.contains(&extension.as_str()))
+}
+
+/// 使用 zstd 压缩 src 写入 dst，返回压缩后的大小
+/// 节省不到十分之一时删除 dst 并返回 None，直接发送原文件
+/// 阻塞执行，需要在 spawn_blocking 中调用
+pub fn compress_file(src: &Path, dst: &Path) -> anyhow::Result<Option<u64>> {
+    let size = std::fs::metadata(src)?.len();
+    let mut writer = BufWriter::new(File::create(dst)?);
+    zstd::stream::copy_encode(BufReader::new(File::open(src)?), &mut writer, LEVEL)?;
+    writer.flush()?;
+    let compressed = std::fs::metadata(dst)?.len();
+    if compressed >= size - size / 10 {
+        std::fs::remove_file(dst)?;
+        return Ok(None);
+    }
+    Ok(Some(compressed))
+}
+
+/// 解压 src 写入 dst，失败时删除 dst
+/// 阻塞执行，需要在 spawn_blocking 中调用
+pub fn decompress_file(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    let res = File::create(dst).map_err(anyhow::Error::from).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        zstd::stream::copy_decode(BufReader::new(File::open(src)?), &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    });
+    if res.is_err() {
+        std::fs::remove_file(dst).ok();
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_is_compressible() {
+        assert!(is_compressible("logs/app.log"));
+        assert!(is_compressible("src/main.rs"));
+        assert!(is_compressible("Makefile"));
+        assert!(!is_compressible("photos/IMG_0001.JPG"));
+        assert!(!is_compressible("release.tar.gz"));
+    }
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("transfer-compress-test-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (plain, packed, unpacked) = (dir.join("plain"), dir.join("packed"), dir.join("unpacked"));
+
+        let text = (0..10_000).map(|i| format!("line {i}: everything is fine\n")).collect::<String>();
+        std::fs::write(&plain, &text).unwrap();
+        let compressed = compress_file(&plain, &packed).unwrap().unwrap();
+        assert!(compressed < text.len() as u64 / 10);
+        decompress_file(&packed, &unpacked).unwrap();
+        assert_eq!(std::fs::read_to_string(&unpacked).unwrap(), text);
+
+        // 随机数据压缩没有收益，不保留压缩结果
+        let noise = (0..100_000).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+        std::fs::write(&plain, &noise).unwrap();
+        assert_eq!(compress_file(&plain, &packed).unwrap(), None);
+        assert!(!packed.exists());
+
+        // 不是 zstd 格式时解压失败
+        assert!(decompress_file(&plain, &unpacked).is_err());
+        assert!(!unpacked.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,981 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use data_encoding::HEXLOWER;
+use indicatif::{HumanBytes, HumanDuration};
+use iroh::{protocol::Router, Endpoint, NodeAddr, NodeId, SecretKey};
+use iroh_blobs::{
+    format::collection::Collection,
+    get::Stats,
+    net_protocol::Blobs,
+    store::{fs::Store as FsStore, EntryStatus, GcConfig, MapMut, Store},
+    ticket::BlobTicket,
+    BlobFormat, Hash, HashAndFormat, Tag,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+use tracing::{info, warn};
+
+use crate::{
+    access::{collection_hashes, AccessList, AccessProtocol},
+    cli::{Commands, DaemonArgs},
+    fetch::{fetch_parallel, fetch_raw, FetchOptions, DEFAULT_PARALLEL},
+    limit::UploadLimits,
+    collection::{fetch_collection, CollectionLimits, StoredCollection, DEFAULT_MAX_ENTRIES, DEFAULT_MAX_NAMES_SIZE},
+    meta::{is_internal, read_meta, ShareMeta},
+    signature::{verify_collection, Authorship, SignaturePolicy},
+    ticket::{unix_now, RevocableTicket, ShareTicket},
+    transfer::{create_endpoint_with_key, export, import, resolve_ticket},
+    trust::TrustStore,
+};
+
+/// 守护进程写入的控制文件，记录控制端口和访问令牌
+const CONTROL_FILE: &str = "daemon.json";
+/// 持久化的分享列表
+const SHARES_FILE: &str = "shares.json";
+/// 节点私钥，保证重启后节点 id 不变
+const SECRET_KEY_FILE: &str = "secret_key";
+/// 回收不再被分享的数据的周期
+const GC_PERIOD: Duration = Duration::from_secs(60);
+
+/// 控制请求
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Request {
+    /// 分享文件或目录
+    Send {
+        path: PathBuf,
+        #[serde(default)]
+        meta: ShareMeta,
+        /// 过期时间（unix 秒）
+        #[serde(default)]
+        expires: Option<u64>,
+        /// 压缩可压缩的文件
+        #[serde(default)]
+        compress: bool,
+    },
+    /// 下载分享码对应的数据到目标目录，下载前按要求检查签名
+    Receive {
+        ticket: String,
+        target: PathBuf,
+        /// 要求由指定节点签名
+        #[serde(default)]
+        author: Option<NodeId>,
+        /// 要求签名的节点在信任列表中
+        #[serde(default)]
+        require_trusted: bool,
+    },
+    /// 列出正在分享的内容
+    List,
+    /// 停止分享
+    Unshare { id: u64 },
+}
+
+/// 控制响应
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", content = "value", rename_all = "snake_case")]
+pub enum Response {
+    Shared(ShareInfo),
+    Received {
+        files: usize,
+        size: u64,
+        elapsed: Duration,
+        /// 分享附带的说明和标签，由客户端展示
+        #[serde(default)]
+        meta: ShareMeta,
+        /// 签名状态，接收自己的分享时不验证
+        #[serde(default)]
+        authorship: Option<Authorship>,
+    },
+    Shares(Vec<ShareInfo>),
+    Unshared(ShareInfo),
+    Error(String),
+}
+
+/// 一次分享的信息
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareInfo {
+    pub id: u64,
+    pub path: PathBuf,
+    pub hash: Hash,
+    pub files: usize,
+    pub size: u64,
+    pub ticket: String,
+    /// 过期时间（unix 秒），过期后自动停止分享
+    #[serde(default)]
+    pub expires: Option<u64>,
+}
+
+/// 带访问令牌的请求
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    token: String,
+    #[serde(flatten)]
+    request: Request,
+}
+
+/// 控制文件内容
+#[derive(Debug, Serialize, Deserialize)]
+struct ControlInfo {
+    addr: std::net::SocketAddr,
+    token: String,
+}
+
+/// 获取守护进程的数据目录
+pub fn daemon_dir(dir: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    match dir {
+        Some(dir) => Ok(dir),
+        None => Ok(dirs::home_dir()
+            .context("can not find home directory, use --daemon-dir")?
+            .join(".transfer")),
+    }
+}
+
+/// 读取数据目录中的节点私钥，没有时生成
+/// 守护进程使用它作为节点身份，单独运行的命令使用它签名分享
+pub async fn load_identity(dir: &Path) -> anyhow::Result<SecretKey> {
+    tokio::fs::create_dir_all(dir).await?;
+    load_secret_key(&dir.join(SECRET_KEY_FILE)).await
+}
+
+/// 读取或生成节点私钥
+async fn load_secret_key(path: &Path) -> anyhow::Result<SecretKey> {
+    if path.exists() {
+        let text = tokio::fs::read_to_string(path).await?;
+        return SecretKey::from_str(text.trim()).context("invalid secret key file");
+    }
+    let secret_key = SecretKey::generate(rand::rngs::OsRng);
+    write_private(path, secret_key.to_string().as_bytes()).await?;
+    Ok(secret_key)
+}
+
+/// 写入只有当前用户可读的文件，创建时就限制权限
+/// 文件已经存在时先收紧权限再写入
+async fn write_private(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    file.write_all(contents).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+/// 距离过期时间（unix 秒）还有多久
+fn format_expiry(expires: u64) -> HumanDuration {
+    HumanDuration(Duration::from_secs(expires.saturating_sub(unix_now())))
+}
+
+fn share_tag(id: u64) -> Tag {
+    Tag::from(format!("share-{id}"))
+}
+
+/// 持久化的分享列表文件
+/// 旧版本只保存分享列表，读取时根据已有的编号推算下一个编号
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum SharesFile {
+    Table { next_id: u64, shares: Vec<ShareInfo> },
+    List(Vec<ShareInfo>),
+}
+
+/// 分享列表
+/// 分享编号单调递增并且随列表保存，撤销后也不会分配给新的分享，旧的分享码不会指向别的内容
+#[derive(Debug)]
+struct ShareTable {
+    next_id: u64,
+    shares: BTreeMap<u64, ShareInfo>,
+}
+
+impl Default for ShareTable {
+    fn default() -> Self {
+        Self {
+            next_id: 1,
+            shares: BTreeMap::new(),
+        }
+    }
+}
+
+impl ShareTable {
+    /// 读取分享列表，文件不存在时为空
+    async fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let (next_id, shares) = match serde_json::from_slice(&tokio::fs::read(path).await?)? {
+            SharesFile::Table { next_id, shares } => (next_id, shares),
+            SharesFile::List(shares) => (1, shares),
+        };
+        let shares = shares.into_iter().map(|share| (share.id, share)).collect::<BTreeMap<_, _>>();
+        let next_id = shares.keys().next_back().map_or(next_id, |id| next_id.max(id + 1));
+        Ok(Self { next_id, shares })
+    }
+
+    /// 写入临时文件后替换，写入中途退出时不会损坏原来的列表
+    async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let file = SharesFile::Table {
+            next_id: self.next_id,
+            shares: self.shares.values().cloned().collect(),
+        };
+        let temp = path.with_extension("json.tmp");
+        tokio::fs::write(&temp, serde_json::to_vec_pretty(&file)?).await?;
+        tokio::fs::rename(&temp, path).await?;
+        Ok(())
+    }
+
+    /// 分配新的分享编号
+    fn allocate(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn insert(&mut self, info: ShareInfo) {
+        self.shares.insert(info.id, info);
+    }
+
+    fn remove(&mut self, id: u64) -> Option<ShareInfo> {
+        self.shares.remove(&id)
+    }
+
+    fn values(&self) -> impl Iterator<Item = &ShareInfo> {
+        self.shares.values()
+    }
+
+    fn len(&self) -> usize {
+        self.shares.len()
+    }
+}
+
+/// 守护进程状态
+struct Daemon {
+    dir: PathBuf,
+    router: Router,
+    blobs: Blobs<FsStore>,
+    access: AccessList,
+    shares: Mutex<ShareTable>,
+}
+
+impl Daemon {
+    /// 使用 endpoint 提供数据目录中的存储，恢复之前的分享，每隔 gc_period 回收不再需要的数据
+    async fn load(dir: PathBuf, endpoint: Endpoint, limits: UploadLimits, gc_period: Duration) -> anyhow::Result<Self> {
+        let blobs = Blobs::persistent(dir.join("blobs")).await?.build(&endpoint);
+        blobs.start_gc(GcConfig {
+            period: gc_period,
+            done_callback: None,
+        })?;
+        let access = AccessList::default();
+        let router = Router::builder(endpoint)
+            .accept(
+                iroh_blobs::ALPN,
+                AccessProtocol::new(blobs.clone(), access.clone()).with_limits(limits),
+            )
+            .spawn();
+
+        let mut shares = ShareTable::load(&dir.join(SHARES_FILE)).await?;
+        let mut broken = Vec::new();
+        for share in shares.values() {
+            // 某个分享的数据缺失或损坏时只停止这一个分享，其他分享照常提供
+            match Collection::load_db(blobs.store(), &share.hash).await {
+                Ok(collection) => access.add(share.id, collection_hashes(&collection), share.expires),
+                Err(e) => {
+                    warn!("dropping share {} {}: {e:#}", share.id, share.path.display());
+                    broken.push(share.id);
+                }
+            }
+        }
+        for id in broken {
+            shares.remove(id);
+        }
+        Ok(Self {
+            dir,
+            router,
+            blobs,
+            access,
+            shares: Mutex::new(shares),
+        })
+    }
+
+    async fn handle(&self, request: Request) -> anyhow::Result<Response> {
+        match request {
+            Request::Send { path, meta, expires, compress } => {
+                self.share(path, &meta, expires, compress).await.map(Response::Shared)
+            }
+            Request::Receive { ticket, target, author, require_trusted } => {
+                let policy = SignaturePolicy {
+                    author,
+                    require_trusted,
+                    trust: TrustStore::load(&self.dir).await?,
+                };
+                self.receive(&ticket, &target, &policy).await
+            }
+            Request::List => {
+                let shares = self.shares.lock().await;
+                Ok(Response::Shares(shares.values().cloned().collect()))
+            }
+            Request::Unshare { id } => self.unshare(id).await.map(Response::Unshared),
+        }
+    }
+
+    async fn share(&self, path: PathBuf, meta: &ShareMeta, expires: Option<u64>, compress: bool) -> anyhow::Result<ShareInfo> {
+        let db = self.blobs.store();
+        let signer = self.router.endpoint().secret_key();
+        let (temp_tag, size, collection) = import(path.clone(), db.clone(), meta, signer, compress).await?;
+        let hash = *temp_tag.hash();
+        let addr = self.router.endpoint().node_addr().await?;
+
+        let mut shares = self.shares.lock().await;
+        let id = shares.allocate();
+        // 分享码带上编号，按编号撤销
+        let ticket = RevocableTicket {
+            blob: BlobTicket::new(addr, hash, BlobFormat::HashSeq)?,
+            id,
+            expires,
+        };
+        self.access.add(id, collection_hashes(&collection), expires);
+        // 使用持久化的 tag 保护数据，重启后继续分享
+        db.set_tag(share_tag(id), HashAndFormat::hash_seq(hash)).await?;
+        drop(temp_tag);
+        let info = ShareInfo {
+            id,
+            path,
+            hash,
+            files: collection.iter().filter(|(name, _)| !is_internal(name)).count(),
+            size,
+            ticket: ticket.to_string(),
+            expires,
+        };
+        shares.insert(info.clone());
+        shares.save(&self.dir.join(SHARES_FILE)).await?;
+        info!("share {} {} hash: {}", id, info.path.display(), hash);
+        Ok(info)
+    }
+
+    async fn unshare(&self, id: u64) -> anyhow::Result<ShareInfo> {
+        let mut shares = self.shares.lock().await;
+        let info = shares.remove(id).with_context(|| format!("no share with id {id}"))?;
+        // 立即停止提供这次分享的全部数据，不再被引用的数据会在下一次 gc 时回收
+        self.access.revoke(id);
+        self.blobs.store().delete_tag(share_tag(id)).await?;
+        // 没有其他分享相同的集合时删除集合本身，重启后也不会再提供
+        if shares.values().all(|share| share.hash != info.hash) {
+            self.blobs.store().delete(vec![info.hash]).await?;
+        }
+        shares.save(&self.dir.join(SHARES_FILE)).await?;
+        info!("unshare {} {}", id, info.path.display());
+        Ok(info)
+    }
+
+    async fn receive(&self, ticket: &str, target: &Path, policy: &SignaturePolicy) -> anyhow::Result<Response> {
+        let ticket = ShareTicket::from_str(ticket)?;
+        let endpoint = self.router.endpoint();
+        let (addr, hash_and_format) = resolve_ticket(endpoint, ticket).await?;
+        let db = self.blobs.store();
+        // 导出完成之前 gc 不能回收下载的数据
+        let temp_tag = db.temp_tag(hash_and_format);
+        // 分享码指向自己时数据已经在存储中
+        let (stats, authorship) = match addr.node_id == endpoint.node_id() {
+            true => (Stats::default(), None),
+            // 下载不是 Send 的，需要在 blobs 的本地线程池中运行
+            false => {
+                let endpoint = endpoint.clone();
+                let db = db.clone();
+                let access = self.access.clone();
+                let policy = policy.clone();
+                let (stats, authorship) = self
+                    .blobs
+                    .rt()
+                    .spawn(move || async move { download(&endpoint, addr, &db, hash_and_format, &access, &policy).await })
+                    .await??;
+                (stats, Some(authorship))
+            }
+        };
+        let collection = StoredCollection::open(db.clone(), hash_and_format.hash).await?;
+        let mut files = 0;
+        let mut entries = collection.entries().await?;
+        while let Some((name, _)) = entries.next().await? {
+            if !is_internal(&name) {
+                files += 1;
+            }
+        }
+        // 加密的分享使用守护进程的节点私钥解密，压缩的条目解压后导出
+        let meta = read_meta(&collection).await?.unwrap_or_default();
+        let identity = endpoint.secret_key();
+        anyhow::ensure!(
+            meta.recipients.is_empty() || meta.recipients.contains(&identity.public()),
+            "share is encrypted for other nodes, this node is {}",
+            identity.public()
+        );
+        export(&collection, target, &meta, identity).await?;
+        drop(temp_tag);
+        Ok(Response::Received {
+            files,
+            size: stats.bytes_read,
+            elapsed: stats.elapsed,
+            meta,
+            authorship,
+        })
+    }
+
+    /// 停止分享已经过期的内容，删除不再需要的访问规则
+    async fn remove_expired(&self) -> anyhow::Result<()> {
+        let now = unix_now();
+        let expired = self
+            .shares
+            .lock()
+            .await
+            .values()
+            .filter(|share| share.expires.is_some_and(|expires| expires <= now))
+            .map(|share| share.id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            let info = self.unshare(id).await?;
+            info!("share {} {} expired", id, info.path.display());
+        }
+        self.access.remove_expired();
+        // gc 删除数据后不再需要拒绝提供这些哈希
+        let mut deleted = Vec::new();
+        for hash in self.access.orphans() {
+            if self.blobs.store().entry_status(&hash).await? == EntryStatus::NotFound {
+                deleted.push(hash);
+            }
+        }
+        self.access.forget(deleted);
+        Ok(())
+    }
+}
+
+/// 从 addr 下载数据到 db，下载文件前验证签名，返回签名状态
+/// 下载的数据不属于任何分享，不向其他节点提供
+async fn download(
+    endpoint: &Endpoint,
+    addr: NodeAddr,
+    db: &FsStore,
+    hash_and_format: HashAndFormat,
+    access: &AccessList,
+    policy: &SignaturePolicy,
+) -> anyhow::Result<(Stats, Authorship)> {
+    let connection = endpoint.connect(addr.clone(), iroh_blobs::protocol::ALPN).await?;
+    match hash_and_format.format {
+        BlobFormat::HashSeq => {
+            let limits = CollectionLimits::default();
+            let (collection, summary) = fetch_collection(&connection, db, hash_and_format.hash, limits).await?;
+            access.hide(collection.hashes().await?);
+            let authorship = verify_collection(&connection, &collection).await?;
+            policy.verify(&authorship)?;
+            connection.close(0u32.into(), b"done");
+            // 子节点只有开头和最后一段，与单独运行的接收一样按缺少的范围下载
+            let stats = fetch_parallel(endpoint, &[addr], &collection, &summary, FetchOptions::default()).await?;
+            Ok((stats, authorship))
+        }
+        BlobFormat::Raw => {
+            policy.verify(&Authorship::Unsigned)?;
+            access.hide([hash_and_format.hash]);
+            let stats = fetch_raw(&connection, db, hash_and_format.hash, None).await?;
+            connection.close(0u32.into(), b"done");
+            Ok((stats, Authorship::Unsigned))
+        }
+    }
+}
+
+/// 处理一个控制连接，每行一个 JSON 请求
+async fn handle_client(daemon: Arc<Daemon>, token: Arc<String>, stream: TcpStream) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<Envelope>(&line) {
+            Ok(envelope) if envelope.token == *token => daemon
+                .handle(envelope.request)
+                .await
+                .unwrap_or_else(|e| Response::Error(format!("{e:#}"))),
+            Ok(_) => Response::Error("invalid token".to_string()),
+            Err(e) => Response::Error(format!("invalid request: {e}")),
+        };
+        let mut bytes = serde_json::to_vec(&response)?;
+        bytes.push(b'\n');
+        writer.write_all(&bytes).await?;
+    }
+    Ok(())
+}
+
+/// 运行守护进程
+/// 使用同一个节点和存储同时提供多个分享，通过本地控制端口接收命令
+pub async fn run_daemon(dir: Option<PathBuf>, args: DaemonArgs) -> anyhow::Result<()> {
+    let dir = daemon_dir(dir)?;
+    tokio::fs::create_dir_all(&dir).await?;
+    let control_file = dir.join(CONTROL_FILE);
+    if DaemonClient::connect(Some(dir.clone())).await?.is_some() {
+        anyhow::bail!("daemon is already running for {}", dir.display());
+    }
+
+    let secret_key = load_identity(&dir).await?;
+    let endpoint = create_endpoint_with_key(secret_key).await?;
+    let limits = UploadLimits::new(args.limit_upload, args.limit_per_peer);
+    let daemon = Arc::new(Daemon::load(dir.clone(), endpoint, limits, GC_PERIOD).await?);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let token = Arc::new(HEXLOWER.encode(&rand::thread_rng().gen::<[u8; 16]>()));
+    let control = ControlInfo {
+        addr: listener.local_addr()?,
+        token: token.to_string(),
+    };
+    write_private(&control_file, &serde_json::to_vec(&control)?).await?;
+
+    println!("daemon running, node id: {}", daemon.router.endpoint().node_id());
+    println!("data directory: {}", dir.display());
+    println!("serving {} share(s)", daemon.shares.lock().await.len());
+
+    let mut expire_timer = tokio::time::interval(GC_PERIOD);
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = expire_timer.tick() => {
+                if let Err(e) = daemon.remove_expired().await {
+                    warn!("failed to remove expired shares: {e:?}");
+                }
+            }
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let daemon = daemon.clone();
+                let token = token.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_client(daemon, token, stream).await {
+                        warn!("control connection failed: {e:?}");
+                    }
+                });
+            }
+        }
+    }
+
+    tokio::fs::remove_file(&control_file).await.ok();
+    tokio::time::timeout(Duration::from_secs(2), daemon.router.shutdown()).await??;
+    println!("shutting down");
+    Ok(())
+}
+
+/// 守护进程客户端
+pub struct DaemonClient {
+    /// 数据目录，展示签名状态时读取信任列表
+    dir: PathBuf,
+    token: String,
+    lines: tokio::io::Lines<BufReader<tokio::net::tcp::OwnedReadHalf>>,
+    writer: tokio::net::tcp::OwnedWriteHalf,
+}
+
+impl DaemonClient {
+    /// 连接正在运行的守护进程，没有运行时返回 None
+    /// 找不到数据目录（例如没有 HOME）时也不会有守护进程
+    pub async fn connect(dir: Option<PathBuf>) -> anyhow::Result<Option<Self>> {
+        let Ok(dir) = daemon_dir(dir) else {
+            return Ok(None);
+        };
+        let control_file = dir.join(CONTROL_FILE);
+        let Ok(bytes) = tokio::fs::read(&control_file).await else {
+            return Ok(None);
+        };
+        let control: ControlInfo = serde_json::from_slice(&bytes)?;
+        // 控制文件可能是上次异常退出时留下的
+        let Ok(stream) = TcpStream::connect(control.addr).await else {
+            return Ok(None);
+        };
+        let (reader, writer) = stream.into_split();
+        Ok(Some(Self {
+            dir,
+            token: control.token,
+            lines: BufReader::new(reader).lines(),
+            writer,
+        }))
+    }
+
+    /// 守护进程可以执行的命令
+    pub fn supports(command: &Commands) -> bool {
+        match command {
+            // 守护进程使用启动时设置的上传限速，单独限速时直接执行
+            Commands::Send(args) => {
+                args.watch.is_none()
+                    && args.to.is_none()
+                    && args.limit_upload.is_none()
+                    && args.limit_per_peer.is_none()
+            }
+            // 有限速、多个提供者、并行设置、条目数限制、继续提供或者跟随新版本时直接执行
+            Commands::Receive(args) => {
+                args.limit_download.is_none()
+                    && args.providers.is_empty()
+                    && !args.seed
+                    && args.follow.is_none()
+                    && args.parallel == DEFAULT_PARALLEL
+                    && args.max_entries == DEFAULT_MAX_ENTRIES
+                    && args.max_names_size == DEFAULT_MAX_NAMES_SIZE
+            }
+            Commands::List | Commands::Unshare(_) => true,
+            Commands::Inspect(_) | Commands::Listen(_) | Commands::Daemon(_) | Commands::Id | Commands::Trust(_) => false,
+        }
+    }
+
+    /// 发送请求并等待响应
+    pub async fn call(&mut self, request: Request) -> anyhow::Result<Response> {
+        let envelope = Envelope {
+            token: self.token.clone(),
+            request,
+        };
+        let mut bytes = serde_json::to_vec(&envelope)?;
+        bytes.push(b'\n');
+        self.writer.write_all(&bytes).await?;
+        let line = self
+            .lines
+            .next_line()
+            .await?
+            .context("daemon closed the connection")?;
+        match serde_json::from_str(&line)? {
+            Response::Error(message) => anyhow::bail!(message),
+            response => Ok(response),
+        }
+    }
+
+    /// 通过守护进程执行命令
+    pub async fn run(mut self, command: Commands) -> anyhow::Result<()> {
+        let mut policy = None;
+        let request = match command {
+            Commands::Send(args) => {
+                let path = args.path.context("missing path to send")?;
+                let path = path
+                    .canonicalize()
+                    .with_context(|| format!("无法访问文件或目录：{}", path.display()))?;
+                Request::Send {
+                    path,
+                    meta: ShareMeta::new(args.message, args.labels, args.recipients),
+                    expires: args.expires.map(|expires| unix_now() + expires.as_secs()),
+                    compress: args.compress,
+                }
+            }
+            Commands::Receive(args) => {
+                policy = Some(SignaturePolicy {
+                    author: args.author,
+                    require_trusted: args.require_trusted,
+                    trust: TrustStore::load(&self.dir).await?,
+                });
+                Request::Receive {
+                    ticket: args.code.to_string(),
+                    target: std::env::current_dir()?,
+                    author: args.author,
+                    require_trusted: args.require_trusted,
+                }
+            }
+            Commands::List => Request::List,
+            Commands::Unshare(args) => Request::Unshare { id: args.id },
+            command => anyhow::bail!("{command:?} is not supported by the daemon"),
+        };
+        match self.call(request).await? {
+            Response::Shared(share) => {
+                println!(
+                    "share {}: {} {} files, {}, hash: {}",
+                    share.id,
+                    share.path.display(),
+                    share.files,
+                    HumanBytes(share.size),
+                    share.hash
+                );
+                println!("to get this data, use");
+                println!("transfer receive --code {}", share.ticket);
+                if let Some(expires) = share.expires {
+                    println!("expires in {}", format_expiry(expires));
+                }
+            }
+            Response::Received { files, size, elapsed, meta, authorship } => {
+                // 守护进程已经按要求检查了签名，在客户端展示签名状态和分享信息
+                if let (Some(policy), Some(authorship)) = (&policy, &authorship) {
+                    policy.print(authorship);
+                }
+                meta.print();
+                println!(
+                    "downloaded {} files, {}. took {}",
+                    files,
+                    HumanBytes(size),
+                    HumanDuration(elapsed)
+                );
+            }
+            Response::Shares(shares) => {
+                if shares.is_empty() {
+                    println!("nothing shared");
+                }
+                for share in shares {
+                    let expires = match share.expires {
+                        Some(expires) => format!("  (expires in {})", format_expiry(expires)),
+                        None => String::new(),
+                    };
+                    println!(
+                        "{:>4}  {}  {} files, {}  {}{}",
+                        share.id,
+                        share.hash.fmt_short(),
+                        share.files,
+                        HumanBytes(share.size),
+                        share.path.display(),
+                        expires
+                    );
+                }
+            }
+            Response::Unshared(share) => {
+                println!("stopped sharing {} {}", share.id, share.path.display());
+            }
+            Response::Error(message) => anyhow::bail!(message),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::RelayMode;
+
+    use super::*;
+
+    fn share_info(id: u64) -> ShareInfo {
+        ShareInfo {
+            id,
+            path: PathBuf::from(format!("share-{id}")),
+            hash: Hash::new(id.to_le_bytes()),
+            files: 1,
+            size: 1,
+            ticket: String::new(),
+            expires: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_share_table() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("transfer-daemon-table-{}", rand::random::<u64>()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join(SHARES_FILE);
+        let res = async {
+            let mut table = ShareTable::load(&path).await?;
+            for _ in 0..3 {
+                let id = table.allocate();
+                table.insert(share_info(id));
+            }
+            // 撤销最新的分享后编号不会被复用
+            assert!(table.remove(3).is_some());
+            assert_eq!(table.allocate(), 4);
+            table.save(&path).await?;
+
+            let mut table = ShareTable::load(&path).await?;
+            assert_eq!(table.values().map(|share| share.id).collect::<Vec<_>>(), [1, 2]);
+            assert_eq!(table.allocate(), 5);
+
+            // 旧版本的文件只有分享列表
+            let old = vec![share_info(1), share_info(7)];
+            tokio::fs::write(&path, serde_json::to_vec(&old)?).await?;
+            let mut table = ShareTable::load(&path).await?;
+            assert_eq!(table.len(), 2);
+            assert_eq!(table.allocate(), 8);
+            anyhow::Ok(())
+        }
+        .await;
+        tokio::fs::remove_dir_all(&dir).await?;
+        res
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_write_private() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("transfer-daemon-private-{}", rand::random::<u64>()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let res = async {
+            let path = dir.join(SECRET_KEY_FILE);
+            write_private(&path, b"secret").await?;
+            assert_eq!(tokio::fs::metadata(&path).await?.permissions().mode() & 0o777, 0o600);
+            // 旧版本留下的文件权限过宽时同样收紧
+            tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).await?;
+            write_private(&path, b"new").await?;
+            assert_eq!(tokio::fs::metadata(&path).await?.permissions().mode() & 0o777, 0o600);
+            assert_eq!(tokio::fs::read(&path).await?, b"new");
+            anyhow::Ok(())
+        }
+        .await;
+        tokio::fs::remove_dir_all(&dir).await?;
+        res
+    }
+
+    #[test]
+    fn test_request_format() -> anyhow::Result<()> {
+        let envelope = Envelope {
+            token: "secret".to_string(),
+            request: Request::Unshare { id: 3 },
+        };
+        let json = serde_json::to_value(&envelope)?;
+        assert_eq!(
+            json,
+            serde_json::json!({ "token": "secret", "method": "unshare", "params": { "id": 3 } })
+        );
+        // 旧的客户端不带签名要求
+        let line = r#"{"token":"t","method":"receive","params":{"ticket":"x","target":"/tmp"}}"#;
+        let envelope: Envelope = serde_json::from_str(line)?;
+        assert!(matches!(
+            envelope.request,
+            Request::Receive { author: None, require_trusted: false, .. }
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_missing_collection() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("transfer-daemon-missing-{}", rand::random::<u64>()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let res = async {
+            let file = dir.join("shared.txt");
+            tokio::fs::write(&file, "hello").await?;
+            let endpoint = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await?;
+            let daemon = Daemon::load(dir.clone(), endpoint, UploadLimits::default(), GC_PERIOD).await?;
+            let shared = daemon.share(file, &ShareMeta::default(), None, false).await?;
+            daemon.router.shutdown().await?;
+            drop(daemon);
+
+            // 分享列表中还有一个存储里没有的集合
+            let mut table = ShareTable::load(&dir.join(SHARES_FILE)).await?;
+            let id = table.allocate();
+            table.insert(share_info(id));
+            table.save(&dir.join(SHARES_FILE)).await?;
+
+            let endpoint = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await?;
+            let daemon = Daemon::load(dir.clone(), endpoint, UploadLimits::default(), GC_PERIOD).await?;
+            let ids = daemon.shares.lock().await.values().map(|share| share.id).collect::<Vec<_>>();
+            assert_eq!(ids, [shared.id]);
+            daemon.router.shutdown().await?;
+            anyhow::Ok(())
+        }
+        .await;
+        tokio::fs::remove_dir_all(&dir).await?;
+        res
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_receive_during_gc() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("transfer-daemon-gc-{}", rand::random::<u64>()));
+        let source = dir.join("source");
+        tokio::fs::create_dir_all(&source).await?;
+        let res = async {
+            for index in 0..200 {
+                tokio::fs::write(source.join(format!("{index}.txt")), format!("file {index} ").repeat(5000)).await?;
+            }
+            let endpoint = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await?;
+            let sender = Daemon::load(dir.join("sender"), endpoint, UploadLimits::default(), GC_PERIOD).await?;
+            let meta = ShareMeta::new(Some("hello".to_string()), vec![("kind".to_string(), "test".to_string())], Vec::new());
+            let shared = sender.share(source.clone(), &meta, None, false).await?;
+
+            // 接收端不停地 gc，下载和导出的数据不能被回收
+            let endpoint = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await?;
+            let receiver = Daemon::load(dir.join("receiver"), endpoint, UploadLimits::default(), Duration::from_millis(1)).await?;
+            for round in 0..3 {
+                let target = dir.join(format!("target-{round}"));
+                let response = receiver.receive(&shared.ticket, &target, &SignaturePolicy::default()).await?;
+                // 分享信息和签名状态返回给客户端展示
+                let Response::Received { files, meta: received, authorship, .. } = response else {
+                    anyhow::bail!("unexpected response");
+                };
+                assert_eq!(files, 200);
+                assert_eq!(received.message, meta.message);
+                assert_eq!(received.labels, meta.labels);
+                let Some(Authorship::Signed(record)) = authorship else {
+                    anyhow::bail!("share is not signed: {authorship:?}");
+                };
+                assert_eq!(record.author, sender.router.endpoint().node_id());
+                for index in 0..200 {
+                    let name = format!("source/{index}.txt");
+                    assert_eq!(tokio::fs::read(target.join(&name)).await?, tokio::fs::read(dir.join(&name)).await?, "{name}");
+                }
+            }
+            // 下载的数据不属于接收端的分享，不向其他节点提供
+            assert!(!receiver.access.is_allowed(&shared.hash));
+            sender.router.shutdown().await?;
+            receiver.router.shutdown().await?;
+            anyhow::Ok(())
+        }
+        .await;
+        tokio::fs::remove_dir_all(&dir).await?;
+        res
+    }
+
+    #[tokio::test]
+    async fn test_control() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("transfer-daemon-control-{}", rand::random::<u64>()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let res = async {
+            let endpoint = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await?;
+            let daemon = Arc::new(Daemon::load(dir.clone(), endpoint, UploadLimits::default(), GC_PERIOD).await?);
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let control = ControlInfo {
+                addr: listener.local_addr()?,
+                token: "secret".to_string(),
+            };
+            write_private(&dir.join(CONTROL_FILE), &serde_json::to_vec(&control)?).await?;
+            let server = tokio::spawn({
+                let daemon = daemon.clone();
+                async move {
+                    let token = Arc::new(control.token);
+                    while let Ok((stream, _)) = listener.accept().await {
+                        tokio::spawn(handle_client(daemon.clone(), token.clone(), stream));
+                    }
+                }
+            });
+
+            let mut client = DaemonClient::connect(Some(dir.clone())).await?.context("daemon is running")?;
+            let Response::Shares(shares) = client.call(Request::List).await? else {
+                anyhow::bail!("unexpected response");
+            };
+            assert!(shares.is_empty());
+
+            let file = dir.join("shared.txt");
+            tokio::fs::write(&file, "hello").await?;
+            let send = Request::Send {
+                path: file.clone(),
+                meta: ShareMeta::default(),
+                expires: None,
+                compress: false,
+            };
+            let Response::Shared(first) = client.call(send.clone()).await? else {
+                anyhow::bail!("unexpected response");
+            };
+            let Response::Unshared(unshared) = client.call(Request::Unshare { id: first.id }).await? else {
+                anyhow::bail!("unexpected response");
+            };
+            assert_eq!(unshared.id, first.id);
+            let err = client.call(Request::Unshare { id: first.id }).await.unwrap_err();
+            assert!(err.to_string().contains("no share with id"));
+            // 撤销后的编号不会分配给新的分享
+            let Response::Shared(second) = client.call(send).await? else {
+                anyhow::bail!("unexpected response");
+            };
+            assert!(second.id > first.id);
+
+            // 令牌错误时拒绝
+            client.token = "wrong".to_string();
+            let err = client.call(Request::List).await.unwrap_err();
+            assert!(err.to_string().contains("invalid token"));
+
+            server.abort();
+            daemon.router.shutdown().await?;
+            anyhow::Ok(())
+        }
+        .await;
+        tokio::fs::remove_dir_all(&dir).await?;
+        res
+    }
+}
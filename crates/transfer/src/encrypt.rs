@@ -0,0 +1,273 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use iroh::{NodeId, SecretKey};
+use rand::RngCore;
+
+/// 加密文件的开头，用于识别格式
+const MAGIC: &[u8; 16] = b"transfer-enc-v0\n";
+
+/// 每段明文的长度
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// 认证标签的长度
+const TAG_SIZE: usize = 16;
+
+/// 封装后的文件密钥长度
+const WRAPPED_KEY_SIZE: usize = 32 + TAG_SIZE;
+
+/// 派生密钥时使用的上下文，避免与其他用途混淆
+const WRAP_CONTEXT: &str = "transfer encrypt v0 file key";
+const PAYLOAD_CONTEXT: &str = "transfer encrypt v0 payload";
+
+/// 为一个接收者封装的文件密钥
+/// 使用临时密钥和接收者的公钥（转换为 X25519）协商出封装密钥
+struct Stanza {
+    ephemeral: [u8; 32],
+    wrapped: [u8; WRAPPED_KEY_SIZE],
+}
+
+/// 节点公钥对应的 X25519 公钥
+fn montgomery(node_id: &NodeId) -> MontgomeryPoint {
+    node_id.public().to_montgomery()
+}
+
+/// 由协商结果派生封装密钥
+fn wrap_cipher(
+    shared: &MontgomeryPoint,
+    ephemeral: &MontgomeryPoint,
+    recipient: &MontgomeryPoint,
+) -> anyhow::Result<ChaCha20Poly1305> {
+    // 对方公钥是小阶点时协商结果为 0
+    anyhow::ensure!(shared.as_bytes() != &[0u8; 32], "invalid recipient key");
+    let mut material = Vec::with_capacity(96);
+    material.extend_from_slice(shared.as_bytes());
+    material.extend_from_slice(ephemeral.as_bytes());
+    material.extend_from_slice(recipient.as_bytes());
+    let key = blake3::derive_key(WRAP_CONTEXT, &material);
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+/// 加密文件内容的密钥，每个文件使用不同的随机 nonce
+fn payload_cipher(file_key: &[u8; 32], nonce: &[u8; 16]) -> ChaCha20Poly1305 {
+    let mut material = file_key.to_vec();
+    material.extend_from_slice(nonce);
+    let key = blake3::derive_key(PAYLOAD_CONTEXT, &material);
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+/// 每段使用的 nonce：段序号加上是否是最后一段，防止分段被截断或重排
+fn chunk_nonce(counter: u64, last: bool) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[3..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    Nonce::from(nonce)
+}
+
+/// 尽量读满缓冲区，返回读取的长度，小于缓冲区长度说明已经读完
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+/// 为多个接收者加密文件，只有接收者的节点私钥可以解密
+/// 阻塞执行，需要在 spawn_blocking 中调用
+pub fn encrypt_file(src: &Path, dst: &Path, recipients: &[NodeId]) -> anyhow::Result<()> {
+    anyhow::ensure!(!recipients.is_empty(), "no recipients to encrypt for");
+    let count = u16::try_from(recipients.len()).context("too many recipients")?;
+    let mut rng = rand::rngs::OsRng;
+    let mut file_key = [0u8; 32];
+    rng.fill_bytes(&mut file_key);
+
+    let mut reader = BufReader::new(File::open(src)?);
+    let mut writer = BufWriter::new(File::create(dst)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&count.to_be_bytes())?;
+    for recipient in recipients {
+        let mut secret = [0u8; 32];
+        rng.fill_bytes(&mut secret);
+        let ephemeral = MontgomeryPoint::mul_base_clamped(secret);
+        let recipient = montgomery(recipient);
+        let shared = recipient.mul_clamped(secret);
+        let wrapped = wrap_cipher(&shared, &ephemeral, &recipient)?
+            .encrypt(&Nonce::default(), file_key.as_slice())
+            .map_err(|_| anyhow::anyhow!("failed to wrap file key"))?;
+        writer.write_all(ephemeral.as_bytes())?;
+        writer.write_all(&wrapped)?;
+    }
+    let mut nonce = [0u8; 16];
+    rng.fill_bytes(&mut nonce);
+    writer.write_all(&nonce)?;
+
+    let cipher = payload_cipher(&file_key, &nonce);
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut next = vec![0u8; CHUNK_SIZE];
+    let mut len = read_full(&mut reader, &mut chunk)?;
+    let mut counter = 0u64;
+    loop {
+        // 预读下一段，用来判断当前段是否是最后一段
+        let next_len = match len {
+            CHUNK_SIZE => read_full(&mut reader, &mut next)?,
+            _ => 0,
+        };
+        let last = next_len == 0;
+        let sealed = cipher
+            .encrypt(&chunk_nonce(counter, last), &chunk[..len])
+            .map_err(|_| anyhow::anyhow!("failed to encrypt"))?;
+        writer.write_all(&sealed)?;
+        if last {
+            break;
+        }
+        std::mem::swap(&mut chunk, &mut next);
+        len = next_len;
+        counter += 1;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// 读取文件头，使用节点私钥解出文件密钥
+fn read_header(reader: &mut impl Read, identity: &SecretKey) -> anyhow::Result<ChaCha20Poly1305> {
+    let mut magic = [0u8; 16];
+    reader.read_exact(&mut magic).context("file is not encrypted")?;
+    anyhow::ensure!(&magic == MAGIC, "file is not encrypted");
+    let mut count = [0u8; 2];
+    reader.read_exact(&mut count)?;
+    let mut stanzas = Vec::new();
+    for _ in 0..u16::from_be_bytes(count) {
+        let mut stanza = Stanza {
+            ephemeral: [0u8; 32],
+            wrapped: [0u8; WRAPPED_KEY_SIZE],
+        };
+        reader.read_exact(&mut stanza.ephemeral)?;
+        reader.read_exact(&mut stanza.wrapped)?;
+        stanzas.push(stanza);
+    }
+    let mut nonce = [0u8; 16];
+    reader.read_exact(&mut nonce)?;
+
+    let scalar = identity.secret().to_scalar_bytes();
+    let recipient = montgomery(&identity.public());
+    let file_key = stanzas
+        .iter()
+        .find_map(|stanza| {
+            let ephemeral = MontgomeryPoint(stanza.ephemeral);
+            let shared = ephemeral.mul_clamped(scalar);
+            let cipher = wrap_cipher(&shared, &ephemeral, &recipient).ok()?;
+            cipher.decrypt(&Nonce::default(), stanza.wrapped.as_slice()).ok()
+        })
+        .context("file is not encrypted for this node")?;
+    let file_key: [u8; 32] = file_key
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("invalid file key"))?;
+    Ok(payload_cipher(&file_key, &nonce))
+}
+
+/// 解密 src 写入 dst，内容被篡改或截断时返回错误并删除 dst
+/// 阻塞执行，需要在 spawn_blocking 中调用
+pub fn decrypt_file(src: &Path, dst: &Path, identity: &SecretKey) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(File::open(src)?);
+    let cipher = read_header(&mut reader, identity)?;
+    let res = File::create(dst)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| decrypt_chunks(&mut reader, BufWriter::new(file), &cipher));
+    if res.is_err() {
+        std::fs::remove_file(dst).ok();
+    }
+    res
+}
+
+fn decrypt_chunks(
+    reader: &mut impl Read,
+    mut writer: impl Write,
+    cipher: &ChaCha20Poly1305,
+) -> anyhow::Result<()> {
+    const SEALED_SIZE: usize = CHUNK_SIZE + TAG_SIZE;
+    let mut chunk = vec![0u8; SEALED_SIZE];
+    let mut next = vec![0u8; SEALED_SIZE];
+    let mut len = read_full(reader, &mut chunk)?;
+    anyhow::ensure!(len >= TAG_SIZE, "encrypted file is truncated");
+    let mut counter = 0u64;
+    loop {
+        let next_len = match len {
+            SEALED_SIZE => read_full(reader, &mut next)?,
+            _ => 0,
+        };
+        let last = next_len == 0;
+        let plain = cipher
+            .decrypt(&chunk_nonce(counter, last), &chunk[..len])
+            .map_err(|_| anyhow::anyhow!("encrypted file is corrupted"))?;
+        writer.write_all(&plain)?;
+        if last {
+            break;
+        }
+        std::mem::swap(&mut chunk, &mut next);
+        len = next_len;
+        counter += 1;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn roundtrip(size: usize) {
+        let dir = std::env::temp_dir().join(format!("transfer-encrypt-test-{size}-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let (plain, sealed, opened) = (dir.join("plain"), dir.join("sealed"), dir.join("opened"));
+        std::fs::write(&plain, &data).unwrap();
+
+        let alice = SecretKey::generate(rand::rngs::OsRng);
+        let bob = SecretKey::generate(rand::rngs::OsRng);
+        let eve = SecretKey::generate(rand::rngs::OsRng);
+        encrypt_file(&plain, &sealed, &[alice.public(), bob.public()]).unwrap();
+
+        for identity in [&alice, &bob] {
+            decrypt_file(&sealed, &opened, identity).unwrap();
+            assert_eq!(std::fs::read(&opened).unwrap(), data);
+        }
+        assert!(decrypt_file(&sealed, &opened, &eve).is_err());
+
+        // 篡改最后一个字节
+        let mut tampered = std::fs::read(&sealed).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        std::fs::write(&sealed, &tampered).unwrap();
+        assert!(decrypt_file(&sealed, &opened, &alice).is_err());
+        assert!(!opened.exists());
+
+        // 截掉最后一段
+        if size > CHUNK_SIZE {
+            let truncated = tampered.len() - (size % CHUNK_SIZE).max(1) - TAG_SIZE;
+            std::fs::write(&sealed, &tampered[..truncated]).unwrap();
+            assert!(decrypt_file(&sealed, &opened, &alice).is_err());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_encrypt_roundtrip() {
+        roundtrip(0);
+        roundtrip(1000);
+        roundtrip(CHUNK_SIZE);
+        roundtrip(CHUNK_SIZE * 2 + 17);
+    }
+}
@@ -0,0 +1,732 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Mutex,
+    time::Instant,
+};
+
+use console::style;
+use file_transfer::progress::Progress;
+use futures::future::join_all;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use iroh::{endpoint::Connection, Endpoint, NodeAddr};
+use iroh_blobs::{
+    get::{fsm, Stats},
+    protocol::{GetRequest, RangeSpec, RangeSpecSeq},
+    store::{
+        bao_tree::{io::BaoContentItem, ChunkNum, ChunkRanges},
+        fs::Store as FsStore,
+        BaoBatchWriter, EntryStatus, MapEntryMut, MapMut,
+    },
+    Hash,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    collection::{CollectionLimits, CollectionSummary, Entries, StoredCollection},
+    limit::RateLimiter,
+    meta::is_internal,
+};
+
+/// 大文件按范围拆分，每段 4 MiB（1 KiB 一个 chunk），不同的段可以同时下载
+const SEGMENT_CHUNKS: u64 = 4096;
+
+/// 不超过该大小的文件合并成一批请求，减少小文件的请求往返
+const SMALL_FILE_SIZE: u64 = 64 * 1024;
+
+/// 一批小文件的最大总大小和最大数量
+const BATCH_SIZE: u64 = 4 * 1024 * 1024;
+const BATCH_FILES: usize = 1024;
+
+/// 默认每个提供者同时进行的请求数
+pub const DEFAULT_PARALLEL: usize = 4;
+
+/// 下载单元
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Unit {
+    /// 一个 blob 的一段范围
+    Segment { hash: Hash, ranges: ChunkRanges },
+    /// 一批小文件，通过集合的哈希序列一次请求，记录它们在哈希序列中的位置
+    Batch { children: Vec<(u64, Hash)> },
+}
+
+impl Unit {
+    /// 完成后写完的 blob
+    fn hashes(&self) -> Vec<Hash> {
+        match self {
+            Unit::Segment { hash, .. } => vec![*hash],
+            Unit::Batch { children } => children.iter().map(|(_, hash)| *hash).collect(),
+        }
+    }
+}
+
+/// 把 blob 拆分成下载单元，最后一段不设上限，同时验证 blob 的大小
+fn segments(hash: Hash, size: u64) -> Vec<Unit> {
+    let chunks = size.div_ceil(1024);
+    if chunks <= SEGMENT_CHUNKS {
+        return vec![Unit::Segment {
+            hash,
+            ranges: ChunkRanges::all(),
+        }];
+    }
+    (0..chunks)
+        .step_by(SEGMENT_CHUNKS as usize)
+        .map(|start| {
+            let end = start + SEGMENT_CHUNKS;
+            let ranges = match end >= chunks {
+                true => ChunkRanges::from(ChunkNum(start)..),
+                false => ChunkRanges::from(ChunkNum(start)..ChunkNum(end)),
+            };
+            Unit::Segment { hash, ranges }
+        })
+        .collect()
+}
+
+/// 按哈希序列的顺序划分下载单元：相邻的小文件合并成一批，大文件按范围拆分
+/// 只保留正在合并的一批，其余单元划分好后立即交出
+#[derive(Debug, Default)]
+struct Plan {
+    batch: Vec<(u64, Hash)>,
+    batch_size: u64,
+}
+
+impl Plan {
+    /// 加入哈希序列中位置为 index 的子节点，返回划分好的单元
+    fn push(&mut self, index: u64, hash: Hash, size: u64) -> Vec<Unit> {
+        if size > SMALL_FILE_SIZE {
+            return segments(hash, size);
+        }
+        self.batch.push((index, hash));
+        self.batch_size += size;
+        match self.batch_size >= BATCH_SIZE || self.batch.len() >= BATCH_FILES {
+            true => self.finish().into_iter().collect(),
+            false => Vec::new(),
+        }
+    }
+
+    /// 交出正在合并的一批
+    fn finish(&mut self) -> Option<Unit> {
+        self.batch_size = 0;
+        match self.batch.is_empty() {
+            true => None,
+            false => Some(Unit::Batch {
+                children: std::mem::take(&mut self.batch),
+            }),
+        }
+    }
+}
+
+/// 并行下载的设置
+#[derive(Debug, Clone, Copy)]
+pub struct FetchOptions<'a> {
+    /// 每个提供者同时进行的请求数
+    pub parallel: usize,
+    /// 下载限速，所有提供者共享
+    pub limit: Option<&'a RateLimiter>,
+    /// 接收集合时的限制
+    pub collection: CollectionLimits,
+    /// 下载进度，每写入一批数据发送一次
+    pub progress: Option<&'a mpsc::UnboundedSender<Progress>>,
+}
+
+impl Default for FetchOptions<'_> {
+    fn default() -> Self {
+        Self {
+            parallel: DEFAULT_PARALLEL,
+            limit: None,
+            collection: CollectionLimits::default(),
+            progress: None,
+        }
+    }
+}
+
+/// 一个提供者和它的下载统计
+struct Provider {
+    addr: NodeAddr,
+    bar: ProgressBar,
+}
+
+/// 记录写入的字节数，更新进度并按下载限速等待
+struct MeteredWriter<'a, W> {
+    inner: W,
+    provider: &'a Provider,
+    total: &'a ProgressBar,
+    limit: Option<&'a RateLimiter>,
+    progress: Option<&'a mpsc::UnboundedSender<Progress>>,
+}
+
+impl<W: BaoBatchWriter> BaoBatchWriter for MeteredWriter<'_, W> {
+    async fn write_batch(&mut self, size: u64, batch: Vec<BaoContentItem>) -> std::io::Result<()> {
+        let bytes = batch
+            .iter()
+            .map(|item| match item {
+                BaoContentItem::Leaf(leaf) => leaf.data.len() as u64,
+                BaoContentItem::Parent(_) => 0,
+            })
+            .sum::<u64>();
+        self.inner.write_batch(size, batch).await?;
+        self.provider.bar.inc(bytes);
+        self.total.inc(bytes);
+        if let Some(progress) = self.progress {
+            progress
+                .send(Progress {
+                    bytes: self.total.position(),
+                    total: self.total.length().unwrap_or_default(),
+                    rate: self.total.per_sec(),
+                    eta: Some(self.total.eta()),
+                })
+                .ok();
+        }
+        if let Some(limit) = self.limit {
+            limit.acquire(bytes).await;
+        }
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> std::io::Result<()> {
+        self.inner.sync().await
+    }
+}
+
+/// 正在下载的 blob
+struct Pending<E> {
+    /// 还没有完成的单元数
+    units: usize,
+    /// 文件名，内部条目没有
+    name: Option<String>,
+    /// 下载期间保持打开，多个请求写入同一个条目的不同范围
+    entry: E,
+}
+
+/// 从存储中按顺序读取集合的条目，划分下载单元
+struct Planner {
+    entries: Entries,
+    /// 上一个条目在哈希序列中的位置，第一个子节点是文件名列表
+    index: u64,
+    plan: Plan,
+    ready: VecDeque<Unit>,
+    done: bool,
+}
+
+/// 并行下载的共享状态
+/// 条目在需要时才从存储中读取，只有正在下载的 blob 保存在内存中
+struct Swarm<'a> {
+    endpoint: &'a Endpoint,
+    collection: &'a StoredCollection,
+    planner: tokio::sync::Mutex<Planner>,
+    /// 出错后需要重新下载的单元
+    retry: Mutex<VecDeque<Unit>>,
+    pending: Mutex<BTreeMap<Hash, Pending<<FsStore as MapMut>::EntryMut>>>,
+    total: ProgressBar,
+    files: ProgressBar,
+    options: FetchOptions<'a>,
+}
+
+impl Swarm<'_> {
+    /// 下一个下载单元，全部划分完后返回 None
+    async fn next_unit(&self) -> anyhow::Result<Option<Unit>> {
+        if let Some(unit) = self.retry.lock().unwrap().pop_front() {
+            return Ok(Some(unit));
+        }
+        let db = self.collection.db();
+        let mut planner = self.planner.lock().await;
+        loop {
+            if let Some(unit) = planner.ready.pop_front() {
+                return Ok(Some(unit));
+            }
+            if planner.done {
+                return Ok(None);
+            }
+            let Some((name, hash)) = planner.entries.next().await? else {
+                planner.done = true;
+                let last = planner.plan.finish();
+                planner.ready.extend(last);
+                continue;
+            };
+            planner.index += 1;
+            let name = (!is_internal(&name)).then_some(name);
+            let size = self.collection.size_of(&hash).await?;
+            // 相同内容的文件只下载一次，之前已经下载完成的跳过
+            let in_flight = self.pending.lock().unwrap().contains_key(&hash);
+            if in_flight || db.entry_status(&hash).await? == EntryStatus::Complete {
+                self.total.inc(size);
+                if let Some(name) = name {
+                    self.files.inc(1);
+                    self.files.set_message(name);
+                }
+                continue;
+            }
+            let entry = db.get_or_create(hash, size).await?;
+            let index = planner.index;
+            let units = planner.plan.push(index, hash, size);
+            // 小文件在正在合并的一批中，大文件的每一段都是一个单元
+            let count = match size > SMALL_FILE_SIZE {
+                true => units.len(),
+                false => 1,
+            };
+            self.pending.lock().unwrap().insert(hash, Pending { units: count, name, entry });
+            planner.ready.extend(units);
+        }
+    }
+
+    /// 把响应中的一个 blob 写入对应的条目
+    async fn write_blob(&self, header: fsm::AtBlobHeader, hash: Hash, provider: &Provider) -> anyhow::Result<fsm::AtEndBlob> {
+        let (content, _size) = header.next().await?;
+        let entry = self
+            .pending
+            .lock()
+            .unwrap()
+            .get(&hash)
+            .map(|pending| pending.entry.clone())
+            .ok_or_else(|| anyhow::anyhow!("{hash} is not being downloaded"))?;
+        let mut writer = MeteredWriter {
+            inner: entry.batch_writer().await?,
+            provider,
+            total: &self.total,
+            limit: self.options.limit,
+            progress: self.options.progress,
+        };
+        let end = content.write_all_batch(&mut writer).await?;
+        writer.sync().await?;
+        Ok(end)
+    }
+
+    /// 下载一个单元
+    async fn fetch_unit(&self, connection: &Connection, provider: &Provider, unit: &Unit) -> anyhow::Result<()> {
+        let request = match unit {
+            Unit::Segment { hash, ranges } => GetRequest::new(*hash, RangeSpecSeq::from_ranges([ranges])),
+            Unit::Batch { children } => {
+                // 哈希序列本身不需要，只请求这一批子节点
+                // 直接生成 RangeSpec，不为前面的每个子节点分配范围，相邻的相同范围会被合并
+                let all = RangeSpec::new(ChunkRanges::all());
+                let mut requested = children.iter().map(|(index, _)| index + 1).peekable();
+                let last = children.last().map(|(index, _)| index + 1).unwrap_or_default();
+                let ranges = (0..=last + 1).map(|offset| match requested.next_if_eq(&offset) {
+                    Some(_) => all.clone(),
+                    None => RangeSpec::EMPTY,
+                });
+                GetRequest::new(self.collection.hash(), RangeSpecSeq::new(ranges))
+            }
+        };
+        let connected = fsm::start(connection.clone(), request).next().await?;
+        let mut next = match (connected.next().await?, unit) {
+            (fsm::ConnectedNext::StartRoot(start), Unit::Segment { hash, .. }) => {
+                self.write_blob(start.next(), *hash, provider).await?.next()
+            }
+            (fsm::ConnectedNext::StartChild(child), Unit::Batch { .. }) => fsm::EndBlobNext::MoreChildren(child),
+            _ => anyhow::bail!("provider sent an unexpected response"),
+        };
+        let children = match unit {
+            Unit::Batch { children } => children.iter().copied().collect::<BTreeMap<_, _>>(),
+            Unit::Segment { .. } => BTreeMap::new(),
+        };
+        let closing = loop {
+            match next {
+                fsm::EndBlobNext::MoreChildren(child) => {
+                    let Some(hash) = children.get(&child.child_offset()) else {
+                        anyhow::bail!("provider sent a child that was not requested");
+                    };
+                    next = self.write_blob(child.next(*hash), *hash, provider).await?.next();
+                }
+                fsm::EndBlobNext::Closing(closing) => break closing,
+            }
+        };
+        closing.next().await?;
+        Ok(())
+    }
+
+    /// 单元完成后把写完的 blob 标记为完整，更新文件进度
+    async fn finish_unit(&self, unit: &Unit) -> anyhow::Result<()> {
+        let finished = {
+            let mut pending = self.pending.lock().unwrap();
+            let mut finished = Vec::new();
+            for hash in unit.hashes() {
+                let Some(blob) = pending.get_mut(&hash) else {
+                    continue;
+                };
+                blob.units -= 1;
+                if blob.units == 0 {
+                    finished.extend(pending.remove(&hash));
+                }
+            }
+            finished
+        };
+        for blob in finished {
+            self.collection.db().insert_complete(blob.entry).await?;
+            if let Some(name) = blob.name {
+                self.files.inc(1);
+                self.files.set_message(name);
+            }
+        }
+        Ok(())
+    }
+
+    /// 不断取出下载单元，出错时放回重试队列交给其他请求
+    async fn worker(&self, connection: &Connection, provider: &Provider) -> anyhow::Result<()> {
+        while let Some(unit) = self.next_unit().await? {
+            if let Err(e) = self.fetch_unit(connection, provider, &unit).await {
+                self.retry.lock().unwrap().push_back(unit);
+                return Err(e);
+            }
+            self.finish_unit(&unit).await?;
+        }
+        Ok(())
+    }
+
+    /// 在同一个连接上同时进行多个请求
+    async fn run(&self, provider: &Provider) -> anyhow::Result<()> {
+        let connection = self.endpoint.connect(provider.addr.clone(), iroh_blobs::protocol::ALPN).await?;
+        let workers = (0..self.options.parallel.max(1)).map(|_| self.worker(&connection, provider));
+        join_all(workers).await.into_iter().collect()
+    }
+
+    /// 全部单元都已下载完成
+    async fn is_done(&self) -> bool {
+        let planner = self.planner.lock().await;
+        planner.done && planner.ready.is_empty() && self.retry.lock().unwrap().is_empty()
+    }
+}
+
+fn make_provider_bar(addr: &NodeAddr) -> ProgressBar {
+    let bar = ProgressBar::hidden();
+    bar.set_style(
+        ProgressStyle::with_template("{msg:>12} {bytes:>10} {binary_bytes_per_sec}")
+            .expect("valid template"),
+    );
+    bar.set_message(addr.node_id.fmt_short());
+    bar
+}
+
+/// 下载集合中的全部文件，collection 和 summary 由 fetch_collection 获取
+/// 子节点同时下载，相邻的小文件合并成一批请求，大文件按范围拆分
+/// 有多个提供者时分配给不同的提供者，某个提供者断开后由其他提供者继续
+pub async fn fetch_parallel(
+    endpoint: &Endpoint,
+    providers: &[NodeAddr],
+    collection: &StoredCollection,
+    summary: &CollectionSummary,
+    options: FetchOptions<'_>,
+) -> anyhow::Result<Stats> {
+    let started = Instant::now();
+    let mp = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+    let total = mp.add(ProgressBar::hidden());
+    total.set_style(
+        ProgressStyle::with_template("{msg:>12} {bytes}/{total_bytes} {binary_bytes_per_sec} {wide_bar}")?
+            .progress_chars("#>-"),
+    );
+    total.set_message("total");
+    total.set_length(summary.total);
+    let files = mp.add(ProgressBar::hidden());
+    files.set_style(ProgressStyle::with_template("{prefix:>12} {pos}/{len} {wide_msg}")?);
+    files.set_prefix("files");
+    files.set_length(summary.files);
+    let planner = Planner {
+        entries: collection.entries().await?,
+        index: 0,
+        plan: Plan::default(),
+        ready: VecDeque::new(),
+        done: false,
+    };
+    let swarm = Swarm {
+        endpoint,
+        collection,
+        planner: tokio::sync::Mutex::new(planner),
+        retry: Mutex::new(VecDeque::new()),
+        pending: Mutex::new(BTreeMap::new()),
+        total,
+        files,
+        options,
+    };
+
+    let mut live = providers
+        .iter()
+        .map(|addr| Provider {
+            addr: addr.clone(),
+            bar: mp.add(make_provider_bar(addr)),
+        })
+        .collect::<Vec<_>>();
+    let mut failed = Vec::new();
+    // 提供者出错时剩下的单元可能已经没有人处理，需要交给仍然可用的提供者再来一轮
+    while !swarm.is_done().await {
+        anyhow::ensure!(!live.is_empty(), "all providers failed");
+        let results = join_all(live.iter().map(|provider| swarm.run(provider))).await;
+        let mut alive = Vec::new();
+        for (provider, res) in live.into_iter().zip(results) {
+            match res {
+                Ok(()) => alive.push(provider),
+                Err(e) => {
+                    mp.suspend(|| {
+                        eprintln!(
+                            "{} provider {} failed: {e:#}",
+                            style("warning:").yellow().bold(),
+                            provider.addr.node_id.fmt_short()
+                        )
+                    });
+                    failed.push(provider);
+                }
+            }
+        }
+        live = alive;
+    }
+
+    swarm.total.finish_and_clear();
+    swarm.files.finish_and_clear();
+    let elapsed = started.elapsed();
+    let mut bytes_read = 0;
+    for provider in live.iter().chain(failed.iter()) {
+        provider.bar.finish_and_clear();
+        let bytes = provider.bar.position();
+        bytes_read += bytes;
+        if providers.len() > 1 {
+            eprintln!(
+                "from {}: {} ({}/s)",
+                provider.addr.node_id.fmt_short(),
+                HumanBytes(bytes),
+                HumanBytes((bytes as f64 / elapsed.as_secs_f64()) as u64)
+            );
+        }
+    }
+    Ok(Stats {
+        bytes_written: bytes_read,
+        bytes_read,
+        elapsed,
+    })
+}
+
+/// 下载单个原始 blob，写入时更新进度并按下载限速等待
+pub async fn fetch_raw(connection: &Connection, db: &FsStore, hash: Hash, limit: Option<&RateLimiter>) -> anyhow::Result<Stats> {
+    let connected = fsm::start(connection.clone(), GetRequest::single(hash)).next().await?;
+    let fsm::ConnectedNext::StartRoot(start) = connected.next().await? else {
+        anyhow::bail!("provider sent an unexpected response");
+    };
+    let (content, size) = start.next().next().await?;
+    let provider = Provider {
+        addr: connection.remote_node_id()?.into(),
+        bar: ProgressBar::hidden(),
+    };
+    let total = ProgressBar::with_draw_target(Some(size), ProgressDrawTarget::stderr());
+    total.set_style(
+        ProgressStyle::with_template("{msg:>12} {bytes}/{total_bytes} {binary_bytes_per_sec} {wide_bar}")?
+            .progress_chars("#>-"),
+    );
+    total.set_message("total");
+    let entry = db.get_or_create(hash, size).await?;
+    let mut writer = MeteredWriter {
+        inner: entry.batch_writer().await?,
+        provider: &provider,
+        total: &total,
+        limit,
+        progress: None,
+    };
+    let end = content.write_all_batch(&mut writer).await?;
+    writer.sync().await?;
+    drop(writer);
+    db.insert_complete(entry).await?;
+    total.finish_and_clear();
+    let fsm::EndBlobNext::Closing(closing) = end.next() else {
+        anyhow::bail!("provider sent an unexpected response");
+    };
+    Ok(closing.next().await?)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use iroh::{protocol::Router, RelayMode};
+    use iroh_blobs::{format::collection::Collection, net_protocol::Blobs, store::Store, BlobFormat};
+
+    use crate::collection::{fetch_collection, tests::temp_store};
+
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let hash = Hash::new(b"blob");
+        assert_eq!(segments(hash, 0), vec![Unit::Segment { hash, ranges: ChunkRanges::all() }]);
+        assert_eq!(segments(hash, 4 * 1024 * 1024).len(), 1);
+
+        let parts = segments(hash, 10 * 1024 * 1024 + 1);
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0], Unit::Segment { hash, ranges: ChunkRanges::from(ChunkNum(0)..ChunkNum(4096)) });
+        assert_eq!(parts[1], Unit::Segment { hash, ranges: ChunkRanges::from(ChunkNum(4096)..ChunkNum(8192)) });
+        // 最后一段包含结尾，用来验证大小
+        assert_eq!(parts[2], Unit::Segment { hash, ranges: ChunkRanges::from(ChunkNum(8192)..) });
+
+        // 小文件合并成批，大文件单独拆分
+        let small = |index: u64| (index, Hash::new(index.to_le_bytes()), 100);
+        let mut children = (0..BATCH_FILES as u64 + 10).map(small).collect::<Vec<_>>();
+        children.insert(5, (9999, hash, 10 * 1024 * 1024));
+        let mut plan = Plan::default();
+        let mut units = children
+            .into_iter()
+            .flat_map(|(index, hash, size)| plan.push(index, hash, size))
+            .collect::<Vec<_>>();
+        units.extend(plan.finish());
+        assert_eq!(units.len(), 5);
+        assert!(matches!(&units[0], Unit::Segment { .. }));
+        assert!(matches!(&units[3], Unit::Batch { children } if children.len() == BATCH_FILES));
+        assert!(matches!(&units[4], Unit::Batch { children } if children.len() == 10));
+    }
+
+    /// 在本机回环上提供 count 个小文件，返回路由、集合哈希和文件名
+    async fn serve_small_files(count: usize) -> anyhow::Result<(Router, Hash, Collection)> {
+        let endpoint = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await?;
+        let blobs = Blobs::memory().build(&endpoint);
+        let mut tags = Vec::new();
+        let mut collection = Vec::new();
+        for index in 0..count {
+            // 1 KiB 到 64 KiB，超过 16 KiB 的文件在获取文件列表后才下载
+            let content = format!("{index:>1023}\n").repeat(index % 64 + 1);
+            let tag = blobs.store().import_bytes(content.into(), BlobFormat::Raw).await?;
+            collection.push((format!("tree/{:03}/{index}.txt", index % 500), *tag.hash()));
+            tags.push(tag);
+        }
+        let collection = collection.into_iter().collect::<Collection>();
+        let root = collection.clone().store(blobs.store()).await?;
+        let hash = *root.hash();
+        std::mem::forget(root);
+        let router = Router::builder(endpoint).accept(iroh_blobs::ALPN, blobs).spawn();
+        Ok((router, hash, collection))
+    }
+
+    async fn receive(router: &Router, hash: Hash, options: Option<FetchOptions<'_>>) -> anyhow::Result<Stats> {
+        let (db, dir) = temp_store("fetch").await?;
+        let res = receive_into(&db, router, hash, options).await;
+        drop(db);
+        tokio::fs::remove_dir_all(dir).await?;
+        res
+    }
+
+    async fn receive_into(db: &FsStore, router: &Router, hash: Hash, options: Option<FetchOptions<'_>>) -> anyhow::Result<Stats> {
+        let endpoint = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await?;
+        let addr = router.endpoint().node_addr().await?;
+        let connection = endpoint.connect(addr.clone(), iroh_blobs::ALPN).await?;
+        let stats = match options {
+            // 一次请求整个集合，逐个接收
+            None => {
+                let get_conn = || async move { Ok(connection) };
+                iroh_blobs::get::db::get_to_db(
+                    db,
+                    get_conn,
+                    &iroh_blobs::HashAndFormat::hash_seq(hash),
+                    iroh_blobs::util::progress::IgnoreProgressSender::default(),
+                )
+                .await?
+            }
+            Some(options) => {
+                let (collection, summary) = fetch_collection(&connection, db, hash, options.collection).await?;
+                fetch_parallel(&endpoint, &[addr], &collection, &summary, options).await?
+            }
+        };
+        let collection = Collection::load_db(db, &hash).await?;
+        for (_, hash) in collection.iter() {
+            anyhow::ensure!(db.entry_status(hash).await? == EntryStatus::Complete);
+        }
+        Ok(stats)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fetch_parallel() -> anyhow::Result<()> {
+        let (router, hash, collection) = serve_small_files(300).await?;
+        let stats = receive(&router, hash, Some(FetchOptions::default())).await?;
+        assert!(stats.bytes_read > 0);
+        assert_eq!(collection.len(), 300);
+
+        // 超过条目数限制时在获取文件列表前返回错误
+        let options = FetchOptions {
+            collection: CollectionLimits {
+                max_entries: 100,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let err = receive(&router, hash, Some(options)).await.unwrap_err();
+        assert!(err.to_string().contains("collection has 300 entries"), "{err:#}");
+
+        // 文件名列表超过大小限制
+        let options = FetchOptions {
+            collection: CollectionLimits {
+                max_names_size: 1024,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let err = receive(&router, hash, Some(options)).await.unwrap_err();
+        assert!(err.to_string().contains("--max-names-size"), "{err:#}");
+        router.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_raw() -> anyhow::Result<()> {
+        let endpoint = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await?;
+        let blobs = Blobs::memory().build(&endpoint);
+        let content = "raw blob\n".repeat(10_000);
+        let tag = blobs.store().import_bytes(content.clone().into(), BlobFormat::Raw).await?;
+        let router = Router::builder(endpoint).accept(iroh_blobs::ALPN, blobs).spawn();
+
+        let (db, dir) = temp_store("fetch-raw").await?;
+        let res = async {
+            let endpoint = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await?;
+            let connection = endpoint.connect(router.endpoint().node_addr().await?, iroh_blobs::ALPN).await?;
+            // 限速时在写入路径中异步等待
+            let limit = RateLimiter::new(1024 * 1024);
+            let stats = fetch_raw(&connection, &db, *tag.hash(), Some(&limit)).await?;
+            // 读取的字节数包括验证用的哈希树
+            assert!(stats.bytes_read >= content.len() as u64);
+            // 完整的条目已经按哈希验证过内容
+            anyhow::ensure!(db.entry_status(tag.hash()).await? == EntryStatus::Complete);
+            anyhow::Ok(())
+        }
+        .await;
+        drop(db);
+        tokio::fs::remove_dir_all(dir).await?;
+        router.shutdown().await?;
+        res
+    }
+
+    /// 哈希序列超过 32 MiB 的集合，所有条目指向同一个 blob
+    /// cargo test -p transfer --release -- --ignored test_huge_collection
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_huge_collection() -> anyhow::Result<()> {
+        let count = 1_100_000;
+        let endpoint = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await?;
+        let blobs = Blobs::memory().build(&endpoint);
+        let tag = blobs.store().import_bytes("same content".into(), BlobFormat::Raw).await?;
+        let collection = (0..count)
+            .map(|index| (format!("tree/{:04}/{index}.txt", index % 1000), *tag.hash()))
+            .collect::<Collection>();
+        let root = collection.store(blobs.store()).await?;
+        let hash = *root.hash();
+        let router = Router::builder(endpoint).accept(iroh_blobs::ALPN, blobs).spawn();
+        receive(&router, hash, Some(FetchOptions::default())).await?;
+        drop((root, tag));
+        router.shutdown().await?;
+        Ok(())
+    }
+
+    /// 对比逐个接收和并行批量接收小文件的速度
+    /// TRANSFER_BENCH_FILES 设置文件数，默认 50000
+    /// cargo test -p transfer --release -- --ignored bench_small_files --nocapture
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_small_files() -> anyhow::Result<()> {
+        let count = std::env::var("TRANSFER_BENCH_FILES")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(50_000);
+        let (router, hash, _) = serve_small_files(count).await?;
+        let sequential = receive(&router, hash, None).await?;
+        println!("get_to_db: {count} files in {:?}", sequential.elapsed);
+        for parallel in [1, 4, 16] {
+            let options = FetchOptions {
+                parallel,
+                ..Default::default()
+            };
+            let stats = receive(&router, hash, Some(options)).await?;
+            println!("fetch_parallel parallel={parallel}: {count} files in {:?}", stats.elapsed);
+        }
+        router.shutdown().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use std::{collections::BTreeSet, path::Path};
+
+use console::{style, Key, Term};
+use indicatif::HumanBytes;
+use iroh::NodeId;
+
+use crate::{cli::ListenArgs, push::Offer, trust::TrustStore};
+
+/// 询问时最多展示的文件数量
+const MAX_LISTED_FILES: usize = 10;
+
+/// 自动接受推送的规则
+#[derive(Debug, Clone, Default)]
+pub struct AcceptRules {
+    /// 信任的发送端，来自信任列表
+    pub trusted: BTreeSet<NodeId>,
+    /// 允许的最大大小
+    pub max_size: Option<u64>,
+    /// 允许的文件类型（小写扩展名），为空时不限制
+    pub extensions: BTreeSet<String>,
+}
+
+/// 规则检查结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// 自动接受
+    Accept,
+    /// 不符合规则，需要询问用户
+    Ask(String),
+}
+
+impl AcceptRules {
+    pub fn from_args(args: &ListenArgs, trust: &TrustStore) -> Self {
+        Self {
+            trusted: trust.nodes().copied().collect(),
+            max_size: args.max_size,
+            extensions: args
+                .extensions
+                .iter()
+                .map(|ext| ext.trim_start_matches('.').to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// 检查推送是否可以自动接受
+    pub fn check(&self, sender: &NodeId, offer: &Offer) -> Verdict {
+        if !self.trusted.contains(sender) {
+            return Verdict::Ask("sender is not trusted".to_string());
+        }
+        if let Some(max_size) = self.max_size {
+            if offer.size > max_size {
+                return Verdict::Ask(format!(
+                    "size {} exceeds {}",
+                    HumanBytes(offer.size),
+                    HumanBytes(max_size)
+                ));
+            }
+        }
+        if !self.extensions.is_empty() {
+            let denied = offer.files.iter().find(|name| {
+                let ext = Path::new(name)
+                    .extension()
+                    .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+                !ext.is_some_and(|ext| self.extensions.contains(&ext))
+            });
+            if let Some(name) = denied {
+                return Verdict::Ask(format!("file type of {name} is not allowed"));
+            }
+        }
+        Verdict::Accept
+    }
+}
+
+/// 展示收到的推送
+pub fn print_offer(sender: &NodeId, offer: &Offer, pending: usize) {
+    println!(
+        "{} {} ({}) offers {} files, {}",
+        style("incoming").bold(),
+        offer.device,
+        sender.fmt_short(),
+        offer.files.len(),
+        HumanBytes(offer.size)
+    );
+    if let Some(message) = &offer.message {
+        println!("    message: {message}");
+    }
+    for (key, value) in &offer.labels {
+        println!("    label: {key}={value}");
+    }
+    for name in offer.files.iter().take(MAX_LISTED_FILES) {
+        println!("    {name}");
+    }
+    if offer.files.len() > MAX_LISTED_FILES {
+        println!("    ... and {} more", offer.files.len() - MAX_LISTED_FILES);
+    }
+    if pending > 0 {
+        println!("    {} more offer(s) waiting", pending);
+    }
+}
+
+/// 询问用户是否接受，无法读取终端时视为拒绝
+pub async fn prompt_accept(reason: &str) -> bool {
+    let reason = reason.to_string();
+    let answer = tokio::task::spawn_blocking(move || {
+        let term = Term::stdout();
+        term.write_line(&format!("{} ({reason})", style("accept? [y/N]").yellow()))?;
+        loop {
+            match term.read_key()? {
+                Key::Char('y') | Key::Char('Y') => return std::io::Result::Ok(true),
+                Key::Char('n') | Key::Char('N') | Key::Enter | Key::Escape => return Ok(false),
+                _ => {}
+            }
+        }
+    })
+    .await;
+    matches!(answer, Ok(Ok(true)))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use iroh::SecretKey;
+
+    fn offer(files: &[&str], size: u64) -> Offer {
+        Offer {
+            ticket: String::new(),
+            device: "test".to_string(),
+            files: files.iter().map(|name| name.to_string()).collect(),
+            size,
+            message: None,
+            labels: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_accept_rules() {
+        let trusted = SecretKey::generate(rand::rngs::OsRng).public();
+        let stranger = SecretKey::generate(rand::rngs::OsRng).public();
+        let rules = AcceptRules {
+            trusted: [trusted].into_iter().collect(),
+            max_size: Some(1024),
+            extensions: ["pdf".to_string()].into_iter().collect(),
+        };
+
+        assert_eq!(rules.check(&trusted, &offer(&["docs/a.PDF"], 100)), Verdict::Accept);
+        assert!(matches!(rules.check(&stranger, &offer(&["a.pdf"], 100)), Verdict::Ask(_)));
+        assert!(matches!(rules.check(&trusted, &offer(&["a.pdf"], 2048)), Verdict::Ask(_)));
+        assert!(matches!(rules.check(&trusted, &offer(&["a.pdf", "b.exe"], 100)), Verdict::Ask(_)));
+        assert!(matches!(rules.check(&trusted, &offer(&["README"], 100)), Verdict::Ask(_)));
+    }
+}
@@ -0,0 +1,213 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use data_encoding::HEXLOWER;
+use futures::future::BoxFuture;
+use iroh::{endpoint::Connection, protocol::ProtocolHandler, Endpoint, NodeAddr, NodeId, SecretKey};
+use iroh_base::Signature;
+use iroh_blobs::Hash;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// 获取最新集合的协议
+pub const ALPN: &[u8] = b"transfer/latest/0";
+
+/// 响应的最大长度
+const MAX_RESPONSE_SIZE: usize = 4096;
+
+/// 签名时使用的前缀，避免与其他用途的签名混淆
+const SIGNATURE_DOMAIN: &[u8] = b"transfer-latest-v0";
+
+/// 发送端当前发布的集合
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatestRecord {
+    /// 集合哈希
+    pub hash: Hash,
+    /// 版本号，每次发布递增
+    pub seq: u64,
+    /// 发布时间（unix 秒）
+    pub timestamp: u64,
+}
+
+impl LatestRecord {
+    fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = SIGNATURE_DOMAIN.to_vec();
+        bytes.extend_from_slice(self.hash.as_bytes());
+        bytes.extend_from_slice(&self.seq.to_le_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes
+    }
+
+    /// 使用节点私钥签名
+    pub fn sign(self, secret_key: &SecretKey) -> SignedLatest {
+        let signature = secret_key.sign(&self.signing_bytes());
+        SignedLatest {
+            record: self,
+            signature: HEXLOWER.encode(&signature.to_bytes()),
+        }
+    }
+}
+
+/// 带签名的最新集合记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedLatest {
+    pub record: LatestRecord,
+    signature: String,
+}
+
+impl SignedLatest {
+    /// 验证记录是否由指定节点签名
+    pub fn verify(&self, node_id: &NodeId) -> anyhow::Result<&LatestRecord> {
+        let bytes = HEXLOWER
+            .decode(self.signature.as_bytes())
+            .context("invalid signature encoding")?;
+        let bytes: [u8; 64] = bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("invalid signature length"))?;
+        let signature = Signature::from_bytes(&bytes);
+        node_id
+            .verify(&self.record.signing_bytes(), &signature)
+            .context("latest record is not signed by the sender")?;
+        Ok(&self.record)
+    }
+}
+
+/// 发布端：每次发布新的集合哈希都会生成新的签名记录
+#[derive(Debug)]
+pub struct LatestPublisher {
+    secret_key: SecretKey,
+    seq: u64,
+    sender: watch::Sender<Option<SignedLatest>>,
+}
+
+impl LatestPublisher {
+    /// 创建发布端以及对应的协议处理器
+    pub fn new(secret_key: SecretKey) -> (Self, LatestProtocol) {
+        let (sender, current) = watch::channel(None);
+        let publisher = Self {
+            secret_key,
+            seq: 0,
+            sender,
+        };
+        (publisher, LatestProtocol { current })
+    }
+
+    /// 发布新的集合哈希
+    pub fn publish(&mut self, hash: Hash) -> LatestRecord {
+        self.seq += 1;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let record = LatestRecord {
+            hash,
+            seq: self.seq,
+            timestamp,
+        };
+        self.sender
+            .send_replace(Some(record.clone().sign(&self.secret_key)));
+        record
+    }
+}
+
+/// 响应最新集合请求的协议处理器
+#[derive(Debug, Clone)]
+pub struct LatestProtocol {
+    current: watch::Receiver<Option<SignedLatest>>,
+}
+
+impl ProtocolHandler for LatestProtocol {
+    fn accept(&self, connection: Connection) -> BoxFuture<'static, anyhow::Result<()>> {
+        let mut current = self.current.clone();
+        Box::pin(async move {
+            let (mut send, mut recv) = connection.accept_bi().await?;
+            // 请求内容为接收端已有的版本号，等到有更新的版本再响应
+            let request = recv.read_to_end(8).await?;
+            let after = u64::from_le_bytes(request.try_into().map_err(|_| anyhow::anyhow!("invalid request"))?);
+            let latest = tokio::select! {
+                latest = current.wait_for(|latest| latest.as_ref().is_some_and(|latest| latest.record.seq > after)) => latest?.clone(),
+                // 接收端不再等待
+                _ = connection.closed() => return Ok(()),
+            };
+            let latest = latest.context("nothing has been published yet")?;
+            send.write_all(&serde_json::to_vec(&latest)?).await?;
+            send.finish()?;
+            // 等待对端读取完成后关闭连接
+            connection.closed().await;
+            Ok(())
+        })
+    }
+}
+
+/// 向发送端获取版本号大于 after 的最新集合，并校验签名
+/// 还没有更新的版本时等待发送端发布
+pub async fn fetch_latest(endpoint: &Endpoint, addr: NodeAddr, after: u64) -> anyhow::Result<LatestRecord> {
+    let node_id = addr.node_id;
+    let connection = endpoint.connect(addr, ALPN).await?;
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send.write_all(&after.to_le_bytes()).await?;
+    send.finish()?;
+    let response = recv.read_to_end(MAX_RESPONSE_SIZE).await?;
+    connection.close(0u32.into(), b"done");
+    let latest: SignedLatest = serde_json::from_slice(&response)
+        .context("sender has not published anything yet")?;
+    let record = latest.verify(&node_id)?;
+    anyhow::ensure!(record.seq > after, "sender returned an outdated version #{}", record.seq);
+    Ok(record.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::{protocol::Router, RelayMode};
+
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let secret_key = SecretKey::generate(rand::rngs::OsRng);
+        let record = LatestRecord {
+            hash: Hash::new(b"collection"),
+            seq: 3,
+            timestamp: 1_700_000_000,
+        };
+        let signed = record.clone().sign(&secret_key);
+        assert_eq!(signed.verify(&secret_key.public()).unwrap(), &record);
+
+        // 其他节点的签名
+        let other = SecretKey::generate(rand::rngs::OsRng);
+        assert!(signed.verify(&other.public()).is_err());
+
+        // 签名后修改了记录
+        let mut tampered = signed.clone();
+        tampered.record.seq = 4;
+        assert!(tampered.verify(&secret_key.public()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_publish_fetch() -> anyhow::Result<()> {
+        let endpoint = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await?;
+        let (mut publisher, protocol) = LatestPublisher::new(endpoint.secret_key().clone());
+        let router = Router::builder(endpoint).accept(ALPN, protocol).spawn();
+        let addr = router.endpoint().node_addr().await?;
+        let client = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await?;
+
+        let first = publisher.publish(Hash::new(b"first"));
+        assert_eq!(fetch_latest(&client, addr.clone(), 0).await?, first);
+
+        // 已经是最新版本时等待下一次发布
+        let next = tokio::spawn({
+            let client = client.clone();
+            let addr = addr.clone();
+            async move { fetch_latest(&client, addr, first.seq).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(!next.is_finished());
+        let second = publisher.publish(Hash::new(b"second"));
+        let fetched = next.await??;
+        assert_eq!(fetched, second);
+        assert_eq!(fetched.seq, first.seq + 1);
+
+        router.shutdown().await?;
+        Ok(())
+    }
+}
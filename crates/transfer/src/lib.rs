@@ -1,2 +1,18 @@
+pub mod access;
+pub mod backend;
 pub mod cli;
-pub mod transfer;
\ No newline at end of file
+pub mod collection;
+pub mod compress;
+pub mod daemon;
+pub mod encrypt;
+pub mod fetch;
+pub mod inbox;
+pub mod latest;
+pub mod limit;
+pub mod meta;
+pub mod push;
+pub mod signature;
+pub mod ticket;
+pub mod transfer;
+pub mod trust;
+pub mod watch;
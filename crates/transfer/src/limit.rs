@@ -0,0 +1,244 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use iroh::NodeId;
+use iroh_blobs::{
+    store::{
+        bao_tree::io::fsm::{AsyncSliceReader, Outboard},
+        BaoBlobSize, MapEntry,
+    },
+    Hash,
+};
+use tokio::time::Instant;
+
+/// 限速器，多个传输共享同一个限速器时共享速率
+/// 记录下一次允许发送的时间，每次传输后按字节数向后推
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    /// 每秒字节数
+    rate: u64,
+    next: Arc<Mutex<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            next: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// 记录传输了 bytes 字节，返回需要等待的时长
+    fn reserve(&self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let mut next = self.next.lock().unwrap();
+        // 空闲时不积累额度，避免之后突发
+        let start = (*next).max(now);
+        *next = start + Duration::from_secs_f64(bytes as f64 / self.rate as f64);
+        *next - now
+    }
+
+    /// 传输了 bytes 字节后等待
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// 同时满足多个限速器时需要等待的时长
+fn reserve_all(limiters: &[RateLimiter], bytes: u64) -> Duration {
+    limiters
+        .iter()
+        .map(|limiter| limiter.reserve(bytes))
+        .max()
+        .unwrap_or_default()
+}
+
+/// 每个节点的限速器，以及正在使用它的连接数
+type PeerTable = Arc<Mutex<HashMap<NodeId, (RateLimiter, usize)>>>;
+
+/// 发送端的限速：所有接收端共享的总速率，以及每个接收端单独的速率
+#[derive(Debug, Clone, Default)]
+pub struct UploadLimits {
+    total: Option<RateLimiter>,
+    per_peer: Option<u64>,
+    peers: PeerTable,
+}
+
+impl UploadLimits {
+    pub fn new(total: Option<u64>, per_peer: Option<u64>) -> Self {
+        Self {
+            total: total.map(RateLimiter::new),
+            per_peer,
+            peers: Default::default(),
+        }
+    }
+
+    /// 向该节点发送数据时需要满足的限速器
+    /// 同一节点的多个连接共享速率，最后一个连接结束后释放该节点的限速器
+    pub fn for_peer(&self, node_id: NodeId) -> PeerLimiters {
+        let peer = self.per_peer.map(|rate| {
+            let mut peers = self.peers.lock().unwrap();
+            let (limiter, connections) = peers
+                .entry(node_id)
+                .or_insert_with(|| (RateLimiter::new(rate), 0));
+            *connections += 1;
+            limiter.clone()
+        });
+        PeerLimiters {
+            release: peer.is_some().then(|| (node_id, self.peers.clone())),
+            limiters: self.total.iter().cloned().chain(peer).collect(),
+        }
+    }
+}
+
+/// 一个连接使用的限速器，连接结束时丢弃
+#[derive(Debug)]
+pub struct PeerLimiters {
+    limiters: Arc<[RateLimiter]>,
+    release: Option<(NodeId, PeerTable)>,
+}
+
+impl PeerLimiters {
+    pub fn limiters(&self) -> Arc<[RateLimiter]> {
+        self.limiters.clone()
+    }
+}
+
+impl Drop for PeerLimiters {
+    fn drop(&mut self) {
+        let Some((node_id, peers)) = &self.release else {
+            return;
+        };
+        let mut peers = peers.lock().unwrap();
+        if let Some((_, connections)) = peers.get_mut(node_id) {
+            *connections -= 1;
+            if *connections == 0 {
+                peers.remove(node_id);
+            }
+        }
+    }
+}
+
+/// 读取时限速的条目，用于限制发送端提供数据的速度
+#[derive(Debug, Clone)]
+pub struct ThrottledEntry<E> {
+    inner: E,
+    limiters: Arc<[RateLimiter]>,
+}
+
+impl<E> ThrottledEntry<E> {
+    pub fn new(inner: E, limiters: Arc<[RateLimiter]>) -> Self {
+        Self { inner, limiters }
+    }
+}
+
+impl<E: MapEntry> MapEntry for ThrottledEntry<E> {
+    fn hash(&self) -> Hash {
+        self.inner.hash()
+    }
+
+    fn size(&self) -> BaoBlobSize {
+        self.inner.size()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.inner.is_complete()
+    }
+
+    fn outboard(&self) -> impl Future<Output = std::io::Result<impl Outboard>> + Send {
+        self.inner.outboard()
+    }
+
+    fn data_reader(&self) -> impl Future<Output = std::io::Result<impl AsyncSliceReader>> + Send {
+        let reader = self.inner.data_reader();
+        let limiters = self.limiters.clone();
+        async move {
+            Ok(ThrottledReader {
+                inner: reader.await?,
+                limiters,
+            })
+        }
+    }
+}
+
+/// 读取数据后按读取的字节数等待
+#[derive(Debug)]
+struct ThrottledReader<R> {
+    inner: R,
+    limiters: Arc<[RateLimiter]>,
+}
+
+impl<R: AsyncSliceReader> AsyncSliceReader for ThrottledReader<R> {
+    async fn read_at(&mut self, offset: u64, len: usize) -> std::io::Result<Bytes> {
+        let bytes = self.inner.read_at(offset, len).await?;
+        let wait = reserve_all(&self.limiters, bytes.len() as u64);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        Ok(bytes)
+    }
+
+    async fn size(&mut self) -> std::io::Result<u64> {
+        self.inner.size().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limiter() {
+        let limiter = RateLimiter::new(1000);
+        assert_eq!(limiter.reserve(500), Duration::from_millis(500));
+        // 共享速率，排在前一次之后
+        assert_eq!(limiter.clone().reserve(500), Duration::from_secs(1));
+
+        let slow = RateLimiter::new(100);
+        let wait = reserve_all(&[limiter, slow], 100);
+        assert_eq!(wait, Duration::from_millis(1100));
+        assert_eq!(reserve_all(&[], 100), Duration::ZERO);
+
+        // 空闲后不积累额度
+        let idle = RateLimiter::new(1000);
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert_eq!(idle.reserve(100), Duration::from_millis(100));
+
+        // 等待到允许的时间
+        let started = Instant::now();
+        idle.acquire(900).await;
+        assert_eq!(started.elapsed(), Duration::from_millis(1000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_upload_limits() {
+        let alice = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let bob = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        assert!(UploadLimits::default().for_peer(alice).limiters().is_empty());
+
+        let limits = UploadLimits::new(Some(1000), Some(100));
+        let first = limits.for_peer(alice);
+        assert_eq!(first.limiters().len(), 2);
+        // 同一节点共享速率，不同节点互不影响
+        first.limiters()[1].reserve(100);
+        let second = limits.for_peer(alice);
+        assert_eq!(second.limiters()[1].reserve(100), Duration::from_secs(2));
+        assert_eq!(limits.for_peer(bob).limiters()[1].reserve(100), Duration::from_secs(1));
+
+        // bob 的连接已经结束，alice 的最后一个连接结束后也释放
+        assert_eq!(limits.peers.lock().unwrap().len(), 1);
+        drop(first);
+        assert_eq!(limits.peers.lock().unwrap().len(), 1);
+        drop(second);
+        assert!(limits.peers.lock().unwrap().is_empty());
+    }
+}
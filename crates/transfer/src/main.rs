@@ -1,6 +1,8 @@
 use anyhow::Result;
 use clap::Parser;
-use transfer::{cli::{Args, Commands}, transfer::{receive_file, send_file}};
+use std::path::PathBuf;
+
+use transfer::{backend::run_quinn, cli::{Args, BackendKind, Commands}, daemon::{daemon_dir, load_identity, run_daemon, DaemonClient}, limit::UploadLimits, meta::ShareMeta, push::listen, transfer::{inspect, receive_file, send_file}, trust::{run_trust, TrustStore}, watch::watch_and_send};
 use tracing_subscriber::{EnvFilter};
 
 #[tokio::main]
@@ -21,8 +23,16 @@ async fn main() -> Result<()> {
     };
 
     let res = match args.command {
-        Commands::Send(args) => send_file(args).await,
-        Commands::Receive(args) => receive_file(args).await,
+        Commands::Daemon(daemon_args) => run_daemon(args.daemon_dir, daemon_args).await,
+        // 其他后端不经过守护进程
+        command if args.backend == BackendKind::Quinn => run_quinn(command).await,
+        command if DaemonClient::supports(&command) => match DaemonClient::connect(args.daemon_dir.clone()).await {
+            // 守护进程运行时由守护进程执行命令
+            Ok(Some(client)) => client.run(command).await,
+            Ok(None) => run_command(command, args.daemon_dir).await,
+            Err(e) => Err(e),
+        },
+        command => run_command(command, args.daemon_dir).await,
     };
 
     if let Err(e) = & res {
@@ -35,4 +45,35 @@ async fn main() -> Result<()> {
         
     }
 
-}
\ No newline at end of file
+}
+
+/// 没有守护进程时直接执行命令
+/// 数据目录中保存签名用的私钥和信任列表
+async fn run_command(command: Commands, dir: Option<PathBuf>) -> Result<()> {
+    let dir = daemon_dir(dir)?;
+    match command {
+        Commands::Send(args) => {
+            let signer = load_identity(&dir).await?;
+            match args.watch {
+                Some(watch) => {
+                    let meta = ShareMeta::new(args.message, args.labels, Vec::new());
+                    let limits = UploadLimits::new(args.limit_upload, args.limit_per_peer);
+                    watch_and_send(watch, meta, signer, limits).await
+                }
+                None => send_file(args, signer).await,
+            }
+        }
+        Commands::Receive(args) => receive_file(args, TrustStore::load(&dir).await?, load_identity(&dir).await?).await,
+        Commands::Inspect(args) => inspect(args, TrustStore::load(&dir).await?).await,
+        Commands::Listen(args) => listen(args, TrustStore::load(&dir).await?, load_identity(&dir).await?).await,
+        Commands::Id => {
+            println!("{}", load_identity(&dir).await?.public());
+            Ok(())
+        }
+        Commands::Trust(args) => run_trust(&dir, args).await,
+        Commands::List | Commands::Unshare(_) => {
+            anyhow::bail!("no daemon is running, start one with `transfer daemon`")
+        }
+        Commands::Daemon(_) => unreachable!("handled before"),
+    }
+}
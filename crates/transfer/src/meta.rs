@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use console::style;
+use data_encoding::HEXLOWER;
+use iroh::{endpoint::Connection, NodeId};
+use iroh_blobs::{store::Store, BlobFormat, TempTag};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    collection::{load_blob, read_blob, StoredCollection},
+    signature::SIGNATURE_NAME,
+};
+
+/// 集合中保存分享信息的条目名，导出时跳过
+pub const META_NAME: &str = ".transfer-meta.json";
+
+/// 是否是内部使用的条目（分享信息、签名），导出和展示文件时跳过
+pub fn is_internal(name: &str) -> bool {
+    name == META_NAME || name == SIGNATURE_NAME
+}
+
+/// 分享信息的最大长度
+pub(crate) const MAX_META_SIZE: u64 = 1024 * 1024;
+
+/// 分享附带的消息和标签
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// 文件内容只有这些节点可以解密
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<NodeId>,
+    /// 使用 zstd 压缩存储的文件，导出时解压
+    #[serde(default, skip_serializing_if = "CompressedFiles::is_empty")]
+    pub compressed: CompressedFiles,
+}
+
+/// 使用 zstd 压缩存储的文件，按文件在集合中的位置（不计内部条目）每个文件记一位
+/// 不重复保存文件名，几十万个文件的集合也远小于 [`MAX_META_SIZE`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompressedFiles(Vec<u8>);
+
+impl CompressedFiles {
+    /// 标记第 index 个文件
+    pub fn insert(&mut self, index: u64) {
+        let byte = (index / 8) as usize;
+        if self.0.len() <= byte {
+            self.0.resize(byte + 1, 0);
+        }
+        self.0[byte] |= 1 << (index % 8);
+    }
+
+    /// 第 index 个文件是否压缩
+    pub fn contains(&self, index: u64) -> bool {
+        self.0
+            .get((index / 8) as usize)
+            .is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+    }
+
+    /// 压缩的文件数
+    pub fn len(&self) -> u64 {
+        self.0.iter().map(|byte| byte.count_ones() as u64).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// 以十六进制字符串保存
+impl Serialize for CompressedFiles {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&HEXLOWER.encode(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for CompressedFiles {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        HEXLOWER.decode(hex.as_bytes()).map(Self).map_err(D::Error::custom)
+    }
+}
+
+impl ShareMeta {
+    pub fn new(message: Option<String>, labels: Vec<(String, String)>, recipients: Vec<NodeId>) -> Self {
+        Self {
+            message,
+            labels: labels.into_iter().collect(),
+            recipients,
+            compressed: CompressedFiles::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.message.is_none() && self.labels.is_empty() && self.recipients.is_empty() && self.compressed.is_empty()
+    }
+
+    /// 作为单独的 blob 存入数据库
+    pub async fn store(&self, db: &impl Store) -> anyhow::Result<TempTag> {
+        let bytes = Bytes::from(serde_json::to_vec(self)?);
+        Ok(db.import_bytes(bytes, BlobFormat::Raw).await?)
+    }
+
+    /// 展示分享信息
+    pub fn print(&self) {
+        if let Some(message) = &self.message {
+            eprintln!("{} {message}", style("message:").bold());
+        }
+        for (key, value) in &self.labels {
+            eprintln!("{} {key}={value}", style("label:").bold());
+        }
+        for recipient in &self.recipients {
+            eprintln!("{} {recipient}", style("encrypted for:").bold());
+        }
+        if !self.compressed.is_empty() {
+            eprintln!("{} {} files", style("compressed:").bold(), self.compressed.len());
+        }
+    }
+}
+
+/// 获取集合附带的分享信息，写入集合所在的存储，下载时不再重复获取
+pub async fn fetch_meta(connection: &Connection, collection: &StoredCollection) -> anyhow::Result<Option<ShareMeta>> {
+    let Some(hash) = collection.find_internal(META_NAME).await? else {
+        return Ok(None);
+    };
+    let bytes = load_blob(connection, collection.db(), hash, MAX_META_SIZE).await?;
+    Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+/// 读取存储中已有集合附带的分享信息，大小限制和 fetch_meta 相同
+pub async fn read_meta(collection: &StoredCollection) -> anyhow::Result<Option<ShareMeta>> {
+    let Some(hash) = collection.find_internal(META_NAME).await? else {
+        return Ok(None);
+    };
+    let bytes = read_blob(collection.db(), hash, MAX_META_SIZE).await?;
+    Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_compressed_files() -> anyhow::Result<()> {
+        // 一百万个文件，每隔一个压缩
+        let count = 1_000_000;
+        let mut meta = ShareMeta::default();
+        for index in (0..count).step_by(2) {
+            meta.compressed.insert(index);
+        }
+        assert_eq!(meta.compressed.len(), count / 2);
+        assert!(meta.compressed.contains(0) && !meta.compressed.contains(1));
+        assert!(!meta.compressed.contains(count));
+
+        let bytes = serde_json::to_vec(&meta)?;
+        assert!((bytes.len() as u64) < MAX_META_SIZE, "{} bytes", bytes.len());
+        let parsed: ShareMeta = serde_json::from_slice(&bytes)?;
+        assert_eq!(parsed, meta);
+
+        // 没有压缩的文件时不写入
+        assert_eq!(serde_json::to_string(&ShareMeta::default())?, "{}");
+        assert!(serde_json::from_str::<ShareMeta>(r#"{"compressed":"xyz"}"#).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_meta_too_large() -> anyhow::Result<()> {
+        use anyhow::Context;
+        use iroh_blobs::format::collection::Collection;
+
+        let (db, dir) = crate::collection::tests::temp_store("meta-large").await?;
+        let res = async {
+            let bytes = vec![b' '; MAX_META_SIZE as usize + 1];
+            let meta = db.import_bytes(bytes.into(), BlobFormat::Raw).await?;
+            let collection = [(META_NAME, *meta.hash())].into_iter().collect::<Collection>();
+            let tag = collection.store(&db).await?;
+            let stored = StoredCollection::open(db.clone(), *tag.hash()).await?;
+            let err = read_meta(&stored).await.err().context("oversized meta was accepted")?;
+            assert!(err.to_string().contains("too large"), "{err:#}");
+            drop((meta, tag));
+            anyhow::Ok(())
+        }
+        .await;
+        drop(db);
+        tokio::fs::remove_dir_all(&dir).await?;
+        res
+    }
+}
@@ -0,0 +1,428 @@
+use std::{collections::BTreeMap, path::Path, str::FromStr, time::Duration};
+
+use anyhow::Context;
+use console::style;
+use futures::{future::BoxFuture, StreamExt};
+use indicatif::HumanBytes;
+use iroh::{
+    endpoint::{Connection, RecvStream, SendStream},
+    protocol::{ProtocolHandler, Router},
+    Endpoint, NodeAddr, NodeId, SecretKey,
+};
+use iroh_base::ticket::NodeTicket;
+use iroh_blobs::{format::collection::Collection, ticket::BlobTicket, HashAndFormat};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::{
+    cli::ListenArgs,
+    collection::{CollectionSummary, StoredCollection},
+    fetch::FetchOptions,
+    inbox::{print_offer, prompt_accept, AcceptRules, Verdict},
+    limit::RateLimiter,
+    meta::{is_internal, ShareMeta},
+    signature::SignaturePolicy,
+    transfer::{create_endpoint_with_key, device_name, list},
+    trust::TrustStore,
+};
+
+/// 推送协议
+pub const ALPN: &[u8] = b"transfer/push/0";
+
+/// 单条消息的最大长度
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// 按设备名查找节点的超时时间
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 发送端提供的集合
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Offer {
+    /// 接收端用来下载的分享码
+    pub ticket: String,
+    /// 发送端设备名
+    pub device: String,
+    /// 集合中的文件
+    pub files: Vec<String>,
+    /// 文件总大小
+    pub size: u64,
+    /// 附带给接收端的消息
+    #[serde(default)]
+    pub message: Option<String>,
+    /// 分享的标签
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Offer {
+    pub fn new(ticket: &BlobTicket, collection: &Collection, size: u64, meta: &ShareMeta) -> Self {
+        Self {
+            ticket: ticket.to_string(),
+            device: device_name(),
+            files: collection
+                .iter()
+                .filter(|(name, _)| !is_internal(name))
+                .map(|(name, _)| name.clone())
+                .collect(),
+            size,
+            message: meta.message.clone(),
+            labels: meta.labels.clone(),
+        }
+    }
+}
+
+/// 接收端的回复
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Answer {
+    /// 接受，即将开始下载
+    Accepted,
+    /// 拒绝
+    Rejected(String),
+    /// 下载完成
+    Done,
+    /// 下载失败
+    Failed(String),
+}
+
+/// 写入一条长度前缀的 JSON 消息
+pub(crate) async fn write_message(send: &mut SendStream, message: &impl Serialize) -> anyhow::Result<()> {
+    let bytes = serde_json::to_vec(message)?;
+    send.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+    send.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// 读取一条长度前缀的 JSON 消息
+pub(crate) async fn read_message<T: DeserializeOwned>(recv: &mut RecvStream) -> anyhow::Result<T> {
+    let mut len = [0u8; 4];
+    recv.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    anyhow::ensure!(len <= MAX_MESSAGE_SIZE, "message too large: {len} bytes");
+    let mut bytes = vec![0u8; len];
+    recv.read_exact(&mut bytes).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// 解析推送目标：节点分享码、节点 id 或局域网内的设备名
+pub async fn resolve_peer(endpoint: &Endpoint, to: &str) -> anyhow::Result<NodeAddr> {
+    if let Ok(ticket) = NodeTicket::from_str(to) {
+        return Ok(ticket.node_addr().clone());
+    }
+    if let Ok(node_id) = NodeId::from_str(to) {
+        // 地址通过 mDNS 解析
+        return Ok(NodeAddr::new(node_id));
+    }
+    let mut discovered = endpoint.discovery_stream();
+    let found = tokio::time::timeout(DISCOVERY_TIMEOUT, async {
+        while let Some(item) = discovered.next().await {
+            let Ok(item) = item else { continue };
+            if item.user_data().is_some_and(|name| name.as_ref() == to) {
+                return Some(item.node_id());
+            }
+        }
+        None
+    })
+    .await
+    .ok()
+    .flatten();
+    let node_id = found.with_context(|| format!("can not find peer named {to} on the local network"))?;
+    Ok(NodeAddr::new(node_id))
+}
+
+/// 向接收端推送集合，等待接收端下载完成
+pub async fn push_offer(endpoint: &Endpoint, peer: NodeAddr, offer: Offer) -> anyhow::Result<()> {
+    let node_id = peer.node_id;
+    println!("offering to {} ...", node_id.fmt_short());
+    let connection = endpoint.connect(peer, ALPN).await?;
+    let (mut send, mut recv) = connection.open_bi().await?;
+    write_message(&mut send, &offer).await?;
+    send.finish()?;
+    loop {
+        match read_message::<Answer>(&mut recv).await? {
+            Answer::Accepted => println!("{} accepted, downloading ...", node_id.fmt_short()),
+            Answer::Rejected(reason) => anyhow::bail!("offer rejected: {reason}"),
+            Answer::Done => break,
+            Answer::Failed(reason) => anyhow::bail!("receiver failed to download: {reason}"),
+        }
+    }
+    connection.close(0u32.into(), b"done");
+    println!("{} received {} files, {}", node_id.fmt_short(), offer.files.len(), HumanBytes(offer.size));
+    Ok(())
+}
+
+/// 收到的推送
+#[derive(Debug)]
+pub struct IncomingOffer {
+    pub sender: NodeId,
+    pub offer: Offer,
+    reply: mpsc::Sender<Answer>,
+}
+
+impl IncomingOffer {
+    async fn answer(&self, answer: Answer) {
+        // 发送端断开时忽略
+        self.reply.send(answer).await.ok();
+    }
+}
+
+/// 接收推送的协议处理器，收到的推送交给 listen 循环处理
+#[derive(Debug, Clone)]
+pub struct PushProtocol {
+    offers: mpsc::Sender<IncomingOffer>,
+}
+
+impl PushProtocol {
+    pub fn new() -> (Self, mpsc::Receiver<IncomingOffer>) {
+        let (offers, recv) = mpsc::channel(16);
+        (Self { offers }, recv)
+    }
+}
+
+impl ProtocolHandler for PushProtocol {
+    fn accept(&self, connection: Connection) -> BoxFuture<'static, anyhow::Result<()>> {
+        let offers = self.offers.clone();
+        Box::pin(async move {
+            let sender = connection.remote_node_id()?;
+            let (mut send, mut recv) = connection.accept_bi().await?;
+            let offer: Offer = read_message(&mut recv).await?;
+            let (reply, mut answers) = mpsc::channel(4);
+            offers
+                .send(IncomingOffer { sender, offer, reply })
+                .await
+                .context("receiver is shutting down")?;
+            while let Some(answer) = answers.recv().await {
+                write_message(&mut send, &answer).await?;
+                if !matches!(answer, Answer::Accepted) {
+                    break;
+                }
+            }
+            send.finish()?;
+            connection.closed().await;
+            Ok(())
+        })
+    }
+}
+
+/// 下载推送的集合到 inbox 目录
+async fn receive_offer(
+    endpoint: &Endpoint,
+    incoming: &IncomingOffer,
+    inbox: &Path,
+    policy: &SignaturePolicy,
+    identity: &SecretKey,
+    limit: Option<&RateLimiter>,
+) -> anyhow::Result<()> {
+    let ticket = BlobTicket::from_str(&incoming.offer.ticket)?;
+    // 只接受发送端自己提供的数据
+    anyhow::ensure!(
+        ticket.node_addr().node_id == incoming.sender,
+        "offer ticket does not belong to the sender"
+    );
+    let hash_and_format = HashAndFormat {
+        hash: ticket.hash(),
+        format: ticket.format(),
+    };
+    let options = FetchOptions {
+        limit,
+        ..Default::default()
+    };
+    let providers = [ticket.node_addr().clone()];
+    let listed = list(endpoint, &providers, hash_and_format, inbox, policy, identity, options.collection).await?;
+    // 接受的是推送中的文件列表，下载前确认集合与推送一致，否则规则和用户的确认都不可信
+    let checked = match listed.collection() {
+        Some(collection) => check_offer(collection, listed.summary(), &incoming.offer).await,
+        None => Err(anyhow::anyhow!("offer does not point to a collection")),
+    };
+    if let Err(e) = checked {
+        listed.discard().await?;
+        return Err(e);
+    }
+    listed.download(endpoint, &providers, inbox, identity, options).await?.cleanup().await
+}
+
+/// 接受推送并下载到 inbox 目录，把结果告诉发送端
+async fn accept_offer(
+    endpoint: &Endpoint,
+    incoming: &IncomingOffer,
+    inbox: &Path,
+    policy: &SignaturePolicy,
+    identity: &SecretKey,
+    limit: Option<&RateLimiter>,
+) {
+    incoming.answer(Answer::Accepted).await;
+    match receive_offer(endpoint, incoming, inbox, policy, identity, limit).await {
+        Ok(()) => incoming.answer(Answer::Done).await,
+        Err(e) => {
+            warn!("failed to receive from {}: {e:?}", incoming.sender);
+            incoming.answer(Answer::Failed(format!("{e:#}"))).await;
+        }
+    }
+}
+
+/// 检查获取到的集合与推送是否一致：文件名、文件数和总大小
+async fn check_offer(collection: &StoredCollection, summary: &CollectionSummary, offer: &Offer) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        summary.size == offer.size,
+        "collection has {} but the offer says {}",
+        HumanBytes(summary.size),
+        HumanBytes(offer.size)
+    );
+    anyhow::ensure!(
+        summary.files == offer.files.len() as u64,
+        "collection has {} files but the offer lists {}",
+        summary.files,
+        offer.files.len()
+    );
+    let mut offered = offer.files.iter();
+    let mut entries = collection.entries().await?;
+    while let Some((name, _)) = entries.next().await? {
+        if !is_internal(&name) {
+            anyhow::ensure!(offered.next() == Some(&name), "{name} is not in the offer");
+        }
+    }
+    Ok(())
+}
+
+/// 等待其他节点推送文件
+pub async fn listen(args: ListenArgs, trust: TrustStore, identity: SecretKey) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(&args.inbox).await?;
+    let inbox = args.inbox.canonicalize()?;
+    // 使用节点身份监听，推送方看到的节点 id 就是加密分享使用的接收者
+    let endpoint = create_endpoint_with_key(identity.clone()).await?;
+    let (push, mut offers) = PushProtocol::new();
+    let router = Router::builder(endpoint).accept(ALPN, push).spawn();
+    let endpoint = router.endpoint();
+
+    // 自动接受和签名检查使用同一个信任列表
+    let rules = AcceptRules::from_args(&args, &trust);
+    // 所有推送共享下载速率
+    let limit = args.limit_download.map(RateLimiter::new);
+    let policy = SignaturePolicy {
+        trust,
+        ..Default::default()
+    };
+    let ticket = NodeTicket::new(endpoint.node_addr().await?);
+    println!("listening as {}, inbox: {}", device_name(), inbox.display());
+    println!("node id: {}", endpoint.node_id());
+    println!("to send files here, use");
+    println!("transfer send -p [path] --to {}", ticket);
+
+    loop {
+        let incoming = tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            incoming = offers.recv() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+        };
+        print_offer(&incoming.sender, &incoming.offer, offers.len());
+        let accepted = match rules.check(&incoming.sender, &incoming.offer) {
+            Verdict::Accept => {
+                println!("    {}", style("auto-accepted").green());
+                true
+            }
+            Verdict::Ask(reason) if args.unattended => {
+                println!("    {} ({reason})", style("rejected").red());
+                false
+            }
+            Verdict::Ask(reason) => prompt_accept(&reason).await,
+        };
+        if !accepted {
+            incoming.answer(Answer::Rejected("receiver declined".to_string())).await;
+            continue;
+        }
+        accept_offer(endpoint, &incoming, &inbox, &policy, &identity, limit.as_ref()).await;
+    }
+
+    tokio::time::timeout(Duration::from_secs(2), router.shutdown()).await??;
+    println!("shutting down");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use iroh::RelayMode;
+    use iroh_blobs::{net_protocol::Blobs, store::Store, BlobFormat, Hash};
+
+    use super::*;
+    use crate::{collection::tests::temp_store, meta::META_NAME};
+
+    #[tokio::test]
+    async fn test_check_offer() -> anyhow::Result<()> {
+        let (db, dir) = temp_store("offer").await?;
+        let collection = [(META_NAME, "meta"), ("a.pdf", "a"), ("b.pdf", "b")]
+            .into_iter()
+            .map(|(name, content)| (name.to_string(), Hash::new(content)))
+            .collect::<Collection>();
+        let tag = collection.clone().store(&db).await?;
+        let stored = StoredCollection::open(db.clone(), *tag.hash()).await?;
+        let summary = CollectionSummary {
+            entries: 3,
+            files: 2,
+            size: 200,
+            total: 210,
+        };
+        let offer = |files: &[&str], size| Offer {
+            ticket: String::new(),
+            device: "test".to_string(),
+            files: files.iter().map(|name| name.to_string()).collect(),
+            size,
+            message: None,
+            labels: Default::default(),
+        };
+        let res = async {
+            check_offer(&stored, &summary, &offer(&["a.pdf", "b.pdf"], 200)).await?;
+            // 推送声明的大小、文件数或文件名与集合不一致时拒绝下载
+            assert!(check_offer(&stored, &summary, &offer(&["a.pdf", "b.pdf"], 100)).await.is_err());
+            assert!(check_offer(&stored, &summary, &offer(&["a.pdf"], 200)).await.is_err());
+            assert!(check_offer(&stored, &summary, &offer(&["a.pdf", "b.exe"], 200)).await.is_err());
+            anyhow::Ok(())
+        }
+        .await;
+        drop((tag, stored, db));
+        tokio::fs::remove_dir_all(dir).await?;
+        res
+    }
+
+    #[tokio::test]
+    async fn test_push_path_traversal() -> anyhow::Result<()> {
+        // 发送端提供一个文件名指向 inbox 外面的集合
+        let sender = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await?;
+        let blobs = Blobs::memory().build(&sender);
+        let content = "escaped\n";
+        let tag = blobs.store().import_bytes(content.into(), BlobFormat::Raw).await?;
+        let collection = [("../escape.txt".to_string(), *tag.hash())].into_iter().collect::<Collection>();
+        let root = collection.clone().store(blobs.store()).await?;
+        let sender = Router::builder(sender).accept(iroh_blobs::ALPN, blobs).spawn();
+        let ticket = BlobTicket::new(sender.endpoint().node_addr().await?, *root.hash(), BlobFormat::HashSeq)?;
+        let offer = Offer::new(&ticket, &collection, content.len() as u64, &ShareMeta::default());
+
+        let dir = std::env::temp_dir().join(format!("transfer-push-{}-{:x}", std::process::id(), rand::random::<u64>()));
+        let inbox = dir.join("inbox");
+        tokio::fs::create_dir_all(&inbox).await?;
+        let receiver = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await?;
+        let (push, mut offers) = PushProtocol::new();
+        let receiver = Router::builder(receiver).accept(ALPN, push).spawn();
+        let listener = async {
+            // 规则自动接受的推送同样要检查文件名
+            let incoming = offers.recv().await.context("no offer")?;
+            let identity = SecretKey::generate(rand::rngs::OsRng);
+            accept_offer(receiver.endpoint(), &incoming, &inbox, &SignaturePolicy::default(), &identity, None).await;
+            anyhow::Ok(())
+        };
+        let addr = receiver.endpoint().node_addr().await?;
+        let (res, listened) = tokio::join!(push_offer(sender.endpoint(), addr, offer), listener);
+        listened?;
+        let escaped = dir.join("escape.txt").exists();
+        drop((tag, root));
+        sender.shutdown().await?;
+        receiver.shutdown().await?;
+        tokio::fs::remove_dir_all(&dir).await?;
+        let err = res.err().context("push with a .. entry was accepted")?;
+        assert!(err.to_string().contains("receiver failed"), "{err:#}");
+        assert!(!escaped, "file was written outside of the inbox");
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use bytes::Bytes;
+use console::style;
+use data_encoding::HEXLOWER;
+use indicatif::HumanDuration;
+use iroh::{endpoint::Connection, NodeId, SecretKey};
+use iroh_base::Signature;
+use iroh_blobs::{format::collection::Collection, store::Store, BlobFormat, Hash, TempTag};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    collection::{load_blob, varint, StoredCollection},
+    trust::TrustStore,
+};
+
+/// 集合中保存签名的条目名，导出时跳过
+pub const SIGNATURE_NAME: &str = ".transfer-signature.json";
+
+/// 签名条目的最大长度
+const MAX_SIGNATURE_SIZE: u64 = 4096;
+
+/// 签名时使用的前缀，避免与其他用途的签名混淆
+const SIGNATURE_DOMAIN: &[u8] = b"transfer-share-v0";
+
+/// 签名的内容
+/// 签名条目无法签名自身，因此签名的是去掉签名条目后的集合哈希，分享信息也包含在其中
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareRecord {
+    /// 不含签名条目的集合哈希
+    pub content: Hash,
+    /// 签名的节点
+    pub author: NodeId,
+    /// 签名时间（unix 秒）
+    pub timestamp: u64,
+}
+
+impl ShareRecord {
+    fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = SIGNATURE_DOMAIN.to_vec();
+        bytes.extend_from_slice(self.content.as_bytes());
+        bytes.extend_from_slice(self.author.as_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes
+    }
+}
+
+/// 带签名的分享记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedShare {
+    pub record: ShareRecord,
+    signature: String,
+}
+
+impl SignedShare {
+    /// 验证签名，返回签名的记录
+    fn verify(&self) -> anyhow::Result<&ShareRecord> {
+        let bytes = HEXLOWER
+            .decode(self.signature.as_bytes())
+            .context("invalid signature encoding")?;
+        let bytes: [u8; 64] = bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("invalid signature length"))?;
+        let signature = Signature::from_bytes(&bytes);
+        self.record
+            .author
+            .verify(&self.record.signing_bytes(), &signature)
+            .context("share signature is invalid")?;
+        Ok(&self.record)
+    }
+}
+
+/// 计算集合的哈希，不写入数据库
+fn collection_hash(collection: &Collection) -> Hash {
+    let root = collection.to_blobs().last().expect("collection has a root blob");
+    Hash::new(root)
+}
+
+/// 签名集合中的全部条目
+fn sign_content(collection: &Collection, secret_key: &SecretKey) -> SignedShare {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let record = ShareRecord {
+        content: collection_hash(collection),
+        author: secret_key.public(),
+        timestamp,
+    };
+    let signature = secret_key.sign(&record.signing_bytes());
+    SignedShare {
+        record,
+        signature: HEXLOWER.encode(&signature.to_bytes()),
+    }
+}
+
+/// 签名集合，签名作为第一个条目加入集合
+/// 返回新的集合以及签名 blob 的 tag，集合存入数据库前需要持有该 tag
+pub async fn sign_collection(
+    collection: Collection,
+    secret_key: &SecretKey,
+    db: &impl Store,
+) -> anyhow::Result<(Collection, TempTag)> {
+    anyhow::ensure!(
+        collection.iter().all(|(name, _)| name != SIGNATURE_NAME),
+        "{} is a reserved name",
+        SIGNATURE_NAME
+    );
+    let signed = sign_content(&collection, secret_key);
+    let bytes = Bytes::from(serde_json::to_vec(&signed)?);
+    let tag = db.import_bytes(bytes, BlobFormat::Raw).await?;
+    let collection = std::iter::once((SIGNATURE_NAME.to_string(), *tag.hash()))
+        .chain(collection.iter().cloned())
+        .collect();
+    Ok((collection, tag))
+}
+
+/// 集合的签名状态
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Authorship {
+    /// 没有签名
+    Unsigned,
+    /// 签名有效
+    Signed(ShareRecord),
+}
+
+/// 获取并验证集合的签名，签名无效时返回错误
+/// 签名由 sign_collection 加入，是集合的第一个条目
+pub async fn verify_collection(connection: &Connection, collection: &StoredCollection) -> anyhow::Result<Authorship> {
+    let hash = match collection.entries().await?.next().await? {
+        Some((name, hash)) if name == SIGNATURE_NAME => hash,
+        _ => return Ok(Authorship::Unsigned),
+    };
+    let bytes = load_blob(connection, collection.db(), hash, MAX_SIGNATURE_SIZE).await?;
+    let signed: SignedShare = serde_json::from_slice(&bytes).context("invalid share signature")?;
+    check_signature(&signed, content_hash(collection).await?).map(Authorship::Signed)
+}
+
+/// 去掉第一个条目（签名）后的集合哈希，从存储中按顺序读取条目计算
+/// 与 collection_hash 相同：先计算文件名列表的哈希，再计算哈希序列的哈希
+async fn content_hash(collection: &StoredCollection) -> anyhow::Result<Hash> {
+    let mut names = blake3::Hasher::new();
+    names.update(Collection::HEADER);
+    names.update(&varint(collection.len().saturating_sub(1)));
+    let mut entries = collection.entries().await?;
+    entries.next().await?;
+    while let Some((name, _)) = entries.next().await? {
+        names.update(&varint(name.len() as u64));
+        names.update(name.as_bytes());
+    }
+    let mut hash_seq = blake3::Hasher::new();
+    hash_seq.update(names.finalize().as_bytes());
+    let mut entries = collection.entries().await?;
+    entries.next().await?;
+    while let Some((_, hash)) = entries.next().await? {
+        hash_seq.update(hash.as_bytes());
+    }
+    Ok(Hash::from_bytes(*hash_seq.finalize().as_bytes()))
+}
+
+/// 检查签名是否覆盖集合中除签名以外的全部条目，content 是去掉签名条目后的集合哈希
+fn check_signature(signed: &SignedShare, content: Hash) -> anyhow::Result<ShareRecord> {
+    let record = signed.verify()?;
+    anyhow::ensure!(content == record.content, "share signature does not match the collection");
+    Ok(record.clone())
+}
+
+/// 接收端对签名的要求
+#[derive(Debug, Clone, Default)]
+pub struct SignaturePolicy {
+    /// 要求由指定节点签名
+    pub author: Option<NodeId>,
+    /// 要求签名的节点在信任列表中
+    pub require_trusted: bool,
+    pub trust: TrustStore,
+}
+
+impl SignaturePolicy {
+    /// 展示签名状态，不满足要求时返回错误
+    pub fn check(&self, authorship: &Authorship) -> anyhow::Result<()> {
+        self.verify(authorship)?;
+        self.print(authorship);
+        Ok(())
+    }
+
+    /// 检查签名是否满足要求，不展示
+    /// 守护进程只检查，签名状态由客户端展示
+    pub fn verify(&self, authorship: &Authorship) -> anyhow::Result<()> {
+        let record = match authorship {
+            Authorship::Signed(record) => record,
+            Authorship::Unsigned => {
+                anyhow::ensure!(
+                    self.author.is_none() && !self.require_trusted,
+                    "share is not signed, refusing to download"
+                );
+                return Ok(());
+            }
+        };
+        if let Some(author) = &self.author {
+            anyhow::ensure!(
+                &record.author == author,
+                "share is signed by {}, expected {}, refusing to download",
+                record.author,
+                author
+            );
+        }
+        anyhow::ensure!(
+            !self.require_trusted || self.author.is_some() || self.trust.name(&record.author).is_some(),
+            "share is signed by {}, which is not trusted, refusing to download",
+            record.author
+        );
+        Ok(())
+    }
+
+    /// 展示签名状态
+    pub fn print(&self, authorship: &Authorship) {
+        let record = match authorship {
+            Authorship::Signed(record) => record,
+            Authorship::Unsigned => {
+                eprintln!("{} share is not signed", style("warning:").yellow().bold());
+                return;
+            }
+        };
+        let age = SystemTime::now()
+            .duration_since(UNIX_EPOCH + Duration::from_secs(record.timestamp))
+            .unwrap_or_default();
+        match self.trust.name(&record.author) {
+            Some(name) => eprintln!(
+                "{} {} ({name}), {} ago",
+                style("signed by").green().bold(),
+                record.author.fmt_short(),
+                HumanDuration(age)
+            ),
+            None if self.author.is_some() => eprintln!(
+                "{} {}, {} ago",
+                style("signed by").green().bold(),
+                record.author.fmt_short(),
+                HumanDuration(age)
+            ),
+            None => eprintln!(
+                "{} share is signed by {}, which is not in the trust store",
+                style("warning:").yellow().bold(),
+                record.author
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sign_collection() -> anyhow::Result<()> {
+        let (db, dir) = crate::collection::tests::temp_store("sign").await?;
+        let secret_key = SecretKey::generate(rand::rngs::OsRng);
+        let content = [("a.txt".to_string(), Hash::new(b"a")), ("b.txt".to_string(), Hash::new(b"b"))]
+            .into_iter()
+            .collect::<Collection>();
+        let (collection, _signature) = sign_collection(content.clone(), &secret_key, &db).await?;
+        let tag = collection.store(&db).await?;
+        let stored = StoredCollection::open(db.clone(), *tag.hash()).await?;
+        let hash = content_hash(&stored).await?;
+        assert_eq!(hash, collection_hash(&content));
+        let signed = sign_content(&content, &secret_key);
+        let record = check_signature(&signed, hash)?;
+        assert_eq!(record.author, secret_key.public());
+
+        // 替换文件后签名不再有效
+        let tampered = content
+            .iter()
+            .map(|(name, hash)| match name.as_str() {
+                "b.txt" => (name.clone(), Hash::new(b"evil")),
+                _ => (name.clone(), *hash),
+            })
+            .collect::<Collection>();
+        assert!(check_signature(&signed, collection_hash(&tampered)).is_err());
+
+        // 冒充其他节点签名
+        let mut forged = signed.clone();
+        forged.record.author = SecretKey::generate(rand::rngs::OsRng).public();
+        assert!(check_signature(&forged, hash).is_err());
+        drop((tag, stored, db));
+        tokio::fs::remove_dir_all(dir).await?;
+        Ok(())
+    }
+}
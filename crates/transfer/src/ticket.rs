@@ -0,0 +1,206 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use iroh_base::ticket::{self, NodeTicket, Ticket};
+use iroh_blobs::ticket::BlobTicket;
+use serde::{Deserialize, Serialize};
+
+/// 接收端可以使用的分享码
+#[derive(Debug, Clone)]
+pub enum ShareTicket {
+    /// 固定指向某一个集合哈希
+    Blob(BlobTicket),
+    /// 带分享编号和有效期，发送端可以撤销
+    Revocable(RevocableTicket),
+    /// 指向发送端节点，接收时获取其最新发布的集合
+    Latest(NodeTicket),
+    /// quinn 后端的分享码，见 [`crate::backend::QuinnBackend`]
+    Quic(QuicTicket),
+}
+
+impl FromStr for ShareTicket {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(ticket) = QuicTicket::from_str(s) {
+            return Ok(ShareTicket::Quic(ticket));
+        }
+        if let Ok(ticket) = BlobTicket::from_str(s) {
+            return Ok(ShareTicket::Blob(ticket));
+        }
+        if let Ok(ticket) = RevocableTicket::from_str(s) {
+            return Ok(ShareTicket::Revocable(ticket));
+        }
+        let ticket = NodeTicket::from_str(s).map_err(|_| {
+            anyhow::anyhow!("invalid ticket: expected a blob, share or node ticket")
+        })?;
+        Ok(ShareTicket::Latest(ticket))
+    }
+}
+
+impl fmt::Display for ShareTicket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShareTicket::Blob(ticket) => ticket.fmt(f),
+            ShareTicket::Revocable(ticket) => ticket.fmt(f),
+            ShareTicket::Latest(ticket) => ticket.fmt(f),
+            ShareTicket::Quic(ticket) => ticket.fmt(f),
+        }
+    }
+}
+
+/// 带分享编号和过期时间的分享码
+/// 发送端按分享编号撤销，过期或撤销后不再提供对应的集合
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevocableTicket {
+    pub blob: BlobTicket,
+    /// 发送端的分享编号
+    pub id: u64,
+    /// 过期时间（unix 秒），None 表示不过期
+    pub expires: Option<u64>,
+}
+
+/// 分享码的序列化格式，预留版本
+#[derive(Serialize, Deserialize)]
+enum TicketWireFormat {
+    Variant0 {
+        blob: Vec<u8>,
+        id: u64,
+        expires: Option<u64>,
+    },
+}
+
+impl RevocableTicket {
+    /// 分享码是否已经过期
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| unix_now() >= expires)
+    }
+}
+
+impl Ticket for RevocableTicket {
+    const KIND: &'static str = "share";
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let data = TicketWireFormat::Variant0 {
+            blob: self.blob.to_bytes(),
+            id: self.id,
+            expires: self.expires,
+        };
+        postcard::to_stdvec(&data).expect("postcard serialization failed")
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ticket::Error> {
+        let TicketWireFormat::Variant0 { blob, id, expires } = postcard::from_bytes(bytes)?;
+        Ok(Self {
+            blob: BlobTicket::from_bytes(&blob)?,
+            id,
+            expires,
+        })
+    }
+}
+
+impl FromStr for RevocableTicket {
+    type Err = ticket::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ticket::deserialize(s)
+    }
+}
+
+impl fmt::Display for RevocableTicket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Ticket::serialize(self))
+    }
+}
+
+/// quinn 后端的分享码，包含发送端地址和它的自签名证书的指纹
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuicTicket {
+    pub addr: SocketAddr,
+    /// 证书指纹，接收端只信任这个证书
+    pub fingerprint: String,
+}
+
+impl Ticket for QuicTicket {
+    const KIND: &'static str = "quic";
+
+    fn to_bytes(&self) -> Vec<u8> {
+        postcard::to_stdvec(self).expect("postcard serialization failed")
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ticket::Error> {
+        Ok(postcard::from_bytes(bytes)?)
+    }
+}
+
+impl FromStr for QuicTicket {
+    type Err = ticket::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ticket::deserialize(s)
+    }
+}
+
+impl fmt::Display for QuicTicket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Ticket::serialize(self))
+    }
+}
+
+/// 当前 unix 时间（秒）
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use iroh::{NodeAddr, SecretKey};
+    use iroh_blobs::{BlobFormat, Hash};
+
+    #[test]
+    fn test_revocable_ticket_roundtrip() {
+        let node_id = SecretKey::generate(rand::rngs::OsRng).public();
+        let blob = BlobTicket::new(NodeAddr::new(node_id), Hash::new(b"data"), BlobFormat::HashSeq).unwrap();
+        let ticket = RevocableTicket {
+            blob,
+            id: 7,
+            expires: Some(unix_now() + 3600),
+        };
+        let text = ticket.to_string();
+        assert!(text.starts_with("share"));
+        let ShareTicket::Revocable(parsed) = ShareTicket::from_str(&text).unwrap() else {
+            panic!("expected a revocable ticket");
+        };
+        assert_eq!(parsed, ticket);
+        assert!(!parsed.is_expired());
+
+        let expired = RevocableTicket {
+            expires: Some(unix_now() - 1),
+            ..ticket
+        };
+        assert!(expired.is_expired());
+    }
+
+    #[test]
+    fn test_quic_ticket_roundtrip() {
+        let ticket = QuicTicket {
+            addr: "192.168.1.20:4433".parse().unwrap(),
+            fingerprint: "sha256:00ff".to_string(),
+        };
+        let text = ticket.to_string();
+        assert!(text.starts_with("quic"));
+        let ShareTicket::Quic(parsed) = ShareTicket::from_str(&text).unwrap() else {
+            panic!("expected a quic ticket");
+        };
+        assert_eq!(parsed, ticket);
+    }
+}
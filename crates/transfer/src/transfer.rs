@@ -1,6 +1,6 @@
-use std::{collections::BTreeMap, path::{Path, PathBuf}, time::Duration};
+use std::{collections::{BTreeMap, HashMap}, fmt::Display, path::{Path, PathBuf}, str::FromStr, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc}, time::Duration};
 
-use crate::cli::{ReceiveArgs, SendArgs};
+use crate::{access::{collection_hashes, AccessList, AccessProtocol}, compress::{compress_file, decompress_file, is_compressible}, encrypt::{decrypt_file, encrypt_file}, cli::{InspectArgs, ReceiveArgs, SendArgs}, latest::fetch_latest, limit::{RateLimiter, UploadLimits}, collection::{fetch_collection, CollectionLimits, CollectionSummary, StoredCollection}, meta::{fetch_meta, is_internal, ShareMeta, META_NAME}, fetch::{fetch_parallel, fetch_raw, FetchOptions}, push::{push_offer, resolve_peer, Offer}, signature::{sign_collection, verify_collection, Authorship, SignaturePolicy}, ticket::{unix_now, RevocableTicket, ShareTicket}, trust::TrustStore};
 use anyhow::{Context, Result};
 use arboard::Clipboard;
 use futures::StreamExt;
@@ -8,17 +8,21 @@ use console::{style, Key, Term};
 use tracing::info;
 use walkdir::WalkDir;
 use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
-use iroh::{node_info::UserData, protocol::Router, Endpoint, RelayMode, SecretKey};
-use iroh_blobs::{format::collection::Collection, get::{db::DownloadProgress, fsm::{AtBlobHeaderNextError, DecodeError}, request::get_hash_seq_and_sizes}, net_protocol::Blobs, store::{ExportMode, ImportMode, ImportProgress}, ticket::BlobTicket, util::fs::canonicalized_path_to_string, BlobFormat, HashAndFormat, TempTag};
+use iroh::{endpoint::Connection, node_info::UserData, protocol::Router, Endpoint, NodeAddr, NodeId, RelayMode, SecretKey};
+use iroh_blobs::{format::collection::Collection, get::{db::DownloadProgress, fsm::{AtBlobHeaderNextError, DecodeError}}, net_protocol::Blobs, store::{ExportMode, ImportMode, ImportProgress, ReadableStore}, ticket::BlobTicket, util::fs::canonicalized_path_to_string, BlobFormat, Hash, HashAndFormat, TempTag};
 use data_encoding::HEXLOWER;
 use rand::Rng;
 
 
 /// create a endpoint
-async fn create_endpoint() -> anyhow::Result<Endpoint> {
+pub(crate) async fn create_endpoint() -> anyhow::Result<Endpoint> {
     let mut rng = rand::rngs::OsRng;
     let secret_key: SecretKey = SecretKey::generate(&mut rng);
+    create_endpoint_with_key(secret_key).await
+}
 
+/// create a endpoint with the given node identity
+pub(crate) async fn create_endpoint_with_key(secret_key: SecretKey) -> anyhow::Result<Endpoint> {
     info!("开始创建endpoint");
     let endpoint = Endpoint::builder()
         // pplication-Layer_Protocol_Negotiation
@@ -31,7 +35,8 @@ async fn create_endpoint() -> anyhow::Result<Endpoint> {
         .bind().await?;
 
     // 获取并打印节点信息
-    let user_data = UserData::try_from(String::from("local-nodes-example"))?;
+    // 通过 mDNS 发布设备名，其他节点可以按名称查找
+    let user_data = UserData::try_from(device_name())?;
     endpoint.set_user_data_for_discovery(Some(user_data));
     let node_id = endpoint.node_id();
     let node_addr = endpoint.node_addr().await?;
@@ -40,64 +45,220 @@ async fn create_endpoint() -> anyhow::Result<Endpoint> {
 
 }
 
-/// 将文件导入数据库
-async fn import(path: PathBuf, db: impl iroh_blobs::store::Store) -> anyhow::Result<(TempTag, u64, Collection)> {
+/// 当前设备名称
+pub(crate) fn device_name() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// 递归获取路径下的全部文件
+/// 返回 (相对于父目录的名称, 文件路径)
+pub(crate) fn collect_files(path: &Path) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    walk_files(path)?.map(|file| file.map(|(name, path, _)| (name, path))).collect()
+}
+
+/// 逐个遍历路径下的全部文件，不把整个目录读入内存
+/// 产生 (相对于父目录的名称, 文件路径, 文件大小)
+fn walk_files(path: &Path) -> anyhow::Result<impl Iterator<Item = anyhow::Result<(String, PathBuf, u64)>>> {
     // 将路径转换为其​​绝对、规范化的形式​​
     let path = path.canonicalize().with_context(||
         format!("无法访问文件或目录：{}", path.display()))?;
         
     anyhow::ensure!(path.exists(), "path {} does not exist", path.display());
-    let root= path.parent().context("context get parent")?;
+    let root = path.parent().context("context get parent")?.to_path_buf();
     
     // 递归获取文件目录
-    let files = WalkDir::new(path.clone()).into_iter();
-    let data_source = files.map(|entry| {
+    let files = WalkDir::new(path).into_iter();
+    Ok(files.map(move |entry| {
         let entry = entry?;
         // 过滤掉非文件
         if !entry.file_type().is_file() {
             return anyhow::Ok(None);
         }
+        let size = entry.metadata()?.len();
         let path = entry.into_path();
         // 相对路径作为name
-        let relative = path.strip_prefix(root)?;
+        let relative = path.strip_prefix(&root)?;
         let name = canonicalized_path_to_string(relative, true)?;
-        anyhow::Ok(Some((name, path)))
-    }).filter_map(Result::transpose).collect::<anyhow::Result<Vec<_>>>()?;
+        anyhow::Ok(Some((name, path, size)))
+    }).filter_map(Result::transpose))
+}
+
+/// 遍历中已经发现的文件数和总大小，遍历结束后是最终的总量
+#[derive(Debug, Default)]
+struct WalkCount {
+    files: AtomicU64,
+    size: AtomicU64,
+    done: AtomicBool,
+}
+
+/// 导入前按需压缩、加密文件，结果写入 dst
+/// 返回是否压缩，以及是否写入了 dst（不需要转换时直接导入原文件）
+/// 阻塞执行，需要在 spawn_blocking 中调用
+fn prepare_file(src: &Path, dst: &Path, compress: bool, recipients: &[NodeId]) -> anyhow::Result<(bool, bool)> {
+    let packed = dst.with_extension("zst");
+    let compressed = compress && compress_file(src, &packed)?.is_some();
+    if recipients.is_empty() {
+        if compressed {
+            std::fs::rename(&packed, dst)?;
+        }
+        return Ok((compressed, compressed));
+    }
+    // 先压缩再加密，密文无法压缩
+    let res = encrypt_file(if compressed { &packed } else { src }, dst, recipients);
+    if compressed {
+        std::fs::remove_file(&packed)?;
+    }
+    res.map(|()| (compressed, true))
+}
+
+/// 导入的文件
+struct ImportedFile {
+    name: String,
+    tag: TempTag,
+    /// 存储的大小，压缩或加密后与原文件不同
+    size: u64,
+    original: u64,
+    compressed: bool,
+}
+
+/// 同时在遍历中等待导入的文件数，遍历比导入快时在这里等待，不会把整个目录读入内存
+const WALK_BUFFER: usize = 1024;
+
+/// 跟随新版本时，无法连接发送端后等待多久重试
+const FOLLOW_RETRY: Duration = Duration::from_secs(5);
+
+/// 将文件导入数据库，集合使用 signer 签名
+/// 边遍历边导入，内存只随集合本身（文件名和哈希）增长
+/// compress 为 true 时压缩可压缩的文件，压缩的文件按位置记录在分享信息中
+pub(crate) async fn import(path: PathBuf, db: impl iroh_blobs::store::Store, meta: &ShareMeta, signer: &SecretKey, compress: bool) -> anyhow::Result<(TempTag, u64, Collection)> {
+    // 只遍历一次，边遍历边统计文件数和总大小用于展示进度
+    let files = walk_files(&path)?;
+    let count = Arc::new(WalkCount::default());
+    let (found, walked) = async_channel::bounded(WALK_BUFFER);
+    let walker = tokio::task::spawn_blocking({
+        let count = count.clone();
+        move || {
+            for file in files {
+                if let Ok((_, _, size)) = &file {
+                    count.files.fetch_add(1, Ordering::Relaxed);
+                    count.size.fetch_add(*size, Ordering::Relaxed);
+                }
+                // 导入出错时接收端已经关闭，停止遍历
+                if found.send_blocking(file).is_err() {
+                    break;
+                }
+            }
+            count.done.store(true, Ordering::Relaxed);
+        }
+    });
+
+    // 压缩或加密时先把结果写到临时目录，再导入
+    let work_dir = match compress || !meta.recipients.is_empty() {
+        false => None,
+        true => {
+            let dir = std::env::temp_dir().join(format!("transfer-import-{}", HEXLOWER.encode(&rand::thread_rng().gen::<[u8; 8]>())));
+            tokio::fs::create_dir_all(&dir).await?;
+            Some(dir)
+        }
+    };
 
     let (send, recv) = async_channel::bounded(32);
     let progress = iroh_blobs::util::progress::AsyncChannelProgressSender::new(send);
-    let show_progress = tokio::spawn(show_ingest_progress(recv));
+    let show_progress = tokio::spawn(show_ingest_progress(recv, count));
     // 使用多cpu, 导入全部的文件,返回 names 和 temp tags
-    let mut names_and_tags = futures_lite::stream::iter(data_source)
-        .map(|(name, path)| {
+    let mut imported = Box::pin(walked
+        .enumerate()
+        .map(|(index, file)| {
             let db = db.clone();
             let progress = progress.clone();
+            let work_dir = work_dir.clone();
+            let recipients = meta.recipients.clone();
             async move {
-                let (temp_tag, file_size) = db
-                    .import_file(path, ImportMode::TryReference, BlobFormat::Raw, progress)
+                let (name, path, original) = file?;
+                let (source, compressed, prepared) = match work_dir {
+                    None => (path, false, false),
+                    Some(work_dir) => {
+                        let target = work_dir.join(index.to_string());
+                        let compress = compress && is_compressible(&name);
+                        let (src, dst) = (path.clone(), target.clone());
+                        let (compressed, prepared) = tokio::task::spawn_blocking(move || {
+                            prepare_file(&src, &dst, compress, &recipients)
+                        })
+                        .await??;
+                        (if prepared { target } else { path }, compressed, prepared)
+                    }
+                };
+                let mode = if prepared { ImportMode::Copy } else { ImportMode::TryReference };
+                let (tag, size) = db
+                    .import_file(source.clone(), mode, BlobFormat::Raw, progress)
                     .await?;
-                anyhow::Ok((name, temp_tag, file_size))
+                if prepared {
+                    tokio::fs::remove_file(source).await?;
+                }
+                anyhow::Ok(ImportedFile { name, tag, size, original, compressed })
             }
-        }).buffer_unordered(num_cpus::get())
-        .collect::<Vec<_>>()
-        .await
-        .into_iter()
-        .collect::<anyhow::Result<Vec<_>>>()?;
+        }).buffer_unordered(num_cpus::get()));
+    // 逐个汇总导入结果，只保留集合需要的名称和哈希
+    let mut names_and_tags = Vec::new();
+    let mut meta = meta.clone();
+    let (mut size, mut original, mut stored) = (0, 0, 0);
+    let res = async {
+        while let Some(file) = imported.next().await {
+            let file = file?;
+            // 内部条目的名称不能用作文件名，否则接收端会把文件当作分享信息或签名
+            anyhow::ensure!(!is_internal(&file.name), "{} is a reserved name", file.name);
+            size += file.size;
+            if file.compressed {
+                original += file.original;
+                stored += file.size;
+            }
+            names_and_tags.push((file.name, file.tag, file.compressed));
+        }
+        anyhow::Ok(())
+    }
+    .await;
     // 导入文件完成，销毁关闭发送器
+    drop(imported);
     drop(progress);
-    names_and_tags.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
-    let size = names_and_tags.iter()
-        .map(|(_, _, size)| * size).sum::<u64>();
+    walker.await?;
+    if let Some(dir) = work_dir {
+        tokio::fs::remove_dir_all(dir).await?;
+    }
+    res?;
+    show_progress.await??;
+    names_and_tags.sort_by(|a, b| a.0.cmp(&b.0));
+    // 按排序后的位置标记压缩的文件
+    for (index, (_, _, compressed)) in names_and_tags.iter().enumerate() {
+        if *compressed {
+            meta.compressed.insert(index as u64);
+        }
+    }
+    let mut names_and_tags = names_and_tags.into_iter().map(|(name, tag, _)| (name, tag)).collect::<Vec<_>>();
+    if !meta.compressed.is_empty() {
+        println!(
+            "compressed {} files, {} -> {} ({:.1}x)",
+            meta.compressed.len(),
+            HumanBytes(original),
+            HumanBytes(stored),
+            original as f64 / stored.max(1) as f64
+        );
+    }
+    // 分享信息作为第一个条目，接收端可以在下载前读取
+    if !meta.is_empty() {
+        let tag = meta.store(&db).await?;
+        names_and_tags.insert(0, (META_NAME.to_string(), tag));
+    }
     // collect the (name, hash) tuples into a collection
     // we must also keep the tags around so the data does not get gced.
-    let (collection, tags) = names_and_tags.into_iter()
-        .map(|(name, tag, _)| ((name, *tag.hash()), tag))
+    let (collection, mut tags) = names_and_tags.into_iter()
+        .map(|(name, tag)| ((name, *tag.hash()), tag))
         .unzip::<_, _, Collection, Vec<_>>();
+    let (collection, signature_tag) = sign_collection(collection, signer, &db).await?;
+    tags.push(signature_tag);
     let temp_tag = collection.clone().store(&db).await?;
     // now that the collection is stored, we can drop the tags
     // data is protected by the collection
     drop(tags);
-    show_progress.await??;
     Ok((temp_tag, size, collection))
 }
 
@@ -115,71 +276,66 @@ fn make_download_progress() -> ProgressBar {
 }
 
 /// 显示文件导入进度的异步函数
-async fn show_ingest_progress(recv: async_channel::Receiver<ImportProgress>) -> anyhow::Result<()> {
-    // 创建多进度条管理器
-    let mp = MultiProgress::new();
-    // 设置输出目标为标准错误输出
-    mp.set_draw_target(ProgressDrawTarget::stderr());
-    // 添加一个隐藏的进度条
-    let op = mp.add(ProgressBar::hidden());
+/// 只展示一个汇总的进度：文件数、字节数、速度和剩余时间，总量随遍历增长，遍历结束前文件数带 +
+/// 压缩或加密后存储的大小与原文件不同，剩余时间只是估算
+async fn show_ingest_progress(recv: async_channel::Receiver<ImportProgress>, count: Arc<WalkCount>) -> anyhow::Result<()> {
+    let op = ProgressBar::hidden();
     op.set_style(
-        ProgressStyle::default_spinner().template("{spinner:.green} [{elapsed_precise}] {msg}")?,
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} {binary_bytes_per_sec} eta {eta} {msg}",
+        )?
+        .progress_chars("#>-"),
     );
-    // op.set_message(format!("{} Ingesting ...\n", style("[1/2]").bold().dim()));
-    // op.set_length(total_files);
-    // 文件名称
-    let mut names = BTreeMap::new();
-    // 文件大小
-    let mut sizes = BTreeMap::new();
-    // 进度条
-    let mut pbs = BTreeMap::new();
+    op.set_length(count.size.load(Ordering::Relaxed));
+    op.set_draw_target(ProgressDrawTarget::stderr());
+    op.enable_steady_tick(Duration::from_millis(100));
+    eprintln!("{} Ingesting files", style("[1/2]").bold().dim());
+    // 正在导入的文件的大小和已经计入的位置，只包含同时进行的导入
+    let mut in_flight = HashMap::new();
+    let mut done = 0u64;
 
     loop {
         let x = recv.recv().await;
         match x {
-            Ok(ImportProgress::Found { id, name }) => {
-                names.insert(id, name);
-            }
+            Ok(ImportProgress::Found { .. }) | Ok(ImportProgress::CopyProgress { .. }) => {}
             Ok(ImportProgress::Size { id, size }) => {
-                // 当获取到文件大小时，记录大小并创建进度条
-                sizes.insert(id, size);
-                let total_size = sizes.values().sum::<u64>();
-                op.set_message(format!(
-                    "{} Ingesting {} files, {}\n",
-                    style("[1/2]").bold().dim(),
-                    sizes.len(),
-                    HumanBytes(total_size)
-                ));
-                let name = names.get(&id).cloned().unwrap_or_default();
-                let pb = mp.add(ProgressBar::hidden());
-                pb.set_style(ProgressStyle::with_template(
-                    "{msg}{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes}",
-                )?.progress_chars("#>-"));
-                pb.set_message(format!("{} {}", style("[2/2]").bold().dim(), name));
-                pb.set_length(size);
-                pbs.insert(id, pb);
+                in_flight.insert(id, (size, 0));
             }
             Ok(ImportProgress::OutboardProgress { id, offset }) => {
-                if let Some(pb) = pbs.get(&id) {
-                    pb.set_position(offset);
+                if let Some((_, last)) = in_flight.get_mut(&id) {
+                    op.inc(offset.saturating_sub(std::mem::replace(last, offset)));
                 }
             }
             Ok(ImportProgress::OutboardDone { id, .. }) => {
                 // you are not guaranteed to get any OutboardProgress
-                if let Some(pb) = pbs.remove(&id) {
-                    pb.finish_and_clear();
+                if let Some((size, last)) = in_flight.remove(&id) {
+                    op.inc(size.saturating_sub(last));
                 }
+                done += 1;
+                let rate = done as f64 / op.elapsed().as_secs_f64().max(0.001);
+                let walking = if count.done.load(Ordering::Relaxed) { "" } else { "+" };
+                op.set_length(count.size.load(Ordering::Relaxed));
+                op.set_message(format!(
+                    "{done}/{}{walking} files ({rate:.0} files/s)",
+                    count.files.load(Ordering::Relaxed)
+                ));
             }
-            Ok(ImportProgress::CopyProgress { .. }) => {
-                // we are not copying anything
-            }
-            Err(e) => {
-                op.set_message(format!("Error receiving progress: {e}"));
-                break;
-            }
+            Err(_) => break,
         }
     }
+    // 结束后进度会被设置为总长度，先记录实际导入的字节数
+    let (bytes, elapsed) = (op.position(), op.elapsed());
     op.finish_and_clear();
+    let secs = elapsed.as_secs_f64().max(0.001);
+    eprintln!(
+        "{} Ingested {} files, {} in {} ({:.0} files/s, {}/s)",
+        style("[2/2]").bold().dim(),
+        done,
+        HumanBytes(bytes),
+        HumanDuration(elapsed),
+        done as f64 / secs,
+        HumanBytes((bytes as f64 / secs) as u64)
+    );
     Ok(())
 }
 
@@ -243,7 +399,7 @@ pub async fn show_download_progress(
 
 
 
-fn add_to_clipboard(ticket: &BlobTicket) {
+pub(crate) fn add_to_clipboard(ticket: &impl Display) {
     let clipboard = Clipboard::new();
     match clipboard {
         Ok(mut clip) => {
@@ -258,7 +414,7 @@ fn add_to_clipboard(ticket: &BlobTicket) {
 }
 
 
-fn show_get_error(e: anyhow::Error) -> anyhow::Error {
+pub(crate) fn show_get_error(e: anyhow::Error) -> anyhow::Error {
     if let Some(err) = e.downcast_ref::<DecodeError>() {
         match err {
             DecodeError::NotFound => {
@@ -304,33 +460,70 @@ fn show_get_error(e: anyhow::Error) -> anyhow::Error {
     e
 }
 
-/// 验证路径是否有效 
-/// 路径不能包含 '/'
+/// 验证路径是否有效
+/// 路径来自发送端，不能为空，不能是 "." 或 ".."，不能包含 '/'、'\' 或 '\0'
 fn validate_path_component(component: &str) -> anyhow::Result<()> {
-    anyhow::ensure!(
-        !component.contains('/'),
-        "path components must not contain the only correct path separator, /"
-    );
-    anyhow::Ok(())
+    file_transfer::protocol::validate_file_name(component)
 }
 
-/// get 导出路径
+/// get 导出路径，导出路径必须在 root 目录下
 fn get_export_path(root: &Path, name: &str) -> anyhow::Result<PathBuf> {
     let parts = name.split('/');
     let mut path = root.to_path_buf();
     for part in parts {
-        validate_path_component(part)?;
+        validate_path_component(part).with_context(|| format!("invalid file name {name:?}"))?;
         path.push(part);
     }
+    anyhow::ensure!(path.starts_with(root), "{name:?} is outside of {}", root.display());
     Ok(path)
 }
 
-/// 导出文件
-async fn export(db: impl iroh_blobs::store::Store, collection: Collection) -> anyhow::Result<()> {
-    // get current dir
-    let root = std::env::current_dir()?;
-    for (name, hash) in collection.iter() {
-        let target = get_export_path(&root, name)?;
+/// 检查集合中所有文件的导出路径
+async fn check_export_paths(collection: &StoredCollection, root: &Path) -> anyhow::Result<()> {
+    let mut entries = collection.entries().await?;
+    while let Some((name, _)) = entries.next().await? {
+        if !is_internal(&name) {
+            get_export_path(root, &name)?;
+        }
+    }
+    Ok(())
+}
+
+/// 把导出的临时文件还原为原始内容：先解密再解压
+/// 阻塞执行，需要在 spawn_blocking 中调用
+fn restore_file(src: &Path, dst: &Path, identity: Option<&SecretKey>, compressed: bool) -> anyhow::Result<()> {
+    match (identity, compressed) {
+        (Some(identity), false) => decrypt_file(src, dst, identity),
+        (None, _) => decompress_file(src, dst),
+        (Some(identity), true) => {
+            let plain = src.with_extension("decrypted");
+            let res = decrypt_file(src, &plain, identity).and_then(|()| decompress_file(&plain, dst));
+            std::fs::remove_file(&plain).ok();
+            res
+        }
+    }
+}
+
+/// 导出文件，返回导出的总大小
+/// 分享是加密的时候使用节点私钥 identity 解密，压缩的条目解压后导出
+/// 条目按顺序从存储中读取
+pub(crate) async fn export(collection: &StoredCollection, root: &Path, meta: &ShareMeta, identity: &SecretKey) -> anyhow::Result<u64> {
+    let db = collection.db();
+    let identity = (!meta.recipients.is_empty()).then_some(identity);
+    // 先检查所有文件名，任何一个不合法时一个文件都不导出
+    check_export_paths(collection, root).await?;
+    let mut total = 0;
+    // 文件在集合中的位置，不计内部条目
+    let mut index = 0;
+    let mut entries = collection.entries().await?;
+    while let Some((name, hash)) = entries.next().await? {
+        let name = &name;
+        if is_internal(name) {
+            continue;
+        }
+        let compressed = meta.compressed.contains(index);
+        index += 1;
+        let target = get_export_path(root, name)?;
         if target.exists() {
             eprintln!(
                 "target {} already exists. Export stopped.",
@@ -339,25 +532,31 @@ async fn export(db: impl iroh_blobs::store::Store, collection: Collection) -> an
             eprintln!("You can remove the file or directory and try again. The download will not be repeated.");
             anyhow::bail!("target {} already exists", target.display());
         }
-        db.export(
-            *hash, 
-            target, 
-            ExportMode::TryReference, 
-            Box::new(move |_position| Ok(()))
-        ,).await?;
-
+        if identity.is_some() || compressed {
+            // 先复制到目标旁边的临时文件，还原完成后删除
+            let file_name = target.file_name().context("invalid export path")?.to_string_lossy();
+            let stored = target.with_file_name(format!(".{file_name}.partial"));
+            db.export(hash, stored.clone(), ExportMode::Copy, Box::new(move |_position| Ok(()))).await?;
+            let identity = identity.cloned();
+            let (src, dst) = (stored.clone(), target.clone());
+            let res = tokio::task::spawn_blocking(move || restore_file(&src, &dst, identity.as_ref(), compressed)).await;
+            tokio::fs::remove_file(stored).await?;
+            res?.with_context(|| format!("failed to restore {name}"))?;
+        } else {
+            db.export(
+                hash, 
+                target.clone(), 
+                ExportMode::TryReference, 
+                Box::new(move |_position| Ok(()))
+            ,).await?;
+        }
+        total += tokio::fs::metadata(&target).await?.len();
     }
-    Ok(())
+    Ok(total)
 }
 
-/// 文件传输
-/// 发送文件
-/// 返回文件码
-pub async fn send_file(args: SendArgs) -> anyhow::Result<()> {
-    // 创建 endpoint
-    let endpoint = create_endpoint().await?;
-
-    // 临时目录
+/// 创建发送端使用的临时存储目录
+pub(crate) async fn create_send_data_dir() -> anyhow::Result<PathBuf> {
     // use a flat store - todo: use a partial in mem store instead
     let suffix = rand::thread_rng().gen::<[u8; 16]>();
     let cwd = std::env::current_dir()?;
@@ -370,17 +569,36 @@ pub async fn send_file(args: SendArgs) -> anyhow::Result<()> {
         std::process::exit(1);
     }
     tokio::fs::create_dir_all(&blobs_data_dir).await?;
+    Ok(blobs_data_dir)
+}
+
+/// 文件传输
+/// 发送文件
+/// 返回文件码
+/// 集合使用 signer 签名，接收端可以验证发送者
+pub async fn send_file(args: SendArgs, signer: SecretKey) -> anyhow::Result<()> {
+    // 使用签名的身份提供数据，接收端看到的节点 id 与签名者一致
+    let endpoint = create_endpoint_with_key(signer.clone()).await?;
+
+    // 临时目录
+    let blobs_data_dir = create_send_data_dir().await?;
 
     // 创建 blobs
     let blobs = Blobs::persistent(&blobs_data_dir).await?.build(&endpoint);
     
 
+    let access = AccessList::default();
+    let limits = UploadLimits::new(args.limit_upload, args.limit_per_peer);
     let router = Router::builder(endpoint)
-        .accept(iroh_blobs::ALPN, blobs.clone())
+        .accept(
+            iroh_blobs::ALPN,
+            AccessProtocol::new(blobs.clone(), access.clone()).with_limits(limits),
+        )
         .spawn();
 
-    let path = args.path;
-    let (temp_tag, size, collection) = import(path.clone(), blobs.store().clone()).await?;
+    let path = args.path.context("missing path to send")?;
+    let meta = ShareMeta::new(args.message, args.labels, args.recipients);
+    let (temp_tag, size, collection) = import(path.clone(), blobs.store().clone(), &meta, &signer, args.compress).await?;
     let hash = *temp_tag.hash();
 
     // let _ = router.endpoint().home_relay().initialized().await?;
@@ -388,6 +606,21 @@ pub async fn send_file(args: SendArgs) -> anyhow::Result<()> {
     // 生成ticket
     let addr = router.endpoint().node_addr().await?;
     let ticket = BlobTicket::new(addr, hash, BlobFormat::HashSeq)?;
+    // 设置有效期时使用带有效期的分享码，过期后不再提供集合中的任何数据
+    // 同一个身份可能同时进行多次分享，分享编号随机生成
+    let share_ticket = match args.expires {
+        Some(expires) => {
+            let expires = unix_now() + expires.as_secs();
+            let id = rand::random();
+            access.add(id, collection_hashes(&collection), Some(expires));
+            ShareTicket::Revocable(RevocableTicket {
+                blob: ticket.clone(),
+                id,
+                expires: Some(expires),
+            })
+        }
+        None => ShareTicket::Blob(ticket.clone()),
+    };
 
     let entry_type = if path.is_file() { "file" } else { "directory" };
 
@@ -396,28 +629,54 @@ pub async fn send_file(args: SendArgs) -> anyhow::Result<()> {
         entry_type,
         path.display(),
         HumanBytes(size),
-        hash.to_string()
+        hash
     );
 
-    for (name, hash) in collection.iter() {
-        println!("    {} {name}", hash.to_string());
+    for (name, hash) in collection.iter().filter(|(name, _)| !is_internal(name)) {
+        println!("    {} {name}", hash);
     }
+    println!("signed by {}", signer.public());
 
     println!("to get this data, use");
-    println!("transfer receive {}", ticket);
+    println!("transfer receive {}", share_ticket);
+    if let Some(expires) = args.expires {
+        println!("expires in {}", HumanDuration(expires));
+    }
 
+    if let Some(to) = args.to {
+        // 推送给接收端，等待接收端下载完成
+        let peer = resolve_peer(router.endpoint(), &to).await?;
+        let offer = Offer::new(&ticket, &collection, size, &meta);
+        let res = push_offer(router.endpoint(), peer, offer).await;
+        drop(temp_tag);
+        tokio::time::timeout(Duration::from_secs(2), router.shutdown()).await??;
+        tokio::fs::remove_dir_all(blobs_data_dir).await?;
+        return res;
+    }
 
-    let _keyboard = tokio::task::spawn(async move {
+    // read_key 会阻塞线程，不能占用 tokio 的工作线程
+    let _keyboard = tokio::task::spawn_blocking(move || {
         let term = Term::stdout();
         println!("press c to copy command to clipboard, or use the --clipboard argument");
-        loop {
-            if let Ok(Key::Char('c')) = term.read_key() {
-                add_to_clipboard(&ticket);
+        // 没有终端时 read_key 直接返回错误
+        while let Ok(key) = term.read_key() {
+            if key == Key::Char('c') {
+                add_to_clipboard(&share_ticket);
             }
         }
     });
 
-    tokio::signal::ctrl_c().await?;
+    // 过期后停止分享
+    let expired = async {
+        match args.expires {
+            Some(expires) => tokio::time::sleep(expires).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res?,
+        _ = expired => println!("share expired"),
+    }
 
     drop(temp_tag);
 
@@ -431,73 +690,620 @@ pub async fn send_file(args: SendArgs) -> anyhow::Result<()> {
 }
 
 
+/// 解析分享码，返回发送端地址和要下载的集合
+pub(crate) async fn resolve_ticket(endpoint: &Endpoint, ticket: ShareTicket) -> anyhow::Result<(NodeAddr, HashAndFormat)> {
+    match ticket {
+        ShareTicket::Blob(ticket) => {
+            let hash_and_format = HashAndFormat {
+                hash: ticket.hash(),
+                format: ticket.format(),
+            };
+            Ok((ticket.node_addr().clone(), hash_and_format))
+        }
+        ShareTicket::Revocable(ticket) => {
+            // 发送端也会拒绝，这里提前给出明确的错误
+            anyhow::ensure!(!ticket.is_expired(), "share {} has expired", ticket.id);
+            let hash_and_format = HashAndFormat {
+                hash: ticket.blob.hash(),
+                format: ticket.blob.format(),
+            };
+            Ok((ticket.blob.node_addr().clone(), hash_and_format))
+        }
+        ShareTicket::Latest(ticket) => {
+            // 先向发送端询问当前最新的集合
+            let addr = ticket.node_addr().clone();
+            let record = fetch_latest(endpoint, addr.clone(), 0).await?;
+            eprintln!(
+                "latest version #{} from {}, hash: {}",
+                record.seq,
+                addr.node_id.fmt_short(),
+                record.hash
+            );
+            Ok((addr, HashAndFormat::hash_seq(record.hash)))
+        }
+        ShareTicket::Quic(_) => anyhow::bail!("this ticket was created by the quinn backend, use --backend quinn"),
+    }
+}
+
+/// 查看分享的内容，不下载文件
+pub async fn inspect(args: InspectArgs, trust: TrustStore) -> anyhow::Result<()> {
+    let endpoint: Endpoint = create_endpoint().await?;
+    let (addr, hash_and_format) = resolve_ticket(&endpoint, args.code).await?;
+    anyhow::ensure!(hash_and_format.format == BlobFormat::HashSeq, "ticket does not point to a collection");
+    let connection = endpoint.connect(addr, iroh_blobs::protocol::ALPN).await?;
+    // 文件列表写入临时存储，查看后删除
+    let suffix = rand::thread_rng().gen::<[u8; 8]>();
+    let dir = std::env::temp_dir().join(format!(".transfer-inspect-{}", HEXLOWER.encode(&suffix)));
+    let db = iroh_blobs::store::fs::Store::load(&dir).await?;
+    let limits = CollectionLimits {
+        max_entries: args.max_entries,
+        max_names_size: args.max_names_size,
+    };
+    let res = async {
+        let (collection, summary) = fetch_collection(&connection, &db, hash_and_format.hash, limits)
+            .await
+            .map_err(show_get_error)?;
+        let meta = fetch_meta(&connection, &collection).await?.unwrap_or_default();
+        let author = match verify_collection(&connection, &collection).await? {
+            Authorship::Signed(record) => Some(record.author),
+            Authorship::Unsigned => None,
+        };
+        connection.close(0u32.into(), b"done");
+        let mut files = Vec::new();
+        let mut entries = collection.entries().await?;
+        while let Some((name, hash)) = entries.next().await? {
+            if !is_internal(&name) {
+                let compressed = meta.compressed.contains(files.len() as u64);
+                files.push((name, collection.size_of(&hash).await?, compressed));
+            }
+        }
+        anyhow::Ok((meta, author, files, summary))
+    }
+    .await;
+    drop(db);
+    tokio::fs::remove_dir_all(&dir).await.ok();
+    let (meta, author, files, summary) = res?;
+    let total_size = summary.size;
+
+    if args.json {
+        let output = serde_json::json!({
+            "hash": hash_and_format.hash,
+            "message": meta.message,
+            "labels": meta.labels,
+            "recipients": meta.recipients,
+            "author": author,
+            "trusted": author.as_ref().and_then(|author| trust.name(author)),
+            "size": total_size,
+            "files": files
+                .iter()
+                .map(|(name, size, compressed)| serde_json::json!({ "name": name, "size": size, "compressed": compressed }))
+                .collect::<Vec<_>>(),
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+    println!("collection {} {} files, {}", hash_and_format.hash, files.len(), HumanBytes(total_size));
+    match author {
+        Some(author) => match trust.name(&author) {
+            Some(name) => println!("signed by {author} ({name})"),
+            None => println!("signed by {author} (not trusted)"),
+        },
+        None => println!("not signed"),
+    }
+    meta.print();
+    for (name, size, _) in &files {
+        println!("    {:>10} {name}", HumanBytes(*size).to_string());
+    }
+    Ok(())
+}
+
 /// 接收文件方法
-pub async fn receive_file(args: ReceiveArgs) -> anyhow::Result<()> {
+/// identity 用于解密只发给本节点的分享
+pub async fn receive_file(args: ReceiveArgs, trust: TrustStore, identity: SecretKey) -> anyhow::Result<()> {
     // Use short code instead of tickets
-    let ticket = args.code;
-    let addr = ticket.node_addr().clone();
     let endpoint: Endpoint = create_endpoint().await?;
+    if let Some(target) = &args.follow {
+        let ShareTicket::Latest(ticket) = &args.code else {
+            anyhow::bail!("--follow requires a node ticket printed by `transfer send --watch`");
+        };
+        let policy = SignaturePolicy {
+            author: args.author,
+            require_trusted: args.require_trusted,
+            trust,
+        };
+        return follow(&endpoint, ticket.node_addr().clone(), target, &policy, &identity, &args).await;
+    }
+    let (addr, hash_and_format) = resolve_ticket(&endpoint, args.code).await?;
+    let mut providers = vec![addr];
+    for peer in &args.providers {
+        // 找不到的提供者只给出警告，仍然可以从其他提供者下载
+        match resolve_provider(&endpoint, peer, hash_and_format.hash).await {
+            Ok(addr) if providers.iter().any(|known| known.node_id == addr.node_id) => {}
+            Ok(addr) => providers.push(addr),
+            Err(e) => eprintln!("{} skipping provider {peer}: {e:#}", style("warning:").yellow().bold()),
+        }
+    }
+    let policy = SignaturePolicy {
+        author: args.author,
+        require_trusted: args.require_trusted,
+        trust,
+    };
+    let limit = args.limit_download.map(RateLimiter::new);
+    // get current dir
+    let root = std::env::current_dir()?;
+    let options = FetchOptions {
+        parallel: args.parallel,
+        limit: limit.as_ref(),
+        collection: CollectionLimits {
+            max_entries: args.max_entries,
+            max_names_size: args.max_names_size,
+        },
+        progress: None,
+    };
+    let downloaded = download(&endpoint, providers, hash_and_format, &root, &policy, &identity, options).await?;
+    match args.seed {
+        true => downloaded.seed(endpoint).await,
+        false => downloaded.cleanup().await,
+    }
+}
+
+/// 跟随发送端发布的版本，直到按下 ctrl-c
+/// 每个新版本先下载到 target 旁边的临时目录，完成后整体替换 target
+/// 某个版本下载失败时给出警告，继续等待下一个版本
+async fn follow(endpoint: &Endpoint, addr: NodeAddr, target: &Path, policy: &SignaturePolicy, identity: &SecretKey, args: &ReceiveArgs) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(target).await?;
+    let target = target.canonicalize()?;
+    let parent = target.parent().context("can not follow into the root directory")?;
+    let name = target.file_name().context("invalid follow directory")?.to_string_lossy().into_owned();
+    let limit = args.limit_download.map(RateLimiter::new);
+    let mut seq = 0;
+    loop {
+        let record = tokio::select! {
+            res = tokio::signal::ctrl_c() => return Ok(res?),
+            record = fetch_latest(endpoint, addr.clone(), seq) => record,
+        };
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                // 发送端可能暂时不在线，稍后重试
+                eprintln!("{} can not get the latest version: {e:#}", style("warning:").yellow().bold());
+                tokio::time::sleep(FOLLOW_RETRY).await;
+                continue;
+            }
+        };
+        seq = record.seq;
+        eprintln!("latest version #{} from {}, hash: {}", record.seq, addr.node_id.fmt_short(), record.hash);
+        let staging = parent.join(format!(".{name}.{}.partial", record.seq));
+        tokio::fs::create_dir_all(&staging).await?;
+        let options = FetchOptions {
+            parallel: args.parallel,
+            limit: limit.as_ref(),
+            collection: CollectionLimits {
+                max_entries: args.max_entries,
+                max_names_size: args.max_names_size,
+            },
+            progress: None,
+        };
+        let res = async {
+            download(endpoint, vec![addr.clone()], HashAndFormat::hash_seq(record.hash), &staging, policy, identity, options)
+                .await?
+                .cleanup()
+                .await
+        }
+        .await;
+        if let Err(e) = res {
+            tokio::fs::remove_dir_all(&staging).await.ok();
+            eprintln!("{} skipping version #{}: {e:#}", style("warning:").yellow().bold(), record.seq);
+            continue;
+        }
+        // 下载完成后才替换，target 中始终是完整的某个版本
+        let previous = parent.join(format!(".{name}.previous"));
+        replace_dir(&staging, &target, &previous).await?;
+        println!("updated {} to version #{}", target.display(), record.seq);
+    }
+}
 
+/// 用 staging 整体替换 target，旧内容先移到 previous 再删除
+/// 上次运行中断时可能留下 previous，替换前先清理掉
+async fn replace_dir(staging: &Path, target: &Path, previous: &Path) -> anyhow::Result<()> {
+    if tokio::fs::symlink_metadata(previous).await.is_ok() {
+        tokio::fs::remove_dir_all(previous).await.with_context(|| format!("can not remove {}", previous.display()))?;
+    }
+    tokio::fs::rename(target, previous).await?;
+    tokio::fs::rename(staging, target).await?;
+    tokio::fs::remove_dir_all(previous).await?;
+    Ok(())
+}
 
-    let dir_name: String = format!(".re-sendme-get-{}", ticket.hash().to_hex());
-    let iroh_data_dir = std::env::current_dir()?.join(dir_name);
-    let db = iroh_blobs::store::fs::Store::load(&iroh_data_dir).await?;
+/// 解析额外的提供者，分享码需要指向同一个集合
+async fn resolve_provider(endpoint: &Endpoint, peer: &str, hash: Hash) -> anyhow::Result<NodeAddr> {
+    let ticket = match ShareTicket::from_str(peer) {
+        Ok(ShareTicket::Blob(ticket)) => ticket,
+        Ok(ShareTicket::Revocable(ticket)) => ticket.blob,
+        _ => return resolve_peer(endpoint, peer).await,
+    };
+    anyhow::ensure!(ticket.hash() == hash, "ticket points to different content");
+    Ok(ticket.node_addr().clone())
+}
+
+/// 依次连接提供者，返回第一个连接成功的
+async fn connect_any(endpoint: &Endpoint, providers: &[NodeAddr]) -> anyhow::Result<Connection> {
+    let mut last_error = None;
+    for addr in providers {
+        match endpoint.connect(addr.clone(), iroh_blobs::protocol::ALPN).await {
+            Ok(connection) => return Ok(connection),
+            Err(e) => {
+                if providers.len() > 1 {
+                    eprintln!("{} can not connect to {}: {e:#}", style("warning:").yellow().bold(), addr.node_id.fmt_short());
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.context("no providers to download from")?)
+}
+
+/// 从发送端下载集合并导出到 root 目录
+/// 下载前检查集合的签名，不满足 policy 时拒绝下载
+/// 加密的分享使用 identity 解密，options 设置并行请求数、下载限速和集合的限制
+/// 集合的文件同时下载，有多个提供者时同时从所有提供者下载
+/// 返回下载使用的存储，调用方继续提供或者清理
+pub(crate) async fn download(endpoint: &Endpoint, providers: Vec<NodeAddr>, hash_and_format: HashAndFormat, root: &Path, policy: &SignaturePolicy, identity: &SecretKey, options: FetchOptions<'_>) -> anyhow::Result<Downloaded> {
+    list(endpoint, &providers, hash_and_format, root, policy, identity, options.collection)
+        .await?
+        .download(endpoint, &providers, root, identity, options)
+        .await
+}
+
+/// 获取集合的文件列表，验证签名并展示发送端附带的分享信息，不下载文件
+/// 文件列表写入 root 下的临时存储，不满足要求时删除
+pub(crate) async fn list(endpoint: &Endpoint, providers: &[NodeAddr], hash_and_format: HashAndFormat, root: &Path, policy: &SignaturePolicy, identity: &SecretKey, limits: CollectionLimits) -> anyhow::Result<Listed> {
     let mp: MultiProgress = MultiProgress::new();
     let connect_progress: ProgressBar = mp.add(ProgressBar::hidden());
     connect_progress.set_draw_target(ProgressDrawTarget::stderr());
     connect_progress.set_style(ProgressStyle::default_spinner());
-    connect_progress.set_message(format!("connecting to {}", addr.node_id));
-    let connection = endpoint.connect(addr, iroh_blobs::protocol::ALPN).await?;
-    let hash_and_format = HashAndFormat {
-        hash: ticket.hash(),
-        format: ticket.format(),
-    };
+    connect_progress.set_message(format!("connecting to {}", providers[0].node_id));
+    let connection = connect_any(endpoint, providers).await?;
     connect_progress.finish_and_clear();
-    let (send, recv) = async_channel::bounded(32);
-    let progress = iroh_blobs::util::progress::AsyncChannelProgressSender::new(send);
-    let (_hash_seq, sizes) =
-        get_hash_seq_and_sizes(&connection, &hash_and_format.hash, 1024 * 1024 * 32)
-            .await
-            .map_err(show_get_error)?;
-    let total_size = sizes.iter().sum::<u64>();
-    let total_files = sizes.len().saturating_sub(1);
-    let payload_size = sizes.iter().skip(1).sum::<u64>();
-    eprintln!(
-        "getting collection {} {} files, {}",
-        &ticket.hash().to_string(),
-        total_files,
-        HumanBytes(payload_size)
-    );
-    eprintln!(
-        "getting {} blobs in total, {}",
-        sizes.len(),
-        HumanBytes(total_size)
-    );
-    let _task = tokio::spawn(show_download_progress(recv, total_size));
-    let get_conn = || async move { Ok(connection) };
-    let stats = iroh_blobs::get::db::get_to_db(&db, get_conn, &hash_and_format, progress)
-        .await
-        .map_err(|e| show_get_error(anyhow::anyhow!(e)))?;
-    let collection = Collection::load_db(&db, &hash_and_format.hash).await?;
-    for (name, hash) in collection.iter() {
-        println!("    {} {name}", hash.to_string());
-    }
-    if let Some((name, _)) = collection.iter().next() {
-        if let Some(first) = name.split('/').next() {
-            println!("downloading to: {};", first);
+    let dir_name: String = format!(".re-sendme-get-{}", hash_and_format.hash.to_hex());
+    let iroh_data_dir = root.join(dir_name);
+    let db = iroh_blobs::store::fs::Store::load(&iroh_data_dir).await?;
+    let mut meta = ShareMeta::default();
+    let (collection, summary) = if hash_and_format.format == BlobFormat::HashSeq {
+        let checked = async {
+            let (collection, summary) = fetch_collection(&connection, &db, hash_and_format.hash, limits)
+                .await
+                .map_err(show_get_error)?;
+            policy.check(&verify_collection(&connection, &collection).await?)?;
+            meta = fetch_meta(&connection, &collection).await?.unwrap_or_default();
+            meta.print();
+            // 不是接收者时下载下来也无法解密
+            anyhow::ensure!(
+                meta.recipients.is_empty() || meta.recipients.contains(&identity.public()),
+                "share is encrypted for other nodes, this node is {}",
+                identity.public()
+            );
+            Ok((Some(collection), summary))
+        };
+        match checked.await {
+            Ok(checked) => checked,
+            Err(e) => {
+                drop(db);
+                tokio::fs::remove_dir_all(&iroh_data_dir).await.ok();
+                return Err(e);
+            }
         }
+    } else {
+        policy.check(&Authorship::Unsigned)?;
+        (None, CollectionSummary::default())
+    };
+    Ok(Listed {
+        connection,
+        db,
+        dir: iroh_data_dir,
+        collection,
+        summary,
+        meta,
+        hash_and_format,
+    })
+}
+
+/// 已经获取了文件列表、还没有下载文件的集合
+pub(crate) struct Listed {
+    connection: Connection,
+    db: iroh_blobs::store::fs::Store,
+    dir: PathBuf,
+    /// 原始 blob 没有文件列表
+    collection: Option<StoredCollection>,
+    summary: CollectionSummary,
+    meta: ShareMeta,
+    hash_and_format: HashAndFormat,
+}
+
+impl Listed {
+    pub(crate) fn collection(&self) -> Option<&StoredCollection> {
+        self.collection.as_ref()
     }
-    export(db, collection).await?;
-    tokio::fs::remove_dir_all(iroh_data_dir).await?;
 
-    println!(
-            "downloaded {} files, {}. took {} ({}/s)",
-            total_files,
-            HumanBytes(payload_size),
-            HumanDuration(stats.elapsed),
-            HumanBytes((stats.bytes_read as f64 / stats.elapsed.as_secs_f64()) as u64),
+    pub(crate) fn summary(&self) -> &CollectionSummary {
+        &self.summary
+    }
+
+    /// 放弃下载，删除文件列表使用的存储
+    pub(crate) async fn discard(self) -> anyhow::Result<()> {
+        self.connection.close(0u32.into(), b"discarded");
+        drop(self.db);
+        tokio::fs::remove_dir_all(self.dir).await?;
+        Ok(())
+    }
+
+    /// 下载全部文件并导出到 root 目录
+    pub(crate) async fn download(self, endpoint: &Endpoint, providers: &[NodeAddr], root: &Path, identity: &SecretKey, options: FetchOptions<'_>) -> anyhow::Result<Downloaded> {
+        let Listed { connection, db, dir, collection, summary, meta, hash_and_format } = self;
+        eprintln!(
+            "getting collection {} {} files, {}",
+            hash_and_format.hash,
+            summary.files,
+            HumanBytes(summary.size)
         );
-    
-    Ok(())
+        eprintln!(
+            "getting {} blobs in total, {}",
+            summary.entries + 1,
+            HumanBytes(summary.total)
+        );
+        let stats = match &collection {
+            Some(collection) => {
+                // 文件名不合法时不下载
+                if let Err(e) = check_export_paths(collection, root).await {
+                    connection.close(0u32.into(), b"invalid collection");
+                    drop(db);
+                    tokio::fs::remove_dir_all(&dir).await.ok();
+                    return Err(e);
+                }
+                if providers.len() > 1 {
+                    eprintln!("downloading from {} providers", providers.len());
+                }
+                connection.close(0u32.into(), b"done");
+                fetch_parallel(endpoint, providers, collection, &summary, options)
+                    .await
+                    .map_err(show_get_error)?
+            }
+            None => {
+                let stats = fetch_raw(&connection, &db, hash_and_format.hash, options.limit)
+                    .await
+                    .map_err(show_get_error)?;
+                connection.close(0u32.into(), b"done");
+                stats
+            }
+        };
+        let collection = match collection {
+            Some(collection) => collection,
+            None => StoredCollection::open(db.clone(), hash_and_format.hash).await?,
+        };
+        let mut first = None;
+        let mut entries = collection.entries().await?;
+        while let Some((name, hash)) = entries.next().await? {
+            if !is_internal(&name) {
+                println!("    {} {name}", hash);
+                first.get_or_insert(name);
+            }
+        }
+        if let Some(name) = first {
+            if let Some(first) = name.split('/').next() {
+                println!("downloading to: {};", first);
+            }
+        }
+        let exported = export(&collection, root, &meta, identity).await?;
+
+        println!(
+                "downloaded {} files, {}. took {} ({}/s)",
+                summary.files,
+                HumanBytes(summary.size),
+                HumanDuration(stats.elapsed),
+                HumanBytes((stats.bytes_read as f64 / stats.elapsed.as_secs_f64()) as u64),
+            );
+        if !meta.compressed.is_empty() {
+            println!(
+                "{} files were compressed, {} after decompression ({:.1}x)",
+                meta.compressed.len(),
+                HumanBytes(exported),
+                exported as f64 / summary.size.max(1) as f64
+            );
+        }
+
+        Ok(Downloaded {
+            db,
+            dir,
+            hash_and_format,
+        })
+    }
+}
+
+/// 下载完成的集合以及保存它的存储
+pub(crate) struct Downloaded {
+    db: iroh_blobs::store::fs::Store,
+    dir: PathBuf,
+    hash_and_format: HashAndFormat,
+}
+
+impl Downloaded {
+    /// 下载的文件名，不包括内部条目
+    pub(crate) async fn files(&self) -> anyhow::Result<Vec<String>> {
+        let collection = StoredCollection::open(self.db.clone(), self.hash_and_format.hash).await?;
+        let mut files = Vec::new();
+        let mut entries = collection.entries().await?;
+        while let Some((name, _)) = entries.next().await? {
+            if !is_internal(&name) {
+                files.push(name);
+            }
+        }
+        Ok(files)
+    }
+
+    /// 删除下载使用的存储
+    pub(crate) async fn cleanup(self) -> anyhow::Result<()> {
+        tokio::fs::remove_dir_all(self.dir).await?;
+        Ok(())
+    }
+
+    /// 继续提供下载的集合，直到按下 ctrl-c
+    /// 之后的接收端可以从这里下载，或者使用 --from 同时从这里和其他节点下载
+    async fn seed(self, endpoint: Endpoint) -> anyhow::Result<()> {
+        let blobs = Blobs::builder(self.db.clone()).build(&endpoint);
+        let router = Router::builder(endpoint)
+            .accept(iroh_blobs::ALPN, AccessProtocol::new(blobs, AccessList::default()))
+            .spawn();
+        let addr = router.endpoint().node_addr().await?;
+        let ticket = BlobTicket::new(addr, self.hash_and_format.hash, self.hash_and_format.format)?;
+        println!("seeding, to get this data from this node, use");
+        println!("transfer receive --code {}", ticket);
+        println!("press ctrl-c to stop seeding");
+        tokio::signal::ctrl_c().await?;
+        tokio::time::timeout(Duration::from_secs(2), router.shutdown()).await??;
+        self.cleanup().await
+    }
 }
 
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_import_reserved_name() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("transfer-reserved-{}", HEXLOWER.encode(&rand::random::<[u8; 8]>())));
+        tokio::fs::create_dir_all(&dir).await?;
+        let signer = SecretKey::generate(rand::rngs::OsRng);
+        let db = iroh_blobs::store::mem::Store::new();
+        // 没有附带分享信息时同样拒绝
+        let path = dir.join(META_NAME);
+        tokio::fs::write(&path, b"{}").await?;
+        let res = import(path, db.clone(), &ShareMeta::default(), &signer, false).await;
+        tokio::fs::remove_dir_all(&dir).await?;
+        let err = res.err().context("reserved name was accepted")?;
+        assert!(err.to_string().contains("reserved name"), "{err:#}");
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_import_compressed_tree() -> anyhow::Result<()> {
+        use iroh_blobs::store::{bao_tree::io::fsm::AsyncSliceReader, Map, MapEntry};
+
+        let dir = std::env::temp_dir().join(format!("transfer-compressed-{}", HEXLOWER.encode(&rand::random::<[u8; 8]>())));
+        let source = dir.join("tree");
+        // 可压缩和不可压缩的文件交替出现
+        let count = 3000;
+        for index in 0..count {
+            let sub = source.join(format!("{:02}", index % 50));
+            std::fs::create_dir_all(&sub)?;
+            let name = if index % 3 == 0 { format!("{index}.jpg") } else { format!("{index}.txt") };
+            std::fs::write(sub.join(name), format!("file {index} ").repeat(100))?;
+        }
+        let (db, store_dir) = crate::collection::tests::temp_store("compressed").await?;
+        let signer = SecretKey::generate(rand::rngs::OsRng);
+        let res = async {
+            let (tag, _, _) = import(source.clone(), db.clone(), &ShareMeta::default(), &signer, true).await?;
+            let collection = StoredCollection::open(db.clone(), *tag.hash()).await?;
+            let hash = collection.find_internal(META_NAME).await?.context("missing meta")?;
+            let entry = db.get(&hash).await?.context("missing meta blob")?;
+            let size = entry.size().value();
+            // 每个文件只占一位，不保存文件名
+            assert!(size < 2048, "meta is {size} bytes");
+            let bytes = entry.data_reader().read_at(0, size as usize).await?;
+            let meta: ShareMeta = serde_json::from_slice(&bytes)?;
+            assert_eq!(meta.compressed.len(), count * 2 / 3);
+
+            let target = dir.join("out");
+            export(&collection, &target, &meta, &signer).await?;
+            for file in walk_files(&source)? {
+                let (name, path, _) = file?;
+                assert_eq!(std::fs::read(target.join(&name))?, std::fs::read(path)?, "{name}");
+            }
+            drop(tag);
+            anyhow::Ok(())
+        }
+        .await;
+        drop(db);
+        tokio::fs::remove_dir_all(&store_dir).await?;
+        tokio::fs::remove_dir_all(&dir).await?;
+        res
+    }
+
+    #[tokio::test]
+    async fn test_replace_dir_stale_previous() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("transfer-replace-{}", HEXLOWER.encode(&rand::random::<[u8; 8]>())));
+        let target = dir.join("target");
+        let staging = dir.join(".target.2.partial");
+        let previous = dir.join(".target.previous");
+        // 上次运行中断留下的 previous
+        for (path, content) in [(&target, "v1"), (&staging, "v2"), (&previous, "v0")] {
+            tokio::fs::create_dir_all(path).await?;
+            tokio::fs::write(path.join("file"), content).await?;
+        }
+        let res = async {
+            replace_dir(&staging, &target, &previous).await?;
+            assert_eq!(tokio::fs::read_to_string(target.join("file")).await?, "v2");
+            assert!(!previous.exists());
+            assert!(!staging.exists());
+            anyhow::Ok(())
+        }
+        .await;
+        tokio::fs::remove_dir_all(&dir).await?;
+        res
+    }
+
+    /// 进程的峰值内存（Linux 的 VmHWM）
+    fn peak_memory() -> Option<u64> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+        let kib = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+        Some(kib * 1024)
+    }
+
+    /// 导入一百万个小文件的目录，输出耗时和峰值内存
+    /// TRANSFER_BENCH_FILES 设置文件数，默认 1000000
+    /// cargo test -p transfer --release -- --ignored bench_import_tree --nocapture
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_import_tree() -> anyhow::Result<()> {
+        let count = std::env::var("TRANSFER_BENCH_FILES")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(1_000_000);
+        let dir = std::env::temp_dir().join(format!("transfer-bench-import-{}", HEXLOWER.encode(&rand::random::<[u8; 8]>())));
+        let source = dir.join("tree");
+        // 每个目录一千个文件
+        for index in 0..count {
+            let sub = source.join(format!("{:04}", index / 1000));
+            if index % 1000 == 0 {
+                std::fs::create_dir_all(&sub)?;
+            }
+            std::fs::write(sub.join(format!("{index}.txt")), format!("file {index}\n"))?;
+        }
+        // 生成文件不计入峰值内存
+        std::fs::write("/proc/self/clear_refs", "5").ok();
+        let (db, store_dir) = crate::collection::tests::temp_store("bench-import").await?;
+        let signer = SecretKey::generate(rand::rngs::OsRng);
+        let res = async {
+            let started = std::time::Instant::now();
+            let (tag, size, collection) = import(source.clone(), db.clone(), &ShareMeta::default(), &signer, false).await?;
+            let elapsed = started.elapsed();
+            let files = collection.iter().filter(|(name, _)| !is_internal(name)).count();
+            assert_eq!(files, count);
+            println!(
+                "imported {files} files, {} in {:?} ({:.0} files/s), peak memory {}",
+                HumanBytes(size),
+                elapsed,
+                files as f64 / elapsed.as_secs_f64(),
+                peak_memory().map_or("unknown".to_string(), |peak| HumanBytes(peak).to_string())
+            );
+            drop(tag);
+            anyhow::Ok(())
+        }
+        .await;
+        drop(db);
+        tokio::fs::remove_dir_all(&store_dir).await?;
+        tokio::fs::remove_dir_all(&dir).await?;
+        res
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use iroh::NodeId;
+
+use crate::cli::{TrustArgs, TrustCommands};
+
+/// 信任列表文件，记录节点 id 和名称
+const TRUST_FILE: &str = "trusted.json";
+
+/// 信任的签名节点
+#[derive(Debug, Clone, Default)]
+pub struct TrustStore {
+    path: PathBuf,
+    nodes: BTreeMap<NodeId, String>,
+}
+
+impl TrustStore {
+    /// 读取数据目录中的信任列表，不存在时为空
+    pub async fn load(dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join(TRUST_FILE);
+        let nodes = match tokio::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("invalid trust store {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, nodes })
+    }
+
+    async fn save(&self) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&self.path, serde_json::to_vec_pretty(&self.nodes)?).await?;
+        Ok(())
+    }
+
+    /// 信任的全部节点
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeId> {
+        self.nodes.keys()
+    }
+
+    /// 信任节点的名称，未信任时返回 None
+    pub fn name(&self, node_id: &NodeId) -> Option<&str> {
+        self.nodes.get(node_id).map(String::as_str)
+    }
+}
+
+/// 管理信任列表
+pub async fn run_trust(dir: &Path, args: TrustArgs) -> anyhow::Result<()> {
+    let mut store = TrustStore::load(dir).await?;
+    match args.command {
+        TrustCommands::Add { node_id, name } => {
+            let name = name.unwrap_or_else(|| node_id.fmt_short());
+            println!("trusted {node_id} as {name}");
+            store.nodes.insert(node_id, name);
+            store.save().await?;
+        }
+        TrustCommands::Remove { node_id } => {
+            let name = store
+                .nodes
+                .remove(&node_id)
+                .with_context(|| format!("{node_id} is not trusted"))?;
+            store.save().await?;
+            println!("removed {name} ({node_id})");
+        }
+        TrustCommands::List => {
+            for (node_id, name) in &store.nodes {
+                println!("{node_id} {name}");
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,270 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use console::style;
+use indicatif::HumanBytes;
+use iroh::{protocol::Router, SecretKey};
+use iroh_base::ticket::NodeTicket;
+use iroh_blobs::{
+    format::collection::Collection,
+    net_protocol::Blobs,
+    store::{ImportMode, Store},
+    ticket::BlobTicket,
+    util::progress::IgnoreProgressSender,
+    BlobFormat, Hash, TempTag,
+};
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::{
+    access::{AccessList, AccessProtocol},
+    latest::{self, LatestPublisher},
+    limit::UploadLimits,
+    meta::{is_internal, ShareMeta, META_NAME},
+    signature::sign_collection,
+    transfer::{collect_files, create_endpoint_with_key, create_send_data_dir},
+};
+
+/// 文件变化后等待一段时间再重新导入，合并连续的修改
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// 已导入的文件
+struct SharedFile {
+    modified: SystemTime,
+    size: u64,
+    hash: Hash,
+    // 持有 tag 防止数据被回收
+    _tag: TempTag,
+}
+
+/// 正在分享的目录
+struct SharedDir {
+    dir: PathBuf,
+    /// 存储目录，位于被监听的目录中时需要忽略
+    data_dir: PathBuf,
+    files: BTreeMap<String, SharedFile>,
+    /// 分享信息，每个版本都会附带
+    meta: Option<TempTag>,
+    /// 每个版本都使用该私钥签名
+    signer: SecretKey,
+    collection: Option<TempTag>,
+}
+
+impl SharedDir {
+    fn new(dir: PathBuf, data_dir: PathBuf, meta: Option<TempTag>, signer: SecretKey) -> Self {
+        Self {
+            dir,
+            data_dir,
+            files: BTreeMap::new(),
+            meta,
+            signer,
+            collection: None,
+        }
+    }
+
+    /// 重新扫描目录，只导入有变化的文件
+    /// 有变化时返回新的集合哈希
+    async fn refresh(&mut self, db: &impl Store) -> anyhow::Result<Option<Hash>> {
+        let found = collect_files(&self.dir)?;
+        let mut changed = false;
+        let mut current = BTreeMap::new();
+        for (name, path) in found {
+            if path.starts_with(&self.data_dir) || is_internal(&name) {
+                continue;
+            }
+            let metadata = match tokio::fs::metadata(&path).await {
+                Ok(metadata) => metadata,
+                // 扫描后被删除的文件下次再处理
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let modified = metadata.modified()?;
+            let size = metadata.len();
+            if let Some(file) = self.files.remove(&name) {
+                if file.modified == modified && file.size == size {
+                    current.insert(name, file);
+                    continue;
+                }
+            }
+            // 文件会继续被修改，必须复制一份而不是引用原文件
+            let (tag, size) = db
+                .import_file(path, ImportMode::Copy, BlobFormat::Raw, IgnoreProgressSender::default())
+                .await?;
+            println!("    {} {name} ({})", style("updated").green(), HumanBytes(size));
+            let hash = *tag.hash();
+            current.insert(name, SharedFile { modified, size, hash, _tag: tag });
+            changed = true;
+        }
+        // 剩下的是已经删除的文件
+        for name in self.files.keys() {
+            println!("    {} {name}", style("removed").red());
+            changed = true;
+        }
+        self.files = current;
+        if !changed && self.collection.is_some() {
+            return Ok(None);
+        }
+
+        let meta = self.meta.iter().map(|tag| (META_NAME.to_string(), *tag.hash()));
+        let collection = meta
+            .chain(self.files.iter().map(|(name, file)| (name.clone(), file.hash)))
+            .collect::<Collection>();
+        let (collection, _signature) = sign_collection(collection, &self.signer, db).await?;
+        let tag = collection.store(db).await?;
+        let hash = *tag.hash();
+        // 替换旧集合的 tag，旧版本的数据随之可以被回收
+        self.collection = Some(tag);
+        Ok(Some(hash))
+    }
+
+    fn total_size(&self) -> u64 {
+        self.files.values().map(|file| file.size).sum()
+    }
+}
+
+/// 判断文件事件是否需要重新导入
+fn is_relevant(event: &notify::Event, data_dir: &Path) -> bool {
+    if event.kind.is_access() {
+        return false;
+    }
+    event.paths.iter().any(|path| !path.starts_with(data_dir))
+}
+
+/// 持续分享目录
+/// 目录变化后重新导入并发布新的集合，接收端可以通过节点分享码获取最新版本
+pub async fn watch_and_send(dir: PathBuf, meta: ShareMeta, signer: SecretKey, limits: UploadLimits) -> anyhow::Result<()> {
+    let dir = dir
+        .canonicalize()
+        .with_context(|| format!("无法访问目录：{}", dir.display()))?;
+    anyhow::ensure!(dir.is_dir(), "{} is not a directory", dir.display());
+
+    // 节点分享码指向持久的节点身份，重启后仍然有效
+    let endpoint = create_endpoint_with_key(signer.clone()).await?;
+    let blobs_data_dir = create_send_data_dir().await?;
+    let blobs = Blobs::persistent(&blobs_data_dir).await?.build(&endpoint);
+    let (mut publisher, latest) = LatestPublisher::new(signer.clone());
+
+    let router = Router::builder(endpoint)
+        .accept(
+            iroh_blobs::ALPN,
+            AccessProtocol::new(blobs.clone(), AccessList::default()).with_limits(limits),
+        )
+        .accept(latest::ALPN, latest)
+        .spawn();
+
+    // notify 的回调运行在独立线程上
+    let (send, mut recv) = mpsc::channel(64);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let _ = send.blocking_send(event);
+    })?;
+    watcher.watch(&dir, RecursiveMode::Recursive)?;
+
+    let meta = match meta.is_empty() {
+        true => None,
+        false => Some(meta.store(blobs.store()).await?),
+    };
+    let mut shared = SharedDir::new(dir.clone(), blobs_data_dir.clone(), meta, signer);
+    let addr = router.endpoint().node_addr().await?;
+    if let Some(hash) = shared.refresh(blobs.store()).await? {
+        let record = publisher.publish(hash);
+        let ticket = BlobTicket::new(addr.clone(), hash, BlobFormat::HashSeq)?;
+        println!(
+            "watching directory {}, {} files, {}, hash: {}",
+            dir.display(),
+            shared.files.len(),
+            HumanBytes(shared.total_size()),
+            record.hash
+        );
+        println!("to get this version only, use");
+        println!("transfer receive --code {}", ticket);
+    }
+    let node_ticket = NodeTicket::new(addr);
+    println!("to always get the latest version, use");
+    println!("transfer receive --code {}", node_ticket);
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            event = recv.recv() => {
+                let Some(event) = event else { break };
+                let mut relevant = matches!(&event, Ok(event) if is_relevant(event, &blobs_data_dir));
+                // 合并短时间内的连续事件
+                tokio::time::sleep(DEBOUNCE).await;
+                while let Ok(event) = recv.try_recv() {
+                    relevant |= matches!(&event, Ok(event) if is_relevant(event, &blobs_data_dir));
+                }
+                if !relevant {
+                    continue;
+                }
+                match shared.refresh(blobs.store()).await {
+                    Ok(Some(hash)) => {
+                        let record = publisher.publish(hash);
+                        info!("published version {} hash: {}", record.seq, hash);
+                        println!(
+                            "published version #{}, {} files, {}, hash: {}",
+                            record.seq,
+                            shared.files.len(),
+                            HumanBytes(shared.total_size()),
+                            hash
+                        );
+                    }
+                    Ok(None) => {}
+                    // 文件可能还在写入中，等待下一次变化
+                    Err(e) => warn!("failed to refresh {}: {e:?}", dir.display()),
+                }
+            }
+        }
+    }
+
+    drop(watcher);
+    drop(shared);
+    tokio::time::timeout(Duration::from_secs(2), router.shutdown()).await??;
+    tokio::fs::remove_dir_all(blobs_data_dir).await?;
+    println!("shutting down");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_refresh() -> anyhow::Result<()> {
+        let root = format!("transfer-watch-test-{}", rand::random::<u64>());
+        let dir = std::env::temp_dir().join(&root);
+        let data_dir = dir.join(".sendme-send-test");
+        tokio::fs::create_dir_all(&data_dir).await?;
+        tokio::fs::write(dir.join("a.txt"), "a").await?;
+        tokio::fs::write(data_dir.join("blobs.db"), "ignored").await?;
+        let db = iroh_blobs::store::mem::Store::new();
+        let signer = SecretKey::generate(rand::rngs::OsRng);
+        let mut shared = SharedDir::new(dir.clone(), data_dir, None, signer);
+
+        let res = async {
+            let first = shared.refresh(&db).await?.context("first refresh publishes")?;
+            // 文件名以目录名开头
+            assert_eq!(shared.files.keys().collect::<Vec<_>>(), [&format!("{root}/a.txt")]);
+            // 没有变化时不发布
+            assert_eq!(shared.refresh(&db).await?, None);
+
+            tokio::fs::write(dir.join("b.txt"), "bb").await?;
+            let second = shared.refresh(&db).await?.context("new file publishes")?;
+            assert_ne!(first, second);
+            assert_eq!(shared.total_size(), 3);
+
+            tokio::fs::remove_file(dir.join("a.txt")).await?;
+            let third = shared.refresh(&db).await?.context("removed file publishes")?;
+            assert_ne!(second, third);
+            assert_eq!(shared.files.keys().collect::<Vec<_>>(), [&format!("{root}/b.txt")]);
+            anyhow::Ok(())
+        }
+        .await;
+        tokio::fs::remove_dir_all(&dir).await?;
+        res
+    }
+}